@@ -0,0 +1,598 @@
+use crate::events::EventLog;
+use crate::http_handler::{select_all_pages, ApiError};
+use crate::metrics::Timed;
+use crate::trash::{is_deleted, soft_delete};
+use chrono::{Duration, Utc};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+const DELETE_BATCH_SIZE: usize = 100;
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenDeleteFilter {
+    pub platform: Option<String>,
+    /// ISO-8601 timestamp; rows whose `last_seen` is older than this match.
+    pub last_seen_before: Option<String>,
+    pub quarantined: Option<bool>,
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+impl TokenDeleteFilter {
+    fn is_empty(&self) -> bool {
+        self.platform.is_none() && self.last_seen_before.is_none() && self.quarantined.is_none()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenDeleteSummary {
+    pub dry_run: bool,
+    pub matched: usize,
+    pub deleted: usize,
+    pub failed: usize,
+}
+
+async fn fetch_matching_ids(
+    client: &SupabaseClient,
+    filter: &TokenDeleteFilter,
+) -> Result<Vec<String>, ApiError> {
+    let query = || {
+        let mut query = client.select("users").columns(vec!["id", "deleted_at"]);
+        if let Some(platform) = &filter.platform {
+            query = query.eq("platform", platform);
+        }
+        if let Some(last_seen_before) = &filter.last_seen_before {
+            query = query.lt("last_seen", last_seen_before);
+        }
+        if let Some(quarantined) = filter.quarantined {
+            query = query.eq("quarantined", &quarantined.to_string());
+        }
+        query
+    };
+
+    let rows = select_all_pages(query, "select users").await.map_err(|e| {
+        error!(error = ?e, "Error fetching tokens matching delete filter");
+        ApiError::SupabaseFetch
+    })?;
+
+    Ok(rows
+        .iter()
+        .filter(|row| !is_deleted(row))
+        .filter_map(row_id)
+        .collect())
+}
+
+/// `users.id` may be a uuid or a bigint depending on the project.
+fn row_id(row: &Value) -> Option<String> {
+    row_id_value(&row["id"])
+}
+
+/// Soft-deletes rows from `users` matching the filter in batches; they can
+/// be restored from the trash until the cleanup job purges them. Without an
+/// explicit `dry_run: false` only the number of matching rows is reported.
+#[instrument(skip(client, event_log))]
+pub async fn delete_tokens_by_filter(
+    client: &SupabaseClient,
+    filter: TokenDeleteFilter,
+    event_log: &mut EventLog,
+) -> Result<TokenDeleteSummary, ApiError> {
+    if filter.is_empty() {
+        return Err(ApiError::BadRequest(
+            "At least one of platform, last_seen_before or quarantined is required".into(),
+        ));
+    }
+
+    let ids = fetch_matching_ids(client, &filter).await?;
+    let mut summary = TokenDeleteSummary {
+        dry_run: filter.dry_run,
+        matched: ids.len(),
+        deleted: 0,
+        failed: 0,
+    };
+    info!(
+        matched = summary.matched,
+        dry_run = filter.dry_run,
+        "Resolved token delete filter"
+    );
+
+    if filter.dry_run {
+        return Ok(summary);
+    }
+
+    for batch in ids.chunks(DELETE_BATCH_SIZE) {
+        let results = join_all(batch.iter().map(|id| soft_delete(client, "users", id))).await;
+        for (id, result) in batch.iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    summary.deleted += 1;
+                    event_log.record("prune", json!({ "row_id": id, "reason": "bulk_delete" }));
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to delete token row");
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+
+    info!(
+        deleted = summary.deleted,
+        failed = summary.failed,
+        "Finished bulk token deletion"
+    );
+    Ok(summary)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeDuplicatesRequest {
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergedGroup {
+    pub kept: String,
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergeDuplicatesSummary {
+    pub dry_run: bool,
+    pub groups: Vec<MergedGroup>,
+    pub failed: usize,
+}
+
+/// Groups rows registered under the same `expo_push_token`. Rows of one
+/// `user_id` with different tokens are separate devices, not duplicates.
+fn group_duplicates(rows: &[Value]) -> Vec<Vec<usize>> {
+    let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(token) = row["expo_push_token"].as_str().filter(|t| !t.is_empty()) {
+            groups.entry(token).or_default().push(i);
+        }
+    }
+    groups.into_values().filter(|g| g.len() > 1).collect()
+}
+
+fn row_id_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Orders a duplicate group newest `last_seen` first, so the survivor
+/// comes first; ISO-8601 strings sort chronologically.
+fn sort_by_last_seen(rows: &[Value], group: &mut [usize]) {
+    group.sort_by(|&a, &b| {
+        let last_seen = |i: usize| rows[i]["last_seen"].as_str().unwrap_or_default();
+        last_seen(b).cmp(last_seen(a))
+    });
+}
+
+/// The survivor's preferences, plus keys only the duplicates set; among
+/// duplicates, the more recently seen one wins.
+fn merged_preferences(rows: &[Value], survivor: usize, duplicates: &[usize]) -> Map<String, Value> {
+    let mut preferences = Map::new();
+    for &i in duplicates.iter().rev().chain(std::iter::once(&survivor)) {
+        if let Some(prefs) = rows[i]["preferences"].as_object() {
+            preferences.extend(prefs.clone());
+        }
+    }
+    preferences
+}
+
+/// What the survivor takes over from the rows being removed: the earliest
+/// `created_at`, and the latest delivery outcome of any of them. Columns no
+/// row sets are left out.
+fn merged_history(rows: &[Value], group: &[usize]) -> Map<String, Value> {
+    let mut history = Map::new();
+    if let Some(created_at) = group
+        .iter()
+        .filter_map(|&i| rows[i]["created_at"].as_str())
+        .min()
+    {
+        history.insert("created_at".into(), json!(created_at));
+    }
+    if let Some(&latest) = group
+        .iter()
+        .filter(|&&i| rows[i]["last_delivery_at"].is_string())
+        .max_by_key(|&&i| rows[i]["last_delivery_at"].as_str())
+    {
+        for column in [
+            "last_delivery_status",
+            "last_delivery_error",
+            "last_delivery_at",
+        ] {
+            history.insert(column.into(), rows[latest][column].clone());
+        }
+    }
+    history
+}
+
+/// Collapses duplicate registrations of a token into its most recently seen
+/// row. The survivor keeps its own preferences and inherits any keys only
+/// set on the rows being removed, along with their [`merged_history`].
+/// Tickets, history and topic subscriptions reference the token rather
+/// than the row, so they carry over as they are.
+#[instrument(skip(client))]
+pub async fn merge_duplicate_tokens(
+    client: &SupabaseClient,
+    request: MergeDuplicatesRequest,
+) -> Result<MergeDuplicatesSummary, ApiError> {
+    let mut rows = select_all_pages(|| client.select("users"), "select users")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching users for duplicate merge");
+            ApiError::SupabaseFetch
+        })?;
+    rows.retain(|row| !is_deleted(row));
+
+    let mut summary = MergeDuplicatesSummary {
+        dry_run: request.dry_run,
+        groups: vec![],
+        failed: 0,
+    };
+
+    for mut group in group_duplicates(&rows) {
+        sort_by_last_seen(&rows, &mut group);
+        let (survivor, duplicates) = group.split_first().expect("groups have 2+ rows");
+        let Some(kept) = row_id(&rows[*survivor]) else {
+            continue;
+        };
+        let removed = duplicates
+            .iter()
+            .filter_map(|&i| row_id(&rows[i]))
+            .collect::<Vec<_>>();
+
+        if !request.dry_run {
+            let mut fields = merged_history(&rows, &group);
+            fields.insert(
+                "preferences".into(),
+                Value::Object(merged_preferences(&rows, *survivor, duplicates)),
+            );
+            if let Err(e) = client
+                .update("users", &kept, Value::Object(fields))
+                .timed("update users")
+                .await
+            {
+                warn!(error = %e, row_id = %kept, "Failed to update surviving row");
+                summary.failed += 1;
+                continue;
+            }
+            for id in &removed {
+                if let Err(e) = soft_delete(client, "users", id).await {
+                    warn!(error = %e, row_id = %id, "Failed to delete duplicate row");
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        summary.groups.push(MergedGroup { kept, removed });
+    }
+
+    info!(
+        groups = summary.groups.len(),
+        dry_run = request.dry_run,
+        failed = summary.failed,
+        "Finished merging duplicate tokens"
+    );
+    Ok(summary)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TokenStats {
+    pub total: usize,
+    pub quarantined: usize,
+    pub by_platform: BTreeMap<String, usize>,
+}
+
+/// Token counts for the admin dashboard.
+#[instrument(skip(client))]
+pub async fn token_stats(client: &SupabaseClient) -> Result<TokenStats, ApiError> {
+    let rows = select_all_pages(
+        || {
+            client
+                .select("users")
+                .columns(vec!["platform", "quarantined", "deleted_at"])
+        },
+        "select users",
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Error fetching users for token stats");
+        ApiError::SupabaseFetch
+    })?;
+
+    let mut stats = TokenStats::default();
+    for row in rows.iter().filter(|row| !is_deleted(row)) {
+        stats.total += 1;
+        if row["quarantined"].as_bool() == Some(true) {
+            stats.quarantined += 1;
+        }
+        let platform = row["platform"].as_str().unwrap_or("unknown");
+        *stats.by_platform.entry(platform.to_string()).or_default() += 1;
+    }
+    Ok(stats)
+}
+
+const DEFAULT_STALE_TOKEN_DAYS: i64 = 90;
+
+/// One night's counts in `token_stats_snapshots`, stored with the
+/// `snapshot_date` as its `id`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenStatsSnapshot {
+    /// `YYYY-MM-DD`, UTC.
+    pub snapshot_date: String,
+    pub total: usize,
+    pub by_platform: BTreeMap<String, usize>,
+    pub by_app_version: BTreeMap<String, usize>,
+    /// `active`, `stale` (not seen for `STALE_TOKEN_DAYS`, default 90),
+    /// `quarantined` and `deleted` (soft-deleted, not yet purged).
+    pub by_health: BTreeMap<String, usize>,
+}
+
+fn health_state(row: &Value, stale_before: &str) -> &'static str {
+    if is_deleted(row) {
+        "deleted"
+    } else if row["quarantined"].as_bool() == Some(true) {
+        "quarantined"
+    } else if row["last_seen"]
+        .as_str()
+        .is_some_and(|last_seen| last_seen < stale_before)
+    {
+        "stale"
+    } else {
+        "active"
+    }
+}
+
+/// Counts the token table by platform, app version and health state and
+/// stores the result as today's snapshot. Meant to run nightly; running it
+/// again the same day replaces that day's snapshot.
+#[instrument(skip(client))]
+pub async fn snapshot_token_stats(client: &SupabaseClient) -> Result<TokenStatsSnapshot, ApiError> {
+    let rows = select_all_pages(
+        || {
+            client.select("users").columns(vec![
+                "platform",
+                "app_version",
+                "quarantined",
+                "last_seen",
+                "deleted_at",
+            ])
+        },
+        "select users",
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Error fetching users for token stats snapshot");
+        ApiError::SupabaseFetch
+    })?;
+
+    let stale_days = env::var("STALE_TOKEN_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_STALE_TOKEN_DAYS);
+    let now = Utc::now();
+    let stale_before = (now - Duration::days(stale_days)).to_rfc3339();
+
+    let mut snapshot = TokenStatsSnapshot {
+        snapshot_date: now.format("%Y-%m-%d").to_string(),
+        ..Default::default()
+    };
+    for row in &rows {
+        let health = health_state(row, &stale_before);
+        *snapshot.by_health.entry(health.to_string()).or_default() += 1;
+        if health == "deleted" {
+            continue;
+        }
+        snapshot.total += 1;
+        let platform = row["platform"].as_str().unwrap_or("unknown");
+        *snapshot
+            .by_platform
+            .entry(platform.to_string())
+            .or_default() += 1;
+        let app_version = row["app_version"].as_str().unwrap_or("unknown");
+        *snapshot
+            .by_app_version
+            .entry(app_version.to_string())
+            .or_default() += 1;
+    }
+
+    let mut body = json!(snapshot);
+    body["taken_at"] = json!(now.to_rfc3339());
+    client
+        .upsert("token_stats_snapshots", &snapshot.snapshot_date, body)
+        .timed("upsert token_stats_snapshots")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error storing token stats snapshot");
+            ApiError::SupabaseWrite
+        })?;
+    info!(
+        snapshot_date = %snapshot.snapshot_date,
+        total = snapshot.total,
+        "Stored token stats snapshot"
+    );
+    Ok(snapshot)
+}
+
+/// A snapshot with the change in `total` since the previous one, the
+/// night's net registration growth (negative when churn outpaced it).
+#[derive(Debug, Serialize)]
+pub struct TokenStatsPoint {
+    #[serde(flatten)]
+    pub snapshot: TokenStatsSnapshot,
+    pub total_change: Option<i64>,
+}
+
+/// Snapshots from the last `days` days, oldest first, for trendlines.
+#[instrument(skip(client))]
+pub async fn token_stats_trend(
+    client: &SupabaseClient,
+    days: i64,
+) -> Result<Vec<TokenStatsPoint>, ApiError> {
+    let since = (Utc::now() - Duration::days(days))
+        .format("%Y-%m-%d")
+        .to_string();
+    let rows = client
+        .select("token_stats_snapshots")
+        .gte("snapshot_date", &since)
+        .order("snapshot_date", true)
+        .execute()
+        .timed("select token_stats_snapshots")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching token stats snapshots");
+            ApiError::SupabaseFetch
+        })?;
+
+    let mut previous_total = None;
+    let mut points = vec![];
+    for row in rows {
+        let snapshot = match serde_json::from_value::<TokenStatsSnapshot>(row) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!(error = %e, "Skipping malformed token stats snapshot");
+                continue;
+            }
+        };
+        let total = snapshot.total as i64;
+        points.push(TokenStatsPoint {
+            snapshot,
+            total_change: previous_total.map(|previous| total - previous),
+        });
+        previous_total = Some(total);
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut groups: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+        for group in &mut groups {
+            group.sort();
+        }
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn group_duplicates_links_rows_sharing_a_token() {
+        let rows = [
+            json!({ "id": 1, "user_id": "a", "expo_push_token": "t1" }),
+            json!({ "id": 2, "user_id": "b", "expo_push_token": "t1" }),
+            json!({ "id": 3, "user_id": "b", "expo_push_token": "t2" }),
+            json!({ "id": 4, "user_id": "a", "expo_push_token": "t1" }),
+        ];
+        assert_eq!(sorted(group_duplicates(&rows)), vec![vec![0, 1, 3]]);
+    }
+
+    #[test]
+    fn group_duplicates_keeps_a_users_devices_apart() {
+        let rows = [
+            json!({ "id": 1, "user_id": "a", "expo_push_token": "phone" }),
+            json!({ "id": 2, "user_id": "a", "expo_push_token": "tablet" }),
+            json!({ "id": 3, "user_id": 7, "expo_push_token": "t3" }),
+            json!({ "id": 4, "user_id": 7, "expo_push_token": "t4" }),
+        ];
+        assert!(group_duplicates(&rows).is_empty());
+    }
+
+    #[test]
+    fn group_duplicates_ignores_missing_tokens() {
+        let rows = [
+            json!({ "id": 1, "user_id": "a", "expo_push_token": null }),
+            json!({ "id": 2, "user_id": "a", "expo_push_token": "" }),
+            json!({ "id": 3, "user_id": "a" }),
+            json!({ "id": 4, "user_id": "a" }),
+        ];
+        assert!(group_duplicates(&rows).is_empty());
+    }
+
+    #[test]
+    fn survivor_is_the_most_recently_seen_row() {
+        let rows = [
+            json!({ "id": 1, "last_seen": "2026-01-01T00:00:00Z" }),
+            json!({ "id": 2, "last_seen": "2026-03-01T00:00:00Z" }),
+            json!({ "id": 3 }),
+            json!({ "id": 4, "last_seen": "2026-02-01T00:00:00Z" }),
+        ];
+        let mut group = vec![0, 1, 2, 3];
+        sort_by_last_seen(&rows, &mut group);
+        assert_eq!(group, vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn survivor_preferences_win_over_duplicates() {
+        let rows = [
+            json!({ "preferences": { "marketing": false } }),
+            json!({ "preferences": { "marketing": true, "news": false } }),
+            json!({ "preferences": { "news": true, "digest": "weekly" } }),
+        ];
+        // Row 0 survives; row 1 was seen more recently than row 2.
+        let preferences = merged_preferences(&rows, 0, &[1, 2]);
+        assert_eq!(
+            Value::Object(preferences),
+            json!({ "marketing": false, "news": false, "digest": "weekly" })
+        );
+    }
+
+    #[test]
+    fn merged_preferences_skip_rows_without_any() {
+        let rows = [json!({}), json!({ "preferences": { "news": true } })];
+        assert_eq!(
+            Value::Object(merged_preferences(&rows, 0, &[1])),
+            json!({ "news": true })
+        );
+    }
+
+    #[test]
+    fn merged_history_keeps_the_first_registration_and_latest_delivery() {
+        let rows = [
+            json!({
+                "created_at": "2026-02-01T00:00:00Z",
+                "last_delivery_status": "ok",
+                "last_delivery_error": null,
+                "last_delivery_at": "2026-02-02T00:00:00Z",
+            }),
+            json!({
+                "created_at": "2026-01-01T00:00:00Z",
+                "last_delivery_status": "error",
+                "last_delivery_error": "MessageRateExceeded",
+                "last_delivery_at": "2026-03-01T00:00:00Z",
+            }),
+            json!({ "created_at": null }),
+        ];
+        assert_eq!(
+            Value::Object(merged_history(&rows, &[0, 1, 2])),
+            json!({
+                "created_at": "2026-01-01T00:00:00Z",
+                "last_delivery_status": "error",
+                "last_delivery_error": "MessageRateExceeded",
+                "last_delivery_at": "2026-03-01T00:00:00Z",
+            })
+        );
+    }
+
+    #[test]
+    fn merged_history_leaves_out_unset_columns() {
+        let rows = [json!({ "id": 1 }), json!({ "id": 2 })];
+        assert!(merged_history(&rows, &[0, 1]).is_empty());
+    }
+}
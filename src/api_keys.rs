@@ -0,0 +1,211 @@
+//! Which API keys are accepted, and what each may do. `API_KEY` (or the
+//! `api-key` secret, or the rotated keys in `API_KEY_SECRET_ID`) grants
+//! everything; `API_KEYS` adds keys limited to some [`Scope`]s, e.g. a
+//! send-only key for the mobile app and a broadcast key for the dashboard.
+
+use crate::rotation::rotating_api_keys;
+use crate::secrets::loaded_secrets;
+use http::Method;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+use tracing::error;
+
+static SCOPED_KEYS: OnceLock<Vec<ApiKey>> = OnceLock::new();
+
+/// Tenant of the full-access keys.
+const DEFAULT_TENANT: &str = "default";
+
+tokio::task_local! {
+    static CALLER: String;
+    static TENANT: String;
+}
+
+/// What a key may call, checked per route by [`required_scope`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Scope {
+    /// Messages to explicit tokens, an audience or a topic: `/send`,
+    /// `/send/batch`, `/bundles`, `/topics/{topic}/send`, scheduled sends
+    /// and the status of asynchronous ones.
+    #[serde(rename = "send")]
+    Send,
+    /// The configured message to every token: `/broadcast`, `/scheduled`,
+    /// sampled broadcasts and resends.
+    #[serde(rename = "broadcast")]
+    Broadcast,
+    /// Registering and removing tokens: `/tokens`, `/unsubscribe`,
+    /// `/preferences` and topic subscriptions.
+    #[serde(rename = "tokens:write")]
+    TokensWrite,
+    /// Everything else: admin, maintenance, jobs, stats and webhooks.
+    #[serde(rename = "admin")]
+    Admin,
+    /// Every scope.
+    #[serde(rename = "*")]
+    All,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Send => "send",
+            Scope::Broadcast => "broadcast",
+            Scope::TokensWrite => "tokens:write",
+            Scope::Admin => "admin",
+            Scope::All => "*",
+        })
+    }
+}
+
+/// One entry of `API_KEYS`, e.g.
+/// `{"name": "mobile", "key": "...", "scopes": ["send", "tokens:write"]}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Shown in logs instead of the key.
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<Scope>,
+    /// Owner of the webhook subscriptions and idempotency keys created with
+    /// this key; [`ApiKey::name`] when unset. Several keys may share one.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl ApiKey {
+    /// A key with every scope. All of them share the `default` tenant, so
+    /// rotating the key, or moving from `API_KEY` to `API_KEY_SECRET_ID`,
+    /// keeps its subscriptions.
+    fn full_access(name: &str, key: String) -> Self {
+        Self {
+            name: name.to_string(),
+            key,
+            scopes: vec![Scope::All],
+            tenant: Some(DEFAULT_TENANT.to_string()),
+        }
+    }
+
+    /// The tenant of requests made with this key. Derived from the
+    /// configuration rather than the secret, so it survives rotation.
+    pub fn tenant(&self) -> &str {
+        self.tenant.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        grants(&self.scopes, scope)
+    }
+}
+
+/// The key a request was authenticated with, added to the request
+/// extensions by [`crate::middleware::Auth`].
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// [`ApiKey::name`].
+    pub name: String,
+    /// [`ApiKey::tenant`].
+    pub tenant: String,
+    /// [`ApiKey::scopes`].
+    pub scopes: Vec<Scope>,
+}
+
+impl Caller {
+    pub fn allows(&self, scope: Scope) -> bool {
+        grants(&self.scopes, scope)
+    }
+}
+
+fn grants(scopes: &[Scope], scope: Scope) -> bool {
+    scopes
+        .iter()
+        .any(|granted| *granted == Scope::All || *granted == scope)
+}
+
+/// The scope a request needs, `None` for the endpoints any valid key may
+/// call.
+pub fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    let scope = match path {
+        "/health" | "/version" => return None,
+        "/" | "/send" | "/send/batch" | "/bundles" => Scope::Send,
+        "/broadcast" | "/scheduled" | "/scheduled/dispatch" | "/admin/sampled-broadcast" => {
+            Scope::Broadcast
+        }
+        "/tokens" | "/unsubscribe" | "/preferences" => Scope::TokensWrite,
+        path if path.starts_with("/bundles/") || path.starts_with("/batches/") => Scope::Send,
+        path if path.starts_with("/topics/") && path.ends_with("/send") => Scope::Send,
+        path if path.starts_with("/topics/") => Scope::TokensWrite,
+        path if path.starts_with("/scheduled/") && method == Method::DELETE => Scope::Send,
+        path if path.starts_with("/history/") && path.ends_with("/resend") => Scope::Broadcast,
+        _ => Scope::Admin,
+    };
+    Some(scope)
+}
+
+/// Every accepted key. Keys from `API_KEY_SECRET_ID`, or else the
+/// `api-key` secret (see [`crate::secrets`]) or `API_KEY`, have full access; a malformed `API_KEYS` is logged and ignored.
+pub async fn api_keys() -> Vec<ApiKey> {
+    let mut keys = match rotating_api_keys().await {
+        Some(rotated) => rotated
+            .iter()
+            .map(|key| ApiKey::full_access("rotated", key.clone()))
+            .collect(),
+        None => loaded_secrets()
+            .and_then(|secrets| secrets.get("api-key").cloned())
+            .or_else(|| env::var("API_KEY").ok())
+            .map(|key| ApiKey::full_access("default", key))
+            .into_iter()
+            .collect::<Vec<_>>(),
+    };
+    keys.extend(scoped_keys().iter().cloned());
+    keys
+}
+
+/// `API_KEYS`, parsed once per container.
+fn scoped_keys() -> &'static [ApiKey] {
+    SCOPED_KEYS.get_or_init(|| {
+        let Ok(scoped) = env::var("API_KEYS") else {
+            return vec![];
+        };
+        serde_json::from_str(&scoped).unwrap_or_else(|e| {
+            error!(error = %e, "Ignoring malformed API_KEYS");
+            vec![]
+        })
+    })
+}
+
+/// Runs `future` as a request authenticated with the key named `name`,
+/// whose tenant is `tenant` (see [`ApiKey::tenant`]).
+pub async fn scope_caller<F: Future>(name: String, tenant: String, future: F) -> F::Output {
+    CALLER.scope(name, TENANT.scope(tenant, future)).await
+}
+
+/// [`ApiKey::name`] of the key the current request was authenticated with.
+/// `None` outside a request, e.g. for EventBridge invocations.
+pub fn current_caller() -> Option<String> {
+    CALLER.try_with(String::clone).ok()
+}
+
+/// Tenant of the current request, which owns the webhook subscriptions its
+/// events go to. `None` outside a request.
+pub fn current_tenant() -> Option<String> {
+    TENANT.try_with(String::clone).ok()
+}
+
+/// Compares in constant time, so response timing does not reveal how much
+/// of a guessed key was right.
+pub fn keys_match(expected: &str, provided: &str) -> bool {
+    expected.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+/// The key in `keys` matching `provided`, if any. Every key is compared,
+/// not just those before the match.
+pub fn find_key(keys: Vec<ApiKey>, provided: &str) -> Option<ApiKey> {
+    let mut found = None;
+    for key in keys {
+        if keys_match(&key.key, provided) && found.is_none() {
+            found = Some(key);
+        }
+    }
+    found
+}
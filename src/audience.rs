@@ -0,0 +1,684 @@
+use crate::http_client::http_client;
+use crate::http_handler::{
+    initialize_supabase_client, normalize_tokens, select_all_pages, ApiError,
+};
+use crate::metrics::Timed;
+use crate::models::AudienceSelector;
+use crate::preferences::{exclude_opted_out, notifications_enabled};
+use crate::token_store::{is_supabase_store, token_store};
+use crate::topics::TopicResolver;
+use crate::trace_context;
+use crate::trash::is_deleted;
+use chrono::{DateTime, NaiveDate};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// One token an audience resolved to, with the user it belongs to when the
+/// source knows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Recipient {
+    pub expo_push_token: String,
+    pub user_id: Option<String>,
+}
+
+impl Recipient {
+    /// A token without user context, as an explicit list provides.
+    pub fn token(expo_push_token: impl Into<String>) -> Self {
+        Self {
+            expo_push_token: expo_push_token.into(),
+            user_id: None,
+        }
+    }
+}
+
+/// Picks `expo_push_token` (and `user_id`, if present) out of each returned
+/// row. Functions declared as `returns setof text` yield bare strings, which
+/// are accepted as-is.
+fn recipients_from_rows(rows: &[Value]) -> Vec<Recipient> {
+    rows.iter()
+        .filter_map(|row| match row {
+            Value::String(token) => Some(Recipient::token(token.clone())),
+            _ => Some(Recipient {
+                expo_push_token: row["expo_push_token"].as_str()?.to_string(),
+                user_id: match &row["user_id"] {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Number(id) => Some(id.to_string()),
+                    _ => None,
+                },
+            }),
+        })
+        .collect()
+}
+
+/// Whether a `users`-like row may be sent to: not quarantined, deleted or
+/// opted out.
+fn is_live(row: &Value) -> bool {
+    row["quarantined"].as_bool() != Some(true) && !is_deleted(row) && notifications_enabled(row)
+}
+
+/// Resolves an audience by calling a Postgres function through the Supabase
+/// REST `rpc` endpoint, so targeting rules can be maintained in SQL.
+#[instrument(skip(secrets, args))]
+pub async fn resolve_rpc_audience(
+    secrets: &HashMap<String, String>,
+    function_name: &str,
+    args: &Value,
+) -> Result<Vec<Recipient>, ApiError> {
+    if !is_valid_identifier(function_name) {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid audience_rpc: {function_name}"
+        )));
+    }
+
+    let supabase_url = secrets
+        .get("supabase-url")
+        .ok_or_else(|| ApiError::MissingSecret("supabase-url".into()))?;
+    let supabase_key = secrets
+        .get("supabase-key")
+        .ok_or_else(|| ApiError::MissingSecret("supabase-key".into()))?;
+
+    let response = trace_context::inject(http_client().post(format!(
+        "{}/rest/v1/rpc/{function_name}",
+        supabase_url.trim_end_matches('/')
+    )))
+    .header("apikey", supabase_key)
+    .header("Authorization", format!("Bearer {supabase_key}"))
+    .json(args)
+    .send()
+    .timed(format!("rpc {function_name}"))
+    .await
+    .and_then(|r| r.error_for_status())
+    .map_err(|e| {
+        error!(error = ?e, "Error calling audience RPC");
+        ApiError::SupabaseFetch
+    })?;
+
+    let rows = response.json::<Vec<Value>>().await.map_err(|e| {
+        error!(error = ?e, "Audience RPC returned an unexpected shape");
+        ApiError::SupabaseFetch
+    })?;
+
+    let recipients = recipients_from_rows(&rows);
+    info!(token_count = recipients.len(), "Resolved audience via RPC");
+    Ok(recipients)
+}
+
+/// Names of Supabase views/tables that may be used as `audience`, taken from
+/// the comma-separated `NAMED_AUDIENCES` environment variable.
+fn named_audience_allowlist() -> Vec<String> {
+    env::var("NAMED_AUDIENCES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Resolves an audience from a view or table maintained in Supabase. Only
+/// names on the allowlist are queried; the view needs an `id` column to be
+/// paged by.
+#[instrument(skip(client))]
+pub async fn resolve_named_audience(
+    client: &SupabaseClient,
+    name: &str,
+) -> Result<Vec<Recipient>, ApiError> {
+    let allowlist = named_audience_allowlist();
+    if !is_valid_identifier(name) || !allowlist.iter().any(|allowed| allowed == name) {
+        return Err(ApiError::BadRequest(format!(
+            "Unknown audience: {name} (allowed: {})",
+            allowlist.join(", ")
+        )));
+    }
+
+    let rows = select_all_pages(|| client.select(name), &format!("select {name}"))
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching named audience");
+            ApiError::SupabaseFetch
+        })?;
+
+    // Views over `users` carry its flags; other rows pass unfiltered.
+    let live_rows = rows.into_iter().filter(is_live).collect::<Vec<_>>();
+    let recipients = recipients_from_rows(&live_rows);
+    info!(token_count = recipients.len(), "Resolved named audience");
+    Ok(recipients)
+}
+
+/// What a targeted send asks for. Each [`AudienceResolver`] reads the parts
+/// it understands; unset parts do not narrow the audience.
+#[derive(Debug, Clone, Default)]
+pub struct AudienceQuery {
+    /// A named audience, see [`resolve_named_audience`].
+    pub segment: Option<String>,
+    pub topic: Option<String>,
+    pub user_ids: Vec<String>,
+    pub group_ids: Vec<String>,
+    /// Only users seen on or after this date (`users.last_seen`).
+    pub active_since: Option<String>,
+    /// `users` columns that must equal the given value, e.g. `plan=premium`.
+    pub attributes: Vec<(String, String)>,
+}
+
+impl AudienceQuery {
+    /// Filters on `users` rather than a segment, topic or explicit users.
+    fn has_user_filters(&self) -> bool {
+        !self.user_ids.is_empty()
+            || !self.group_ids.is_empty()
+            || self.active_since.is_some()
+            || !self.attributes.is_empty()
+    }
+
+    /// Nothing set, i.e. everyone.
+    pub fn is_empty(&self) -> bool {
+        self.segment.is_none() && self.topic.is_none() && !self.has_user_filters()
+    }
+}
+
+/// Query parameters of the broadcast endpoints that are not audience
+/// filters.
+const RESERVED_QUERY_PARAMS: [&str; 4] = ["job_id", "queue_if_unavailable", "async", "dry_run"];
+
+/// `users` columns a broadcast may filter on by query parameter, taken from
+/// the comma-separated `AUDIENCE_FILTER_COLUMNS` environment variable.
+fn filter_column_allowlist() -> Vec<String> {
+    env::var("AUDIENCE_FILTER_COLUMNS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The audience selected by the query string of a broadcast, e.g.
+/// `?active_since=2024-01-01&plan=premium`: `active_since` takes a date or
+/// an RFC 3339 timestamp, and every other parameter must name an
+/// allowlisted `users` column. No filters means every token.
+pub fn audience_from_query_params(params: &[(String, String)]) -> Result<AudienceQuery, ApiError> {
+    let allowlist = filter_column_allowlist();
+    let mut query = AudienceQuery::default();
+    for (name, value) in params {
+        if RESERVED_QUERY_PARAMS.contains(&name.as_str()) {
+            continue;
+        }
+        if name == "active_since" {
+            let valid = NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+                || DateTime::parse_from_rfc3339(value).is_ok();
+            if !valid {
+                return Err(ApiError::BadRequest(
+                    "active_since must be a date such as 2024-01-01 or an RFC 3339 timestamp"
+                        .into(),
+                ));
+            }
+            query.active_since = Some(value.clone());
+        } else if is_valid_identifier(name) && allowlist.iter().any(|allowed| allowed == name) {
+            query.attributes.push((name.clone(), value.clone()));
+        } else {
+            return Err(ApiError::BadRequest(format!(
+                "Unknown audience filter: {name} (allowed: active_since{})",
+                allowlist
+                    .iter()
+                    .map(|column| format!(", {column}"))
+                    .collect::<String>()
+            )));
+        }
+    }
+    Ok(query)
+}
+
+/// A targeting strategy. The send pipeline only sees the resolved
+/// recipients, so strategies can be added without touching it.
+pub trait AudienceResolver: Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>>;
+}
+
+/// Filters on Supabase tables: `segment` selects an allowlisted view or
+/// table, otherwise live `users` rows are filtered by `user_ids`,
+/// `group_ids` (`users.group_id`), `active_since` and `attributes`.
+pub struct SupabaseFilterResolver(pub SupabaseClient);
+
+impl SupabaseFilterResolver {
+    async fn filter_users(&self, query: &AudienceQuery) -> Result<Vec<Recipient>, ApiError> {
+        if query.topic.is_some() {
+            return Err(ApiError::BadRequest(
+                "Topic audiences are not supported by the Supabase filter resolver".into(),
+            ));
+        }
+        if !query.has_user_filters() {
+            return Err(ApiError::BadRequest(
+                "An audience filter needs a segment, user_ids, group_ids or attributes".into(),
+            ));
+        }
+        let select = || {
+            let mut select = self.0.select("users");
+            if !query.user_ids.is_empty() {
+                select = select.in_("user_id", &to_refs(&query.user_ids));
+            }
+            if !query.group_ids.is_empty() {
+                select = select.in_("group_id", &to_refs(&query.group_ids));
+            }
+            if let Some(active_since) = &query.active_since {
+                select = select.gte("last_seen", active_since);
+            }
+            for (column, value) in &query.attributes {
+                select = select.eq(column, value);
+            }
+            select
+        };
+        let rows = select_all_pages(select, "select users")
+            .await
+            .map_err(|e| {
+                error!(error = ?e, "Error filtering users for audience");
+                ApiError::SupabaseFetch
+            })?;
+        let live_rows = rows.into_iter().filter(is_live).collect::<Vec<_>>();
+        let recipients = recipients_from_rows(&live_rows);
+        info!(token_count = recipients.len(), "Resolved filtered audience");
+        Ok(recipients)
+    }
+}
+
+fn to_refs(values: &[String]) -> Vec<&str> {
+    values.iter().map(String::as_str).collect()
+}
+
+impl AudienceResolver for SupabaseFilterResolver {
+    fn resolve<'a>(
+        &'a self,
+        query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>> {
+        Box::pin(async move {
+            match &query.segment {
+                Some(name) => resolve_named_audience(&self.0, name).await,
+                None => self.filter_users(query).await,
+            }
+        })
+    }
+}
+
+/// A Postgres function, see [`resolve_rpc_audience`]. The query's set parts
+/// are passed as extra arguments, so the function can target with them.
+pub struct RpcResolver {
+    pub secrets: HashMap<String, String>,
+    pub function_name: String,
+    pub args: Value,
+}
+
+impl AudienceResolver for RpcResolver {
+    fn resolve<'a>(
+        &'a self,
+        query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>> {
+        Box::pin(async move {
+            let mut args = self.args.clone();
+            if let Some(args) = args.as_object_mut() {
+                if let Some(topic) = &query.topic {
+                    args.insert("topic".into(), json!(topic));
+                }
+                if !query.user_ids.is_empty() {
+                    args.insert("user_ids".into(), json!(query.user_ids));
+                }
+                if !query.group_ids.is_empty() {
+                    args.insert("group_ids".into(), json!(query.group_ids));
+                }
+                if let Some(active_since) = &query.active_since {
+                    args.insert("active_since".into(), json!(active_since));
+                }
+                if !query.attributes.is_empty() {
+                    let attributes = query
+                        .attributes
+                        .iter()
+                        .map(|(column, value)| (column.clone(), json!(value)))
+                        .collect::<Map<_, _>>();
+                    args.insert("attributes".into(), json!(attributes));
+                }
+            }
+            resolve_rpc_audience(&self.secrets, &self.function_name, &args).await
+        })
+    }
+}
+
+/// A frozen audience, see [`create_audience_snapshot`]. Snapshots hold only
+/// tokens, so the query is not applied.
+pub struct SnapshotResolver {
+    pub client: SupabaseClient,
+    pub snapshot_id: String,
+}
+
+impl AudienceResolver for SnapshotResolver {
+    fn resolve<'a>(
+        &'a self,
+        _query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>> {
+        Box::pin(load_audience_snapshot(&self.client, &self.snapshot_id))
+    }
+}
+
+/// A fixed list, e.g. for callers embedding the engine that already know
+/// their recipients. `user_ids` narrows it to recipients of those users.
+pub struct StaticResolver(pub Vec<Recipient>);
+
+impl AudienceResolver for StaticResolver {
+    fn resolve<'a>(
+        &'a self,
+        query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>> {
+        let recipients = self
+            .0
+            .iter()
+            .filter(|recipient| {
+                query.user_ids.is_empty()
+                    || recipient
+                        .user_id
+                        .as_ref()
+                        .is_some_and(|user_id| query.user_ids.contains(user_id))
+            })
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(recipients) })
+    }
+}
+
+const MAX_USER_IDS: usize = 1000;
+
+/// `user_ids` from a request body, checked so they are safe inside a
+/// PostgREST `in.(...)` filter.
+fn requested_user_ids(user_ids: &[String]) -> Result<Vec<String>, ApiError> {
+    if user_ids.is_empty() || user_ids.len() > MAX_USER_IDS {
+        return Err(ApiError::BadRequest(format!(
+            "user_ids must hold 1 to {MAX_USER_IDS} ids"
+        )));
+    }
+    if let Some(position) = user_ids.iter().position(|user_id| {
+        user_id.is_empty()
+            || !user_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }) {
+        return Err(ApiError::BadRequest(format!(
+            "user_ids[{position}] must be letters, digits, - or _"
+        )));
+    }
+    let mut seen = HashSet::new();
+    Ok(user_ids
+        .iter()
+        .filter(|user_id| seen.insert(user_id.as_str()))
+        .cloned()
+        .collect())
+}
+
+/// A resolver and the query to run it with.
+pub type RequestedAudience = (Box<dyn AudienceResolver>, AudienceQuery);
+
+/// The resolver and query for the audience selected in a request body:
+/// `audience_rpc`, `audience`, `audience_snapshot` or `user_ids`. `None`
+/// when the body addresses explicit tokens instead.
+pub fn requested_audience(
+    secrets: &HashMap<String, String>,
+    selector: &AudienceSelector,
+) -> Result<Option<RequestedAudience>, ApiError> {
+    let user_ids = selector
+        .user_ids
+        .as_deref()
+        .map(requested_user_ids)
+        .transpose()?;
+    if user_ids.is_some() && (selector.audience.is_some() || selector.audience_snapshot.is_some()) {
+        return Err(ApiError::BadRequest(
+            "user_ids cannot be combined with audience or audience_snapshot".into(),
+        ));
+    }
+
+    if let Some(function_name) = &selector.audience_rpc {
+        let resolver = RpcResolver {
+            secrets: secrets.clone(),
+            function_name: function_name.clone(),
+            args: selector
+                .audience_rpc_args
+                .clone()
+                .unwrap_or_else(|| json!({})),
+        };
+        let query = AudienceQuery {
+            user_ids: user_ids.unwrap_or_default(),
+            ..AudienceQuery::default()
+        };
+        return Ok(Some((Box::new(resolver), query)));
+    }
+
+    if let Some(name) = &selector.audience {
+        let resolver = SupabaseFilterResolver(initialize_supabase_client(secrets)?);
+        let query = AudienceQuery {
+            segment: Some(name.clone()),
+            ..AudienceQuery::default()
+        };
+        return Ok(Some((Box::new(resolver), query)));
+    }
+
+    if let Some(snapshot_id) = &selector.audience_snapshot {
+        let resolver = SnapshotResolver {
+            client: initialize_supabase_client(secrets)?,
+            snapshot_id: snapshot_id.clone(),
+        };
+        return Ok(Some((Box::new(resolver), AudienceQuery::default())));
+    }
+
+    if let Some(user_ids) = user_ids {
+        let resolver = SupabaseFilterResolver(initialize_supabase_client(secrets)?);
+        let query = AudienceQuery {
+            user_ids,
+            ..AudienceQuery::default()
+        };
+        return Ok(Some((Box::new(resolver), query)));
+    }
+
+    Ok(None)
+}
+
+/// Resolves the audience selected in a request body, if any. Returns
+/// `Ok(None)` when the body addresses explicit tokens instead. A token
+/// listed more than once, e.g. for two selected users sharing a device, is
+/// kept once.
+pub async fn resolve_requested_audience(
+    secrets: &HashMap<String, String>,
+    selector: &AudienceSelector,
+) -> Result<Option<Vec<String>>, ApiError> {
+    let Some((resolver, query)) = requested_audience(secrets, selector)? else {
+        return Ok(None);
+    };
+    let recipients = resolver.resolve(&query).await?;
+    let mut seen = HashSet::new();
+    Ok(Some(
+        recipients
+            .into_iter()
+            .map(|recipient| recipient.expo_push_token)
+            .filter(|token| seen.insert(token.clone()))
+            .collect(),
+    ))
+}
+
+/// Freezes the resolved audience into `audience_snapshots` so a large
+/// broadcast (and any re-run of it) targets exactly the same recipients.
+#[instrument(skip(secrets, selector))]
+pub async fn create_audience_snapshot(
+    secrets: &HashMap<String, String>,
+    selector: &AudienceSelector,
+) -> Result<Value, ApiError> {
+    if selector.audience_snapshot.is_some() {
+        return Err(ApiError::BadRequest(
+            "A snapshot cannot be taken of another snapshot".into(),
+        ));
+    }
+    let tokens = resolve_requested_audience(secrets, selector)
+        .await?
+        .ok_or_else(|| {
+            ApiError::BadRequest("audience, audience_rpc or user_ids is required".into())
+        })?;
+
+    let client = initialize_supabase_client(secrets)?;
+    let token_count = tokens.len();
+    let snapshot = json!({
+        "id": Uuid::new_v4().to_string(),
+        "source": selector.audience.as_ref().or(selector.audience_rpc.as_ref()),
+        "token_count": token_count,
+        "tokens": tokens,
+    });
+    client
+        .insert("audience_snapshots", snapshot.clone())
+        .timed("insert audience_snapshots")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error storing audience snapshot");
+            ApiError::SupabaseWrite
+        })?;
+
+    info!(
+        snapshot_id = %snapshot["id"],
+        token_count,
+        "Stored audience snapshot"
+    );
+    Ok(json!({
+        "id": snapshot["id"],
+        "source": snapshot["source"],
+        "token_count": snapshot["token_count"],
+    }))
+}
+
+#[instrument(skip(client))]
+pub async fn load_audience_snapshot(
+    client: &SupabaseClient,
+    snapshot_id: &str,
+) -> Result<Vec<Recipient>, ApiError> {
+    let rows = client
+        .select("audience_snapshots")
+        .eq("id", snapshot_id)
+        .execute()
+        .timed("select audience_snapshots")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching audience snapshot");
+            ApiError::SupabaseFetch
+        })?;
+
+    let snapshot = rows
+        .first()
+        .ok_or_else(|| ApiError::BadRequest(format!("Unknown audience_snapshot: {snapshot_id}")))?;
+    let recipients = snapshot["tokens"]
+        .as_array()
+        .map(|tokens| recipients_from_rows(tokens))
+        .unwrap_or_default();
+    info!(token_count = recipients.len(), "Loaded audience snapshot");
+    Ok(recipients)
+}
+
+/// Keeps roughly `percent` of `tokens`. Whether a token is kept depends only
+/// on the token and `seed`, so re-running a load test with the same seed hits
+/// the same devices, and a larger percentage is a superset of a smaller one.
+pub fn sample_audience(tokens: Vec<String>, percent: f64, seed: &str) -> Vec<String> {
+    // Basis points, so fractional percentages such as 0.5 work.
+    let threshold = (percent * 100.0).round() as u64;
+    tokens
+        .into_iter()
+        .filter(|token| {
+            let digest = Sha256::digest(format!("{seed}:{token}").as_bytes());
+            let bucket = u64::from_be_bytes(digest[..8].try_into().expect("8 byte prefix"));
+            bucket % 10_000 < threshold
+        })
+        .collect()
+}
+
+const ESTIMATE_SAMPLE_SIZE: usize = 5;
+/// Characters of the device id [`mask_token`] leaves readable.
+const MASKED_TOKEN_VISIBLE_CHARS: usize = 4;
+
+/// `ExponentPushToken[abcd…`: enough to tell sampled devices apart without
+/// handing out tokens that can be sent to.
+fn mask_token(token: &str) -> String {
+    let visible = token.find('[').map_or(0, |open| open + 1) + MASKED_TOKEN_VISIBLE_CHARS;
+    let end = token
+        .char_indices()
+        .nth(visible)
+        .map_or(token.len(), |(end, _)| end);
+    format!("{}…", &token[..end])
+}
+
+/// Runs the audience resolution of a send without sending: the body's
+/// selector, else the subscribers of `query.topic`, else the `users`
+/// filters in `query`, else every token. Invalid and repeated tokens are
+/// dropped and, as on the send path, so are users who opted out of
+/// `category`. The sample holds masked tokens only.
+#[instrument(skip(secrets, selector, query))]
+pub async fn estimate_audience(
+    secrets: &HashMap<String, String>,
+    selector: &AudienceSelector,
+    query: &AudienceQuery,
+    category: Option<&str>,
+) -> Result<Value, ApiError> {
+    if query.topic.is_some() && query.has_user_filters() {
+        return Err(ApiError::BadRequest(
+            "topic cannot be combined with audience filters".into(),
+        ));
+    }
+    let mut tokens = match resolve_requested_audience(secrets, selector).await? {
+        Some(_) if !query.is_empty() => {
+            return Err(ApiError::BadRequest(
+                "topic and audience filters cannot be combined with an audience selector".into(),
+            ));
+        }
+        Some(tokens) => tokens,
+        None if query.topic.is_some() => tokens_of(
+            TopicResolver(initialize_supabase_client(secrets)?)
+                .resolve(query)
+                .await?,
+        ),
+        None if !query.is_empty() => tokens_of(
+            SupabaseFilterResolver(initialize_supabase_client(secrets)?)
+                .resolve(query)
+                .await?,
+        ),
+        None => token_store(secrets).await?.fetch_tokens().await?,
+    };
+
+    let skipped = normalize_tokens(&mut tokens);
+    let opted_out = if is_supabase_store() {
+        exclude_opted_out(initialize_supabase_client(secrets), &mut tokens, category).await?
+    } else {
+        0
+    };
+
+    info!(
+        token_count = tokens.len(),
+        opted_out, "Estimated audience size"
+    );
+    Ok(json!({
+        "recipient_count": tokens.len(),
+        "opted_out": opted_out,
+        "skipped_tokens": skipped,
+        "sample": tokens
+            .iter()
+            .take(ESTIMATE_SAMPLE_SIZE)
+            .map(|token| mask_token(token))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+fn tokens_of(recipients: Vec<Recipient>) -> Vec<String> {
+    recipients
+        .into_iter()
+        .map(|recipient| recipient.expo_push_token)
+        .collect()
+}
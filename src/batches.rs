@@ -0,0 +1,144 @@
+//! Progress of asynchronous sends (see [`crate::send_queue`]) for
+//! `GET /batches/{id}`. The `send_batches` table holds each batch's state,
+//! moved along by the API when it enqueues and by the worker as it sends;
+//! the counts come from the rows the sends themselves leave behind, since
+//! a batch is sent under its id as the `job_id`.
+
+use crate::http_handler::{select_all_pages, ApiError};
+use crate::metrics::Timed;
+use chrono::Utc;
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+
+/// Where an asynchronous send is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchState {
+    /// On the queue, or back on it after a partial send, for the worker.
+    Queued,
+    /// Picked up by the worker.
+    Sending,
+    Done,
+    /// Dropped by the worker, e.g. a body that no longer validates.
+    Failed,
+}
+
+impl BatchState {
+    fn as_str(self) -> &'static str {
+        match self {
+            BatchState::Queued => "queued",
+            BatchState::Sending => "sending",
+            BatchState::Done => "done",
+            BatchState::Failed => "failed",
+        }
+    }
+}
+
+/// Records that batch `batch_id` is now in `state`. `route` is only known
+/// when it is enqueued.
+#[instrument(skip(client))]
+pub async fn set_batch_state(
+    client: &SupabaseClient,
+    batch_id: &str,
+    state: BatchState,
+    route: Option<&str>,
+) -> Result<(), ApiError> {
+    let mut row = json!({
+        "state": state.as_str(),
+        "updated_at": Utc::now().to_rfc3339(),
+    });
+    if let Some(route) = route {
+        row["route"] = json!(route);
+        row["enqueued_at"] = row["updated_at"].clone();
+    }
+    client
+        .upsert("send_batches", batch_id, row)
+        .timed("upsert send_batches")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error recording batch state");
+            ApiError::SupabaseWrite
+        })?;
+    info!(state = state.as_str(), "Recorded batch state");
+    Ok(())
+}
+
+async fn rows_for_job(
+    client: &SupabaseClient,
+    table: &str,
+    columns: Vec<&str>,
+    job_id: &str,
+) -> Result<Vec<Value>, ApiError> {
+    let description = format!("select {table}");
+    select_all_pages(
+        || {
+            client
+                .select(table)
+                .columns(columns.clone())
+                .eq("job_id", job_id)
+        },
+        &description,
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, table, "Error fetching batch progress");
+        ApiError::SupabaseFetch
+    })
+}
+
+/// The state and counts of batch `batch_id`, `None` if it is unknown:
+/// `tickets` Expo accepted, `receipts` resolved so far out of those, and
+/// `errors`, the messages that got no ticket plus the error receipts.
+#[instrument(skip(client))]
+pub async fn load_batch(
+    client: &SupabaseClient,
+    batch_id: &str,
+) -> Result<Option<Value>, ApiError> {
+    let rows = client
+        .select("send_batches")
+        .eq("id", batch_id)
+        .execute()
+        .timed("select send_batches")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching send batch");
+            ApiError::SupabaseFetch
+        })?;
+    let Some(batch) = rows.first() else {
+        return Ok(None);
+    };
+
+    // One log row per invocation, so resumed sends add up.
+    let sends = rows_for_job(
+        client,
+        "notification_log",
+        vec!["id", "ticket_summary"],
+        batch_id,
+    )
+    .await?;
+    let sum = |field: &str| {
+        sends
+            .iter()
+            .filter_map(|send| send["ticket_summary"][field].as_u64())
+            .sum::<u64>()
+    };
+    let tickets = rows_for_job(client, "push_tickets", vec!["id", "status"], batch_id).await?;
+    let status_count = |status: &str| {
+        tickets
+            .iter()
+            .filter(|ticket| ticket["status"].as_str() == Some(status))
+            .count() as u64
+    };
+    let resolved = tickets.len() as u64 - status_count("pending");
+
+    Ok(Some(json!({
+        "id": batch_id,
+        "state": batch["state"],
+        "route": batch["route"],
+        "enqueued_at": batch["enqueued_at"],
+        "updated_at": batch["updated_at"],
+        "tickets": sum("accepted"),
+        "receipts": resolved,
+        "errors": sum("failed") + status_count("error"),
+    })))
+}
@@ -0,0 +1,17 @@
+//! Lambda entry point for the SQS send queue: consumes what `?async=true`
+//! requests enqueued and performs the Expo sends.
+
+use expo_push_notification_api::router::consume_send_queue;
+use expo_push_notification_api::{build_info, http_client, logging, AppState};
+use lambda_http::lambda_runtime::{self, service_fn};
+use lambda_http::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    logging::init();
+    build_info::log_startup();
+    http_client::configure_sdk_clients();
+
+    let state = AppState::load().await?;
+    lambda_runtime::run(service_fn(|event| consume_send_queue(&state, event))).await
+}
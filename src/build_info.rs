@@ -0,0 +1,48 @@
+use chrono::DateTime;
+use serde::Serialize;
+use std::env;
+use tracing::info;
+
+/// What is running: the build, from `build.rs`, and the configuration it was
+/// started with.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub built_at: String,
+    pub features: Vec<&'static str>,
+    /// `CONFIG_PROFILE`, e.g. `dev` or `prod`.
+    pub config_profile: String,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("BUILD_GIT_SHA"),
+        built_at: env!("BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .map(|built_at| built_at.to_rfc3339())
+            .unwrap_or_default(),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        config_profile: env::var("CONFIG_PROFILE").unwrap_or_else(|_| "default".into()),
+    }
+}
+
+/// Logs [`build_info`] once per container, on cold start.
+pub fn log_startup() {
+    let info = build_info();
+    info!(
+        event = "startup",
+        version = info.version,
+        git_sha = info.git_sha,
+        built_at = %info.built_at,
+        features = ?info.features,
+        config_profile = %info.config_profile,
+        "Expo push notification API starting"
+    );
+}
@@ -0,0 +1,116 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use chrono::Utc;
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Related notifications, e.g. one to each partner of a couple, are few;
+/// anything larger is a broadcast.
+pub const MAX_BUNDLE_MESSAGES: usize = 10;
+
+/// Records a bundle whose messages all passed validation, before any of them
+/// is sent, and returns its id.
+#[instrument(skip(client))]
+pub async fn create_bundle(
+    client: &SupabaseClient,
+    message_count: usize,
+) -> Result<String, ApiError> {
+    let id = Uuid::new_v4().to_string();
+    client
+        .insert(
+            "notification_bundles",
+            json!({
+                "id": id,
+                "status": "sending",
+                "message_count": message_count,
+                "messages": [],
+                "created_at": Utc::now().to_rfc3339(),
+            }),
+        )
+        .timed("insert notification_bundles")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error creating notification bundle");
+            ApiError::SupabaseWrite
+        })?;
+    info!(bundle_id = %id, message_count, "Accepted notification bundle");
+    Ok(id)
+}
+
+/// `sent` when every message was sent, `failed` when none was, `partial`
+/// otherwise.
+fn bundle_status(messages: &[Value]) -> &'static str {
+    let sent = messages
+        .iter()
+        .filter(|message| message["status"] == "sent")
+        .count();
+    match sent {
+        0 => "failed",
+        sent if sent == messages.len() => "sent",
+        _ => "partial",
+    }
+}
+
+/// Stores the outcome of every message and the resulting bundle status
+/// (see [`bundle_status`]).
+#[instrument(skip(client, messages))]
+pub async fn finish_bundle(
+    client: &SupabaseClient,
+    id: &str,
+    messages: &[Value],
+) -> Result<&'static str, ApiError> {
+    let status = bundle_status(messages);
+    client
+        .update(
+            "notification_bundles",
+            id,
+            json!({
+                "status": status,
+                "messages": messages,
+                "finished_at": Utc::now().to_rfc3339(),
+            }),
+        )
+        .timed("update notification_bundles")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error updating notification bundle");
+            ApiError::SupabaseWrite
+        })?;
+    Ok(status)
+}
+
+#[instrument(skip(client))]
+pub async fn load_bundle(client: &SupabaseClient, id: &str) -> Result<Option<Value>, ApiError> {
+    let rows = client
+        .select("notification_bundles")
+        .eq("id", id)
+        .execute()
+        .timed("select notification_bundles")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching notification bundle");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(rows.into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundle_status_is_sent_only_when_every_message_was() {
+        let sent = json!({ "status": "sent" });
+        let failed = json!({ "status": "failed" });
+        assert_eq!(bundle_status(&[sent.clone(), sent.clone()]), "sent");
+        assert_eq!(bundle_status(&[sent, failed.clone()]), "partial");
+        assert_eq!(bundle_status(&[failed.clone(), failed]), "failed");
+    }
+
+    #[test]
+    fn bundle_status_of_an_empty_bundle_is_failed() {
+        assert_eq!(bundle_status(&[]), "failed");
+    }
+}
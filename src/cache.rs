@@ -0,0 +1,101 @@
+use crate::http_handler::tenant_id;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::{HeaderValue, Method, StatusCode};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    expires_at: Instant,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+/// Keyed by tenant and request URI, so one tenant never sees another's data.
+static CACHE: Mutex<BTreeMap<String, CachedResponse>> = Mutex::new(BTreeMap::new());
+
+fn ttl() -> Duration {
+    env::var("RESPONSE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CACHE_TTL)
+}
+
+fn with_cache_headers(mut response: Response, ttl: Duration, hit: bool) -> Response {
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("private, max-age={}", ttl.as_secs())) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+    headers.insert(
+        "x-cache",
+        HeaderValue::from_static(if hit { "HIT" } else { "MISS" }),
+    );
+    response
+}
+
+/// Route layer for read-only endpoints the dashboard polls every few seconds.
+/// Successful `GET` responses are served from memory for
+/// `RESPONSE_CACHE_TTL_SECS` (default 5), and carry a matching
+/// `Cache-Control` header; `0` disables the cache.
+///
+/// Use with [`axum::middleware::from_fn`].
+pub async fn cache_response(request: Request, next: Next) -> Response {
+    let ttl = ttl();
+    if request.method() != Method::GET || ttl.is_zero() {
+        return next.run(request).await;
+    }
+
+    let key = format!("{}:{}", tenant_id(request.extensions()), request.uri());
+    let cached = CACHE
+        .lock()
+        .expect("response cache poisoned")
+        .get(&key)
+        .filter(|entry| entry.expires_at > Instant::now())
+        .cloned();
+    if let Some(entry) = cached {
+        debug!(key = %key, "Serving cached response");
+        let mut response = Body::from(entry.body).into_response();
+        if let Some(content_type) = entry.content_type {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        return with_cache_headers(response, ttl, true);
+    }
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(error = %e, "Failed to buffer response for caching");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let now = Instant::now();
+    let mut cache = CACHE.lock().expect("response cache poisoned");
+    cache.retain(|_, entry| entry.expires_at > now);
+    cache.insert(
+        key,
+        CachedResponse {
+            expires_at: now + ttl,
+            content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+            body: body.clone(),
+        },
+    );
+    drop(cache);
+
+    with_cache_headers(Response::from_parts(parts, Body::from(body)), ttl, false)
+}
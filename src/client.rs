@@ -0,0 +1,120 @@
+//! Typed async client for this API, for other Rust services. Built with the
+//! `client` feature; requests and responses use the same types as the
+//! server, so the two cannot drift apart.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), expo_push_notification_api::client::ClientError> {
+//! use expo_push_notification_api::client::NotificationsClient;
+//! use expo_push_notification_api::models::SendRequest;
+//!
+//! let client = NotificationsClient::new("https://push.example.com", "api-key");
+//! let response = client
+//!     .send(&SendRequest {
+//!         title: Some("hi".into()),
+//!         body: Some("hello".into()),
+//!         audience: Some("active_premium_users".into()),
+//!         ..SendRequest::default()
+//!     })
+//!     .await?;
+//! println!("{} sent, {} failed", response.sent, response.failed);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::jobs::JobStatus;
+use crate::models::{SendRequest, SendResponse};
+use crate::tokens::{RegisterTokenRequest, Registration};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The API answered with an error body, see
+    /// [`crate::http_handler::ApiError`] for the codes.
+    #[error("API error {status} ({error_code}): {error}")]
+    Api {
+        status: StatusCode,
+        error_code: String,
+        error: String,
+        body: Value,
+    },
+}
+
+/// A client for one deployment, authenticated with one API key.
+#[derive(Debug, Clone)]
+pub struct NotificationsClient {
+    http: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl NotificationsClient {
+    pub fn new(base_url: impl Into<String>, api_key: impl Into<String>) -> Self {
+        Self::with_http_client(Client::new(), base_url, api_key)
+    }
+
+    /// Like [`NotificationsClient::new`], reusing the caller's `reqwest`
+    /// client and its proxy, timeout and TLS settings.
+    pub fn with_http_client(
+        http: Client,
+        base_url: impl Into<String>,
+        api_key: impl Into<String>,
+    ) -> Self {
+        Self {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{path}", self.base_url))
+            .header("x-api-key", &self.api_key)
+    }
+
+    async fn execute<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let body = response.json::<Value>().await.unwrap_or_default();
+        Err(ClientError::Api {
+            status,
+            error_code: body["error_code"].as_str().unwrap_or_default().to_string(),
+            error: body["error"].as_str().unwrap_or_default().to_string(),
+            body,
+        })
+    }
+
+    /// `POST /send`. The response includes `job_id` and the per-token
+    /// `results`. A partially failed broadcast comes back as
+    /// [`ClientError::Api`] with code `send_failed`.
+    pub async fn send(&self, request: &SendRequest) -> Result<SendResponse, ClientError> {
+        self.execute(self.request(reqwest::Method::POST, "/send").json(request))
+            .await
+    }
+
+    /// `POST /tokens`.
+    pub async fn register_token(
+        &self,
+        request: &RegisterTokenRequest,
+    ) -> Result<Registration, ClientError> {
+        self.execute(self.request(reqwest::Method::POST, "/tokens").json(request))
+            .await
+    }
+
+    /// `GET /jobs/{id}`, the progress of a checkpointed broadcast.
+    pub async fn get_job(&self, job_id: &str) -> Result<JobStatus, ClientError> {
+        self.execute(self.request(reqwest::Method::GET, &format!("/jobs/{job_id}")))
+            .await
+    }
+}
@@ -0,0 +1,179 @@
+use crate::http_handler::ApiError;
+use crate::secrets::fetch_parameters_by_path;
+use crate::templates::LocalizedText;
+use expo_push_notification_client::Priority;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_CONFIG_TTL: Duration = Duration::from_secs(60);
+
+/// Non-secret settings that can change without a redeploy. Read from the SSM
+/// parameters under `CONFIG_PARAMETER_PATH`:
+///
+/// - `feature-flags`: comma-separated list of enabled flags
+/// - `default-title` / `default-body`: message for `/scheduled`, see
+///   [`DynamicConfig::default_message`]
+/// - `localized-messages`: JSON object mapping a `users.locale` value to the
+///   [`LocalizedText`] of that message, e.g.
+///   `{"en": {"title": "It's the 25th", "body": "Time to bill your partner"}}`
+/// - `max-recipients`: upper bound on the audience of a single request
+/// - `allowed-sounds`: comma-separated custom sounds bundled in the app;
+///   `default` is always allowed
+/// - `category-policies`: JSON object mapping a category to its
+///   [`CategoryPolicy`], e.g.
+///   `{"billing": {"priority": "high", "channel_id": "billing", "sound": "billing.wav"}}`
+/// - `success-messages`: JSON object mapping a language (`en`, `ja`) to
+///   overrides for the success `message` texts, keyed as in
+///   [`crate::i18n::success_key`], e.g. `{"ja": {"sent": "送信しました"}}`
+#[derive(Debug, Default)]
+pub struct DynamicConfig {
+    pub feature_flags: HashSet<String>,
+    pub default_title: Option<String>,
+    pub default_body: Option<String>,
+    pub localized_messages: HashMap<String, LocalizedText>,
+    pub max_recipients: Option<usize>,
+    pub allowed_sounds: Vec<String>,
+    pub category_policies: HashMap<String, CategoryPolicy>,
+    pub success_messages: HashMap<String, HashMap<String, String>>,
+}
+
+/// Delivery defaults for a category, applied to every send that names it so
+/// callers don't have to know about Android channels or APNs priorities.
+/// Fields set on the request itself take precedence.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CategoryPolicy {
+    pub sound: Option<String>,
+    pub channel_id: Option<String>,
+    pub priority: Option<Priority>,
+}
+
+impl DynamicConfig {
+    /// Title and body of the scheduled broadcast: the `default-title` and
+    /// `default-body` parameters, falling back to the `DEFAULT_TITLE` and
+    /// `DEFAULT_BODY` env vars, so each deployment brings its own wording
+    /// and locale.
+    pub fn default_message(&self) -> Result<(String, String), ApiError> {
+        let setting = |parameter: &Option<String>, var: &str| {
+            parameter
+                .clone()
+                .or_else(|| env::var(var).ok())
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| ApiError::MissingEnvVar(var.into()))
+        };
+        Ok((
+            setting(&self.default_title, "DEFAULT_TITLE")?,
+            setting(&self.default_body, "DEFAULT_BODY")?,
+        ))
+    }
+
+    /// Translations of [`DynamicConfig::default_message`]: the
+    /// `localized-messages` parameter, falling back to the same JSON in the
+    /// `LOCALIZED_MESSAGES` env var.
+    pub fn default_message_locales(&self) -> HashMap<String, LocalizedText> {
+        if !self.localized_messages.is_empty() {
+            return self.localized_messages.clone();
+        }
+        env::var("LOCALIZED_MESSAGES")
+            .ok()
+            .filter(|messages| !messages.is_empty())
+            .and_then(|messages| {
+                serde_json::from_str(&messages)
+                    .inspect_err(|e| warn!(error = %e, "Ignoring malformed LOCALIZED_MESSAGES"))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+}
+
+static CACHE: Mutex<Option<(Instant, Arc<DynamicConfig>)>> = Mutex::new(None);
+
+fn ttl() -> Duration {
+    env::var("CONFIG_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CONFIG_TTL)
+}
+
+async fn load() -> Option<DynamicConfig> {
+    let path = env::var("CONFIG_PARAMETER_PATH").ok()?;
+    let parameters = match fetch_parameters_by_path(&path).await {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            warn!(error = %e, "Failed to refresh dynamic config");
+            return None;
+        }
+    };
+
+    Some(DynamicConfig {
+        feature_flags: parameters
+            .get("feature-flags")
+            .map(|flags| split_list(flags).collect())
+            .unwrap_or_default(),
+        default_title: parameters.get("default-title").cloned(),
+        default_body: parameters.get("default-body").cloned(),
+        localized_messages: parameters
+            .get("localized-messages")
+            .and_then(|messages| {
+                serde_json::from_str(messages)
+                    .inspect_err(|e| warn!(error = %e, "Ignoring malformed localized-messages"))
+                    .ok()
+            })
+            .unwrap_or_default(),
+        max_recipients: parameters
+            .get("max-recipients")
+            .and_then(|max| max.parse().ok()),
+        allowed_sounds: parameters
+            .get("allowed-sounds")
+            .map(|sounds| split_list(sounds).collect())
+            .unwrap_or_default(),
+        category_policies: parameters
+            .get("category-policies")
+            .and_then(|policies| {
+                serde_json::from_str(policies)
+                    .inspect_err(|e| warn!(error = %e, "Ignoring malformed category-policies"))
+                    .ok()
+            })
+            .unwrap_or_default(),
+        success_messages: parameters
+            .get("success-messages")
+            .and_then(|messages| {
+                serde_json::from_str(messages)
+                    .inspect_err(|e| warn!(error = %e, "Ignoring malformed success-messages"))
+                    .ok()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+}
+
+/// Current dynamic config, re-resolved once the cached copy is older than
+/// `CONFIG_TTL_SECS` (default 60). A failed refresh keeps serving the
+/// previous copy.
+pub async fn dynamic_config() -> Arc<DynamicConfig> {
+    let cached = CACHE.lock().expect("config cache poisoned").clone();
+    if let Some((loaded_at, config)) = &cached {
+        if loaded_at.elapsed() < ttl() {
+            return config.clone();
+        }
+    }
+
+    match load().await {
+        Some(config) => {
+            let config = Arc::new(config);
+            info!(feature_flags = ?config.feature_flags, "Loaded dynamic config");
+            *CACHE.lock().expect("config cache poisoned") = Some((Instant::now(), config.clone()));
+            config
+        }
+        None => cached.map(|(_, config)| config).unwrap_or_default(),
+    }
+}
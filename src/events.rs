@@ -0,0 +1,170 @@
+use crate::http_client::aws_sdk_config;
+use crate::models::{TicketResult, TicketStatus};
+use aws_sdk_firehose::primitives::Blob;
+use aws_sdk_firehose::types::Record;
+use aws_sdk_firehose::Client as FirehoseClient;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use chrono::Utc;
+use expo_push_notification_client::{CustomError, ExpoPushTicket};
+use serde_json::{json, Value};
+use std::env;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+const FIREHOSE_MAX_BATCH_RECORDS: usize = 500;
+
+/// Append-only log of what happened during one invocation (sends, prunes,
+/// ...). Flushed as a single NDJSON object under
+/// `s3://$EVENT_LOG_BUCKET/$EVENT_LOG_PREFIX/dt=YYYY-MM-DD/`, which Athena can
+/// query as a date-partitioned table, or streamed to Firehose instead.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: Vec<Value>,
+}
+
+impl EventLog {
+    pub fn record(&mut self, event_type: &str, mut fields: Value) {
+        if let Some(fields) = fields.as_object_mut() {
+            fields.insert("type".into(), json!(event_type));
+            fields.insert("ts".into(), json!(Utc::now().to_rfc3339()));
+        }
+        self.events.push(fields);
+    }
+
+    pub fn to_ndjson(&self) -> String {
+        self.events
+            .iter()
+            .map(|event| format!("{event}\n"))
+            .collect()
+    }
+
+    /// Writes the buffered events to the configured sink: Firehose when
+    /// `EVENT_FIREHOSE_STREAM_ARN` is set, otherwise S3 when
+    /// `EVENT_LOG_BUCKET` is set. Failures are logged only; losing analytics
+    /// must never fail a send.
+    #[instrument(skip(self), fields(event_count = self.events.len()))]
+    pub async fn flush(self) {
+        if self.events.is_empty() {
+            return;
+        }
+        if let Ok(stream_arn) = env::var("EVENT_FIREHOSE_STREAM_ARN") {
+            self.flush_to_firehose(&stream_arn).await;
+        } else if let Ok(bucket) = env::var("EVENT_LOG_BUCKET") {
+            self.flush_to_s3(&bucket).await;
+        }
+    }
+
+    async fn flush_to_s3(&self, bucket: &str) {
+        let prefix = env::var("EVENT_LOG_PREFIX").unwrap_or_else(|_| "events".into());
+        let now = Utc::now();
+        let key = format!(
+            "{}/dt={}/{}-{}.ndjson",
+            prefix.trim_end_matches('/'),
+            now.format("%Y-%m-%d"),
+            now.format("%H%M%S"),
+            Uuid::new_v4()
+        );
+
+        let config = aws_sdk_config().await;
+        let result = S3Client::new(config)
+            .put_object()
+            .bucket(bucket)
+            .key(&key)
+            .content_type("application/x-ndjson")
+            .body(ByteStream::from(self.to_ndjson().into_bytes()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => info!(bucket = %bucket, key = %key, "Wrote event log to S3"),
+            Err(e) => error!(error = ?e, bucket = %bucket, "Failed to write event log to S3"),
+        }
+    }
+
+    /// One Firehose record per event, newline-terminated so the delivered
+    /// objects stay valid NDJSON.
+    async fn flush_to_firehose(&self, stream_arn: &str) {
+        // PutRecordBatch addresses streams by name: arn:...:deliverystream/<name>
+        let stream_name = stream_arn.rsplit('/').next().unwrap_or(stream_arn);
+        let config = aws_sdk_config().await;
+        let client = FirehoseClient::new(config);
+
+        for batch in self.events.chunks(FIREHOSE_MAX_BATCH_RECORDS) {
+            let records = batch
+                .iter()
+                .map(|event| {
+                    Record::builder()
+                        .data(Blob::new(format!("{event}\n")))
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>();
+            let records = match records {
+                Ok(records) => records,
+                Err(e) => {
+                    error!(error = ?e, "Failed to build Firehose records");
+                    return;
+                }
+            };
+
+            match client
+                .put_record_batch()
+                .delivery_stream_name(stream_name)
+                .set_records(Some(records))
+                .send()
+                .await
+            {
+                Ok(output) if output.failed_put_count() > 0 => error!(
+                    failed = output.failed_put_count(),
+                    stream = %stream_name,
+                    "Firehose rejected some event records"
+                ),
+                Ok(_) => {
+                    info!(stream = %stream_name, records = batch.len(), "Sent events to Firehose")
+                }
+                Err(e) => {
+                    error!(error = ?e, stream = %stream_name, "Failed to send events to Firehose")
+                }
+            }
+        }
+    }
+}
+
+/// One recipient's ticket as `{token, status, ticket_id}` or
+/// `{token, status, error}`, as returned to callers of the send endpoints.
+pub fn ticket_result(
+    token: &str,
+    result: &Result<Vec<ExpoPushTicket>, CustomError>,
+) -> TicketResult {
+    let (status, ticket_id, error) = match result {
+        Ok(tickets) => match tickets.first() {
+            Some(ExpoPushTicket::Ok(ticket)) => {
+                (TicketStatus::Ok, Some(ticket.id.to_string()), None)
+            }
+            Some(ExpoPushTicket::Error(receipt)) => {
+                (TicketStatus::Error, None, Some(receipt.message.clone()))
+            }
+            None => (TicketStatus::Error, None, None),
+        },
+        Err(e) => (TicketStatus::Error, None, Some(e.to_string())),
+    };
+    TicketResult {
+        token: token.to_string(),
+        status,
+        ticket_id,
+        error,
+    }
+}
+
+/// Fields of a `send` event for one recipient's ticket.
+pub fn send_event(
+    token: &str,
+    job_id: Option<&str>,
+    content_hash: &str,
+    result: &Result<Vec<ExpoPushTicket>, CustomError>,
+) -> Value {
+    let mut event = json!(ticket_result(token, result));
+    event["job_id"] = json!(job_id);
+    event["content_hash"] = json!(content_hash);
+    event
+}
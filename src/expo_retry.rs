@@ -0,0 +1,169 @@
+use crate::http_client::http_client;
+use crate::http_handler::deadline_safety_margin;
+use crate::trace_context;
+use expo_push_notification_client::{CustomError, ExpoPushMessage, ExpoPushTicket};
+use http::header::RETRY_AFTER;
+use http::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+const DEFAULT_EXPO_BASE_URL: &str = "https://exp.host";
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(200);
+/// Cap on a single wait, whether backed off or asked for by `Retry-After`.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct SendResponse {
+    data: Vec<ExpoPushTicket>,
+}
+
+/// How persistently a push request is retried: `EXPO_MAX_RETRIES` extra
+/// attempts (default 3), backing off from `EXPO_RETRY_BASE_MS` (default
+/// 200).
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            max_retries: env::var("EXPO_MAX_RETRIES")
+                .ok()
+                .and_then(|retries| retries.parse().ok())
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+            base: env::var("EXPO_RETRY_BASE_MS")
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_RETRY_BASE),
+        }
+    }
+
+    /// Full jitter: a random wait up to `base * 2^retry`, so containers
+    /// retrying at once do not hit Expo in lockstep.
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_RETRY_DELAY);
+        let fraction = (Uuid::new_v4().as_u128() % 1_000) as f64 / 1_000.0;
+        ceiling.mul_f64(fraction)
+    }
+}
+
+/// Why an attempt failed, and whether trying again may help.
+enum Failure {
+    Retriable {
+        error: CustomError,
+        retry_after: Option<Duration>,
+    },
+    Fatal(CustomError),
+}
+
+/// `EXPO_BASE_URL`, for pointing the service at a stub; defaults to Expo's
+/// production host.
+pub fn expo_base_url() -> String {
+    env::var("EXPO_BASE_URL").unwrap_or_else(|_| DEFAULT_EXPO_BASE_URL.into())
+}
+
+async fn attempt(
+    access_token: Option<&str>,
+    messages: &[ExpoPushMessage],
+) -> Result<Vec<ExpoPushTicket>, Failure> {
+    let mut request = trace_context::inject(
+        http_client().post(format!("{}/--/api/v2/push/send", expo_base_url())),
+    )
+    .json(messages);
+    if let Some(token) = access_token {
+        request = request.bearer_auth(token);
+    }
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(Failure::Retriable {
+                error: CustomError::ServerErr(format!("Request failed: {e}")),
+                retry_after: None,
+            })
+        }
+    };
+
+    let status = response.status();
+    if status.is_success() {
+        return response
+            .json::<SendResponse>()
+            .await
+            .map(|response| response.data)
+            .map_err(|e| {
+                Failure::Fatal(CustomError::DeserializeErr(format!(
+                    "Failed to deserialize response: {e}"
+                )))
+            });
+    }
+    let error = CustomError::ServerErr(format!("Request failed: {status}"));
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(Duration::from_secs);
+        Err(Failure::Retriable { error, retry_after })
+    } else {
+        Err(Failure::Fatal(error))
+    }
+}
+
+/// Sends one request of up to 100 messages to Expo, retrying rate limits
+/// (429), server errors and network failures with exponential backoff.
+/// A `Retry-After` header takes precedence over the backoff. Gives up early
+/// rather than sleep past `deadline`. Tickets come back in message order,
+/// as with [`expo_push_notification_client::Expo::send_push_notifications`].
+#[instrument(skip(secrets, messages), fields(message_count = messages.len()))]
+pub async fn send_with_retry(
+    secrets: &HashMap<String, String>,
+    messages: &[ExpoPushMessage],
+    deadline: Option<SystemTime>,
+) -> Result<Vec<ExpoPushTicket>, CustomError> {
+    let policy = RetryPolicy::from_env();
+    let access_token = secrets.get("expo-access-token").map(String::as_str);
+    let mut retry = 0;
+    loop {
+        let (error, retry_after) = match attempt(access_token, messages).await {
+            Ok(tickets) => return Ok(tickets),
+            Err(Failure::Fatal(error)) => return Err(error),
+            Err(Failure::Retriable { error, retry_after }) => (error, retry_after),
+        };
+        if retry >= policy.max_retries {
+            warn!(error = %error, retries = retry, "Giving up on Expo request");
+            return Err(error);
+        }
+        let delay = retry_after
+            .map(|delay| delay.min(MAX_RETRY_DELAY))
+            .unwrap_or_else(|| policy.backoff(retry));
+        let fits_before_deadline = deadline.is_none_or(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .is_ok_and(|remaining| remaining > delay + deadline_safety_margin())
+        });
+        if !fits_before_deadline {
+            warn!(error = %error, "Not retrying Expo request, too close to the deadline");
+            return Err(error);
+        }
+        retry += 1;
+        info!(
+            error = %error,
+            retry,
+            delay_ms = delay.as_millis() as u64,
+            "Retrying Expo request"
+        );
+        sleep(delay).await;
+    }
+}
@@ -0,0 +1,86 @@
+use crate::api_keys::{Caller, Scope};
+use crate::config::dynamic_config;
+use http::HeaderMap;
+use std::collections::HashSet;
+use std::future::Future;
+use tracing::{info, warn};
+
+/// Flags the pipeline checks:
+///
+/// - `timings`: attach the per-stage `timings` breakdown to send responses,
+///   as `DEBUG_MODE=true` does for every request
+pub const TIMINGS: &str = "timings";
+
+/// Header with `name=on` / `name=off` pairs (comma-separated, or repeated
+/// headers) toggling flags for one request. A bare `name` means `on`.
+pub const OVERRIDE_HEADER: &str = "x-feature-override";
+
+tokio::task_local! {
+    static CURRENT: HashSet<String>;
+}
+
+/// The overrides a request asks for, in header order.
+pub fn overrides_from_headers(headers: &HeaderMap) -> Vec<(String, bool)> {
+    headers
+        .get_all(OVERRIDE_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let (name, state) = item.split_once('=').unwrap_or((item, "on"));
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let enabled = match state.trim() {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return None,
+            };
+            Some((name.to_string(), enabled))
+        })
+        .collect()
+}
+
+/// Flags in effect for a request: the globally enabled `feature-flags` from
+/// the dynamic config, with the request's overrides applied on top.
+/// Overrides are only honoured for a `caller` whose key has the
+/// [`Scope::Admin`] scope, so ordinary clients cannot opt into unreleased
+/// behavior.
+pub async fn resolve(headers: &HeaderMap, caller: Option<&Caller>) -> HashSet<String> {
+    let mut flags = dynamic_config().await.feature_flags.clone();
+    let overrides = overrides_from_headers(headers);
+    if overrides.is_empty() {
+        return flags;
+    }
+    let Some(caller) = caller.filter(|caller| caller.allows(Scope::Admin)) else {
+        warn!(overrides = ?overrides, "Ignoring feature overrides from non-admin caller");
+        return flags;
+    };
+    for (name, enabled) in &overrides {
+        if *enabled {
+            flags.insert(name.clone());
+        } else {
+            flags.remove(name);
+        }
+    }
+    info!(
+        overrides = ?overrides,
+        api_key = %caller.name,
+        "Applied request feature overrides"
+    );
+    flags
+}
+
+/// Runs `future` with `flags` available to [`is_enabled`].
+pub async fn scope<F: Future>(flags: HashSet<String>, future: F) -> F::Output {
+    CURRENT.scope(flags, future).await
+}
+
+/// Whether `flag` is on for the current request. Outside a request scope
+/// (background jobs, the rotation handler) every flag is off.
+pub fn is_enabled(flag: &str) -> bool {
+    CURRENT
+        .try_with(|flags| flags.contains(flag))
+        .unwrap_or(false)
+}
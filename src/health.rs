@@ -0,0 +1,127 @@
+//! Readiness probes behind `GET /health`, so an uptime monitor can tell a
+//! broken dependency (e.g. rotated Supabase credentials) from the Lambda
+//! itself being down.
+
+use crate::build_info::build_info;
+use crate::expo_retry::expo_base_url;
+use crate::http_client::http_client;
+use crate::http_handler::initialize_supabase_client;
+use crate::metrics::Timed;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tracing::{instrument, warn};
+
+/// How long a single dependency may take to answer before it counts as
+/// down.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeStatus {
+    Ok,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    pub status: ProbeStatus,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// `ok` when every dependency answered, `degraded` otherwise.
+    pub status: &'static str,
+    pub version: &'static str,
+    pub supabase: Probe,
+    pub expo: Probe,
+}
+
+impl HealthReport {
+    pub fn healthy(&self) -> bool {
+        self.supabase.status == ProbeStatus::Ok && self.expo.status == ProbeStatus::Ok
+    }
+}
+
+async fn probe(name: &str, check: impl Future<Output = Result<(), String>>) -> Probe {
+    let started = Instant::now();
+    let result = match timeout(PROBE_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no answer within {}s", PROBE_TIMEOUT.as_secs())),
+    };
+    let latency_ms = started.elapsed().as_millis();
+    match result {
+        Ok(()) => Probe {
+            status: ProbeStatus::Ok,
+            latency_ms,
+            error: None,
+        },
+        Err(error) => {
+            warn!(dependency = name, error = %error, "Health probe failed");
+            Probe {
+                status: ProbeStatus::Down,
+                latency_ms,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+/// A one-row select on `users`, which fails on bad credentials as well as
+/// on an unreachable project.
+async fn check_supabase(secrets: &HashMap<String, String>) -> Result<(), String> {
+    let client = initialize_supabase_client(secrets).map_err(|e| e.to_string())?;
+    client
+        .select("users")
+        .columns(vec!["expo_push_token"])
+        .limit(1)
+        .execute()
+        .timed("select users (health)")
+        .await
+        .map(|_| ())
+        .map_err(|_| "select on users failed".to_string())
+}
+
+/// A receipts lookup for no ids: it sends nothing, and any answer short of a
+/// server error means the push API is reachable.
+async fn check_expo(secrets: &HashMap<String, String>) -> Result<(), String> {
+    let mut request = http_client()
+        .post(format!("{}/--/api/v2/push/getReceipts", expo_base_url()))
+        .json(&json!({ "ids": [] }));
+    if let Some(token) = secrets.get("expo-access-token") {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|_| "push API unreachable".to_string())?;
+    if response.status().is_server_error() {
+        return Err(format!("push API answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Probes Supabase and Expo concurrently.
+#[instrument(skip(secrets))]
+pub async fn check_health(secrets: &HashMap<String, String>) -> HealthReport {
+    let (supabase, expo) = tokio::join!(
+        probe("supabase", check_supabase(secrets)),
+        probe("expo", check_expo(secrets)),
+    );
+    let mut report = HealthReport {
+        status: "ok",
+        version: build_info().version,
+        supabase,
+        expo,
+    };
+    if !report.healthy() {
+        report.status = "degraded";
+    }
+    report
+}
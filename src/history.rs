@@ -0,0 +1,179 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use chrono::Utc;
+use expo_push_notification_client::Sound;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Hex SHA-256 of the canonical JSON encoding of `content`. History entries
+/// reference content by this hash instead of repeating it per recipient.
+pub fn content_hash(content: &Value) -> String {
+    // serde_json keeps object keys sorted, so equal content hashes equally.
+    hex::encode(Sha256::digest(content.to_string().as_bytes()))
+}
+
+/// Stores `content` once in `notification_contents`, keyed by its hash.
+/// Storing the same content again is a no-op.
+#[instrument(skip(client, content))]
+pub async fn store_content(
+    client: &SupabaseClient,
+    hash: &str,
+    content: &Value,
+) -> Result<(), ApiError> {
+    client
+        .upsert_without_defined_key(
+            "notification_contents",
+            json!({ "hash": hash, "content": content }),
+        )
+        .timed("upsert notification_contents")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error storing notification content");
+            ApiError::SupabaseWrite
+        })
+}
+
+/// One invocation's worth of a broadcast in `notification_history`: who it
+/// went to, who it failed for, and a reference to its content.
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub job_id: Option<String>,
+    pub content_hash: String,
+    pub sound: Option<Sound>,
+    pub collapse_key: Option<String>,
+    pub category: Option<String>,
+    pub tokens: Vec<String>,
+    pub failed_tokens: Vec<String>,
+}
+
+impl HistoryEntry {
+    fn from_row(row: &Value) -> Option<Self> {
+        let strings = |field: &str| -> Vec<String> {
+            row[field]
+                .as_array()
+                .map(|items| {
+                    items
+                        .iter()
+                        .filter_map(|item| item.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Some(HistoryEntry {
+            job_id: row["job_id"].as_str().map(str::to_string),
+            content_hash: row["content_hash"].as_str()?.to_string(),
+            sound: serde_json::from_value(row["sound"].clone()).ok(),
+            collapse_key: row["collapse_key"].as_str().map(str::to_string),
+            category: row["category"].as_str().map(str::to_string),
+            tokens: strings("tokens"),
+            failed_tokens: strings("failed_tokens"),
+        })
+    }
+}
+
+/// Body of `POST /history/{id}/resend`. Without narrowing, everyone the
+/// original was sent to receives it again.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResendRequest {
+    /// Only recipients whose send failed.
+    #[serde(default)]
+    pub only_failed: bool,
+    /// Only these recipients, out of those the original went to.
+    pub tokens: Option<Vec<String>>,
+}
+
+impl ResendRequest {
+    pub fn recipients(&self, entry: &HistoryEntry) -> Vec<String> {
+        let candidates = if self.only_failed {
+            &entry.failed_tokens
+        } else {
+            &entry.tokens
+        };
+        candidates
+            .iter()
+            .filter(|token| self.tokens.as_ref().is_none_or(|only| only.contains(token)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Records a broadcast and returns its history id.
+#[instrument(skip(client, entry), fields(token_count = entry.tokens.len()))]
+pub async fn record_broadcast(
+    client: &SupabaseClient,
+    entry: &HistoryEntry,
+) -> Result<String, ApiError> {
+    let id = Uuid::new_v4().to_string();
+    client
+        .insert(
+            "notification_history",
+            json!({
+                "id": id,
+                "job_id": entry.job_id,
+                "content_hash": entry.content_hash,
+                "sound": entry.sound,
+                "collapse_key": entry.collapse_key,
+                "category": entry.category,
+                "tokens": entry.tokens,
+                "failed_tokens": entry.failed_tokens,
+                "created_at": Utc::now().to_rfc3339(),
+            }),
+        )
+        .timed("insert notification_history")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error recording notification history");
+            ApiError::SupabaseWrite
+        })?;
+    info!(history_id = %id, "Recorded notification history");
+    Ok(id)
+}
+
+#[instrument(skip(client))]
+pub async fn load_history(
+    client: &SupabaseClient,
+    id: &str,
+) -> Result<Option<HistoryEntry>, ApiError> {
+    let rows = client
+        .select("notification_history")
+        .eq("id", id)
+        .execute()
+        .timed("select notification_history")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching notification history");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(rows.first().and_then(HistoryEntry::from_row))
+}
+
+/// Title and body stored under `hash`. `None` when the content was redacted
+/// before storing (see [`crate::privacy::PrivacyLevel`]) or never stored.
+#[instrument(skip(client))]
+pub async fn load_content(
+    client: &SupabaseClient,
+    hash: &str,
+) -> Result<Option<(String, String)>, ApiError> {
+    let rows = client
+        .select("notification_contents")
+        .eq("hash", hash)
+        .execute()
+        .timed("select notification_contents")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching notification content");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(rows.first().and_then(|row| {
+        let content = &row["content"];
+        Some((
+            content["title"].as_str()?.to_string(),
+            content["body"].as_str()?.to_string(),
+        ))
+    }))
+}
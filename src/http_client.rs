@@ -0,0 +1,99 @@
+use aws_config::{BehaviorVersion, SdkConfig};
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use std::env;
+use std::sync::OnceLock;
+use tokio::sync::OnceCell;
+use tracing::{error, info, warn};
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+static AWS_SDK_CONFIG: OnceCell<SdkConfig> = OnceCell::const_new();
+
+/// Outbound HTTP settings, for environments behind an egress proxy or a
+/// TLS-inspecting gateway:
+///
+/// - `OUTBOUND_PROXY_URL`: proxy for every outbound request
+/// - `OUTBOUND_NO_PROXY`: comma-separated hosts that bypass it
+/// - `EXTRA_CA_CERTS`: path to a PEM bundle trusted in addition to the
+///   built-in roots
+#[derive(Debug, Default)]
+pub struct OutboundConfig {
+    pub proxy_url: Option<String>,
+    pub no_proxy: Option<String>,
+    pub extra_ca_certs: Option<String>,
+}
+
+impl OutboundConfig {
+    pub fn from_env() -> Self {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        Self {
+            proxy_url: var("OUTBOUND_PROXY_URL"),
+            no_proxy: var("OUTBOUND_NO_PROXY"),
+            extra_ca_certs: var("EXTRA_CA_CERTS"),
+        }
+    }
+}
+
+fn build(config: &OutboundConfig) -> Result<Client, String> {
+    let mut builder = Client::builder();
+    if let Some(proxy_url) = &config.proxy_url {
+        let proxy = Proxy::all(proxy_url).map_err(|e| format!("OUTBOUND_PROXY_URL: {e}"))?;
+        builder = builder
+            .proxy(proxy.no_proxy(config.no_proxy.as_deref().and_then(NoProxy::from_string)));
+    }
+    if let Some(path) = &config.extra_ca_certs {
+        let pem = std::fs::read(path).map_err(|e| format!("EXTRA_CA_CERTS {path}: {e}"))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| format!("EXTRA_CA_CERTS {path}: {e}"))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// The shared client for requests this service makes itself (webhooks,
+/// Supabase RPC calls), built once from [`OutboundConfig::from_env`]. A bad
+/// setting is logged and falls back to a default client rather than failing
+/// every request.
+pub fn http_client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        build(&OutboundConfig::from_env()).unwrap_or_else(|e| {
+            error!(error = %e, "Invalid outbound HTTP settings, using defaults");
+            Client::new()
+        })
+    })
+}
+
+/// Region and credentials for the AWS SDK clients, resolved once per
+/// container rather than by every client a warm invocation builds.
+pub async fn aws_sdk_config() -> &'static SdkConfig {
+    AWS_SDK_CONFIG
+        .get_or_init(|| aws_config::load_defaults(BehaviorVersion::v2026_01_12()))
+        .await
+}
+
+/// The Expo and Supabase SDKs build their own `reqwest` clients, which only
+/// pick up a proxy from the standard `HTTPS_PROXY` / `NO_PROXY` variables.
+/// Exports `OUTBOUND_PROXY_URL` there so they go through the same proxy.
+/// Must run at startup, before any SDK client is created.
+pub fn configure_sdk_clients() {
+    let config = OutboundConfig::from_env();
+    if let Some(proxy_url) = &config.proxy_url {
+        if env::var_os("HTTPS_PROXY").is_none() {
+            env::set_var("HTTPS_PROXY", proxy_url);
+        }
+        if let Some(no_proxy) = &config.no_proxy {
+            if env::var_os("NO_PROXY").is_none() {
+                env::set_var("NO_PROXY", no_proxy);
+            }
+        }
+        info!("Routing outbound requests through the configured proxy");
+    }
+    if config.extra_ca_certs.is_some() {
+        warn!("EXTRA_CA_CERTS only applies to webhook and RPC requests; the Expo and Supabase SDK clients use the built-in roots");
+    }
+}
@@ -1,16 +1,74 @@
-use aws_config::BehaviorVersion;
-use aws_sdk_ssm::Client as SsmClient;
-use expo_push_notification_client::{Expo, ExpoClientOptions, ExpoPushMessage};
-use futures::future::join_all;
-use http::{header::HeaderValue, StatusCode};
-use lambda_http::{Body, Error, Request, Response};
-use lambda_http::RequestExt;
-use serde_json::{json, Value};
-use std::collections::HashMap;
+use crate::api_keys::{current_caller, current_tenant, Caller};
+use crate::audience::is_valid_identifier;
+use crate::config::dynamic_config;
+use crate::events::{send_event, ticket_result, EventLog};
+use crate::expo_retry::send_with_retry;
+use crate::history::{content_hash, record_broadcast, store_content, HistoryEntry};
+use crate::jobs::{
+    chunk_size_for, create_checkpointed_job, is_job_aborted, load_or_create_job,
+    mark_chunk_completed,
+};
+use crate::metrics::{
+    is_dead_token, is_expo_error, record_audience_empty, record_invalid_token_rate, record_send,
+    Timed,
+};
+use crate::models::{SendRequest, SendResponse};
+use crate::notification_log::{record_notification, LogEntry};
+use crate::preferences::{exclude_opted_out, notifications_enabled};
+use crate::privacy::PrivacyLevel;
+use crate::receipts::record_tickets;
+use crate::router::AppState;
+use crate::scheduling::{create_paced_slots, has_paced_slots};
+use crate::templates::{load_user_vars, render, user_columns, LocaleVariants, PlatformVariants};
+use crate::timings::Timings;
+use crate::token_store::is_supabase_store;
+use crate::trash::{is_deleted, prune_unregistered_tokens};
+use crate::unsubscribe::{is_marketing_category, unsubscribe_token};
+use crate::webhooks::dispatch_event;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::Utc;
+use expo_push_notification_client::{
+    CustomError, DetailsErrorType, Expo, ExpoPushMessage, ExpoPushTicket, Priority, RichContent,
+    Sound,
+};
+use futures::stream::{self, StreamExt};
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
+use http::{Extensions, HeaderValue, StatusCode};
+use lambda_http::{Body, Error, Response};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use supabase_rs::query::QueryBuilder;
 use supabase_rs::SupabaseClient;
 use thiserror::Error;
 use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+/// A day: paced chunks wait in `scheduled_notifications`, not in an
+/// invocation, but a campaign should not trickle out for longer.
+const MAX_SPREAD_OVER_MINUTES: u64 = 24 * 60;
+const DEFAULT_DEADLINE_SAFETY_MARGIN: Duration = Duration::from_secs(5);
+const DEFAULT_STORE_RETRY_AFTER_SECS: &str = "30";
+const DEFAULT_CHUNK_RETRY_ATTEMPTS: usize = 2;
+const DEFAULT_MAX_CONCURRENT_SENDS: usize = 4;
+const DEFAULT_SUPABASE_PAGE_SIZE: usize = 1000;
+/// APNs rejects an `apns-collapse-id` longer than 64 bytes.
+const MAX_COLLAPSE_KEY_LEN: usize = 64;
+/// Longer titles and bodies are cut off on the lock screen anyway, and the
+/// whole APNs payload must fit in 4 KiB.
+const MAX_TITLE_CHARS: usize = 256;
+const MAX_BODY_CHARS: usize = 2048;
+/// Expo rejects notifications whose `data` exceeds 4 KiB once serialized.
+const MAX_DATA_BYTES: usize = 4096;
+/// `data` keys this service fills in itself.
+const RESERVED_DATA_KEYS: [&str; 2] = ["collapse_key", "unsubscribe_token"];
 
 #[derive(Error, Debug)]
 pub enum ApiError {
@@ -24,57 +82,218 @@ pub enum ApiError {
     SupabaseInitialization,
     #[error("Failed to fetch tokens from Supabase")]
     SupabaseFetch,
+    #[error("Failed to write to Supabase")]
+    SupabaseWrite,
     #[error("Invalid API Key")]
     InvalidApiKey,
     #[error("Invalid request body")]
     InvalidBody,
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
     #[error("Failed to build push message")]
     PushMessageBuild,
+    #[error("Secret rotation failed: {0}")]
+    SecretRotation(String),
+    #[error("Token store is temporarily unavailable")]
+    StoreUnavailable,
+    #[error("Unknown sound \"{sound}\"; valid sounds are {}", valid_sounds.join(", "))]
+    InvalidSound {
+        sound: String,
+        valid_sounds: Vec<String>,
+    },
+    #[error("Invalid expo push tokens at indices {indices:?}")]
+    InvalidTokens { indices: Vec<usize> },
+    #[error("Request to Expo failed")]
+    ExpoRequest,
+    #[error("Unknown fields: {}", fields.join(", "))]
+    UnknownFields { fields: Vec<String> },
+    #[error("Offline journal failed: {0}")]
+    Journal(String),
+    #[error("Token store request failed: {0}")]
+    TokenStore(String),
+    #[error("Send queue request failed: {0}")]
+    SendQueue(String),
+    #[error("Failed to load secrets from Secrets Manager: {0}")]
+    SecretsManager(String),
+    #[error("Service is misconfigured; missing {}", missing.join(", "))]
+    Misconfigured { missing: Vec<String> },
+    #[error("Invalid fields: {}", violations.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("; "))]
+    Validation { violations: Vec<Violation> },
 }
 
-/// gwt secrets from ssm parameter store without pagination
-#[instrument]
-pub async fn get_secrets() -> Result<HashMap<String, String>, ApiError> {
-    let ssm_parameter_path = env::var("SSM_PARAMETER_PATH")
-        .map_err(|_| ApiError::MissingEnvVar("SSM_PARAMETER_PATH".into()))?;
+/// One problem with a field of a request body.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub field: String,
+    pub message: String,
+}
 
-    let config = aws_config::load_defaults(BehaviorVersion::v2025_08_07()).await;
-    let ssm_client = SsmClient::new(&config);
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
 
-    info!(ssm_parameter_path = %ssm_parameter_path, "Fetching parameters from SSM");
+/// Collects every [`Violation`] of a body, so the caller learns about all
+/// of them in one `422` ([`ApiError::Validation`]) instead of one per try.
+#[derive(Debug, Default)]
+pub struct Violations(Vec<Violation>);
 
-    let mut secrets = HashMap::new();
+impl Violations {
+    pub fn push(&mut self, field: &str, message: impl Into<String>) {
+        self.0.push(Violation {
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
 
-    // Note: This assumes the number of parameters is within the limit of a single response.
-    let response = ssm_client
-        .get_parameters_by_path()
-        .path(ssm_parameter_path.clone())
-        .with_decryption(true)
-        .send()
-        .await
-        .map_err(|e| {
-            error!(error = ?e, "Failed to get parameters from SSM");
-            ApiError::SsmError
-        })?;
+    /// The value of a field validator, recording its error under `field`.
+    /// On error the default is returned, for a body that [`into_result`]
+    /// rejects anyway.
+    ///
+    /// [`into_result`]: Violations::into_result
+    pub fn check<T: Default>(&mut self, field: &str, result: Result<T, ApiError>) -> T {
+        match result {
+            Ok(value) => value,
+            Err(ApiError::BadRequest(message)) => {
+                self.push(field, message);
+                T::default()
+            }
+            Err(e) => {
+                self.push(field, e.to_string());
+                T::default()
+            }
+        }
+    }
 
-    if let Some(params) = response.parameters {
-        for param in params {
-            if let (Some(name), Some(value)) = (param.name, param.value) {
-                info!(parameter_name = %name, "Fetched parameter from SSM");
-                // Extract only the key name from the path (e.g., /expo-push-api/supabase-key -> supabase-key)
-                if let Some(key) = name.split('/').last() {
-                    secrets.insert(key.to_string(), value);
-                }
+    pub fn into_result(self) -> Result<(), ApiError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation { violations: self.0 })
+        }
+    }
+}
+
+/// What route handlers return: a status and JSON body, or an [`ApiError`]
+/// rendered as an RFC 7807 problem document (see [`problem`]).
+pub type ApiResult = Result<(StatusCode, Json<Value>), ApiError>;
+
+impl ApiError {
+    /// Stable, machine-readable identifier clients can branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::SsmError => "ssm_error",
+            ApiError::MissingSecret(_) => "missing_secret",
+            ApiError::MissingEnvVar(_) => "missing_env_var",
+            ApiError::SupabaseInitialization => "supabase_initialization",
+            ApiError::SupabaseFetch => "supabase_fetch",
+            ApiError::SupabaseWrite => "supabase_write",
+            ApiError::InvalidApiKey => "forbidden",
+            ApiError::InvalidBody => "invalid_body",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PushMessageBuild => "push_message_build",
+            ApiError::SecretRotation(_) => "secret_rotation",
+            ApiError::StoreUnavailable => "store_unavailable",
+            ApiError::InvalidSound { .. } => "invalid_sound",
+            ApiError::InvalidTokens { .. } => "invalid_tokens",
+            ApiError::ExpoRequest => "expo_request",
+            ApiError::UnknownFields { .. } => "unknown_fields",
+            ApiError::Journal(_) => "journal_error",
+            ApiError::TokenStore(_) => "token_store",
+            ApiError::SendQueue(_) => "send_queue_error",
+            ApiError::SecretsManager(_) => "secrets_manager_error",
+            ApiError::Misconfigured { .. } => "misconfigured",
+            ApiError::Validation { .. } => "validation_failed",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidBody | ApiError::BadRequest(_) | ApiError::InvalidTokens { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::InvalidApiKey => StatusCode::FORBIDDEN,
+            ApiError::StoreUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InvalidSound { .. }
+            | ApiError::UnknownFields { .. }
+            | ApiError::Validation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Extra machine-readable field for errors that carry more than a message.
+    fn details(&self) -> Option<(&'static str, Value)> {
+        match self {
+            ApiError::InvalidSound { valid_sounds, .. } => {
+                Some(("valid_sounds", json!(valid_sounds)))
             }
+            ApiError::InvalidTokens { indices } => Some(("invalid_indices", json!(indices))),
+            ApiError::UnknownFields { fields } => Some(("unknown_fields", json!(fields))),
+            ApiError::Misconfigured { missing } => Some(("missing", json!(missing))),
+            ApiError::Validation { violations } => Some(("violations", json!(violations))),
+            _ => None,
         }
     }
 
-    info!("Successfully fetched secrets from SSM");
-    Ok(secrets)
+    /// Treats a failure to reach Supabase (or another token store) as a temporary outage, for routes
+    /// that cannot do anything useful without the token store.
+    pub fn store_unavailable(self) -> Self {
+        match self {
+            ApiError::SupabaseInitialization
+            | ApiError::SupabaseFetch
+            | ApiError::TokenStore(_) => ApiError::StoreUnavailable,
+            e => e,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        let retry_after = matches!(self, ApiError::StoreUnavailable).then(|| {
+            env::var("STORE_RETRY_AFTER_SECS")
+                .unwrap_or_else(|_| DEFAULT_STORE_RETRY_AFTER_SECS.into())
+        });
+        let error_code = self.code();
+        let details = self.details();
+        let message = match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message) => message,
+            e => e.to_string(),
+        };
+        if status.is_server_error() {
+            error!(error = %message, "Request failed");
+        }
+        let mut body = problem(status, error_code, &message);
+        if let Some((field, value)) = details {
+            body[field] = value;
+        }
+        let mut response = (status, Json(body)).into_response();
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+        if let Some(value) = retry_after.and_then(|secs| HeaderValue::from_str(&secs).ok()) {
+            response.headers_mut().insert(RETRY_AFTER, value);
+        }
+        response
+    }
 }
 
+/// The client built for the current Supabase URL and key. Each
+/// `SupabaseClient` owns its own connection pool, so warm invocations reuse
+/// this one instead of reconnecting.
+static SUPABASE_CLIENT: Mutex<Option<(String, String, SupabaseClient)>> = Mutex::new(None);
+
 pub fn initialize_supabase_client(
     secrets: &HashMap<String, String>,
 ) -> Result<SupabaseClient, ApiError> {
@@ -85,157 +304,1355 @@ pub fn initialize_supabase_client(
         .get("supabase-key")
         .ok_or_else(|| ApiError::MissingSecret("supabase-key".into()))?;
 
-    SupabaseClient::new(supabase_url.to_string(), supabase_key.to_string())
-        .map_err(|_| ApiError::SupabaseInitialization)
+    let mut cached = SUPABASE_CLIENT.lock().expect("Supabase client poisoned");
+    if let Some((url, key, client)) = cached.as_ref() {
+        if url == supabase_url && key == supabase_key {
+            return Ok(client.clone());
+        }
+    }
+    let client = SupabaseClient::new(supabase_url.to_string(), supabase_key.to_string())
+        .map_err(|_| ApiError::SupabaseInitialization)?;
+    *cached = Some((supabase_url.clone(), supabase_key.clone(), client.clone()));
+    Ok(client)
+}
+
+/// Rows per request of [`select_all_pages`], from `SUPABASE_PAGE_SIZE`.
+fn supabase_page_size() -> usize {
+    env::var("SUPABASE_PAGE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_SUPABASE_PAGE_SIZE)
+}
+
+/// Every row the query from `select` matches, a page at a time. PostgREST
+/// silently caps a response at its `max-rows` (1000 on Supabase), so a
+/// single select of a large table drops the rest. Pages are ordered by `id`
+/// so none is skipped or repeated, and fetching stops at an empty page
+/// rather than a short one, in case `max-rows` is below the page size.
+pub async fn select_all_pages(
+    select: impl Fn() -> QueryBuilder,
+    description: &str,
+) -> Result<Vec<Value>, String> {
+    let page_size = supabase_page_size();
+    let mut rows = vec![];
+    loop {
+        let page = select()
+            .order("id", true)
+            .limit(page_size)
+            .offset(rows.len())
+            .execute()
+            .timed(description)
+            .await?;
+        if page.is_empty() {
+            return Ok(rows);
+        }
+        rows.extend(page);
+    }
 }
 
 #[instrument(skip(client))]
 pub async fn fetch_expo_push_tokens(client: &SupabaseClient) -> Result<Vec<String>, ApiError> {
-    let response = client.select("users").execute().await.map_err(|e| {
-        error!(error = ?e, "Error fetching expo push tokens");
-        ApiError::SupabaseFetch
-    })?;
+    let response = select_all_pages(|| client.select("users"), "select users")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching expo push tokens");
+            ApiError::SupabaseFetch
+        })?;
 
+    // Quarantined tokens are only contacted by the revalidation job.
     let tokens = response
         .iter()
+        .filter(|row| {
+            row["quarantined"].as_bool() != Some(true)
+                && !is_deleted(row)
+                && notifications_enabled(row)
+        })
         .filter_map(|row| row["expo_push_token"].as_str().map(|s| s.to_string()))
         .collect::<Vec<String>>();
-    info!(token_count = tokens.len(), "Fetched expo push tokens from Supabase");
+    info!(
+        token_count = tokens.len(),
+        "Fetched expo push tokens from Supabase"
+    );
     Ok(tokens)
 }
 
-pub async fn extract_body(req: &Request) -> Result<Value, ApiError> {
-    let body_str = match req.body() {
-        Body::Text(s) => s.to_string(),
-        Body::Binary(b) => String::from_utf8(b.to_vec()).map_err(|_| ApiError::InvalidBody)?,
-        _ => return Err(ApiError::InvalidBody),
-    };
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
 
-    serde_json::from_str(&body_str).map_err(|_| ApiError::InvalidBody)
+/// An RFC 7807 problem document. `error` and `error_code` repeat `detail`
+/// and the code under the names clients read before; the middleware adds
+/// the `request_id`.
+pub fn problem(status: StatusCode, error_code: &str, detail: &str) -> Value {
+    json!({
+        "type": format!("urn:expo-push-api:error:{error_code}"),
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": detail,
+        "error": detail,
+        "error_code": error_code,
+    })
 }
 
 pub fn create_error_response(
     status_code: StatusCode,
+    error_code: &str,
     message: &str,
+) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", PROBLEM_CONTENT_TYPE)
+        .body(problem(status_code, error_code, message).to_string().into())?)
+}
+
+pub fn create_json_response(
+    status_code: StatusCode,
+    body: &Value,
 ) -> Result<Response<Body>, Error> {
     Ok(Response::builder()
         .status(status_code)
         .header("Content-Type", "application/json")
-        .body(json!({ "error": message }).to_string().into())?)
+        .body(body.to_string().into())?)
+}
+
+/// Tenant of the authenticated caller (see [`crate::api_keys::ApiKey::tenant`]),
+/// used to scope per-tenant resources. Empty for unauthenticated requests.
+pub fn tenant_id(extensions: &Extensions) -> String {
+    extensions
+        .get::<Caller>()
+        .map(|caller| caller.tenant.clone())
+        .unwrap_or_default()
+}
+
+/// Time to keep free before the Lambda deadline for checkpointing and the
+/// response, from `DEADLINE_SAFETY_MARGIN_MS` (default 5s).
+pub fn deadline_safety_margin() -> Duration {
+    env::var("DEADLINE_SAFETY_MARGIN_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_DEADLINE_SAFETY_MARGIN)
+}
+
+/// Whether the invocation is too close to its timeout to start another chunk.
+pub fn is_near_deadline(deadline: Option<SystemTime>) -> bool {
+    let Some(deadline) = deadline else {
+        return false;
+    };
+    deadline
+        .duration_since(SystemTime::now())
+        .map_or(true, |remaining| remaining < deadline_safety_margin())
+}
+
+/// A notification to deliver to every token in `tokens`, after the route has
+/// resolved its audience.
+#[derive(Debug)]
+pub struct Broadcast {
+    pub title: String,
+    pub body: String,
+    pub tokens: Vec<String>,
+    pub spread_over_minutes: Option<u64>,
+    pub sound: Option<Sound>,
+    /// iOS app icon badge count; `0` clears it.
+    pub badge: Option<u64>,
+    pub priority: Option<Priority>,
+    /// Seconds Expo and the platform keep retrying an undeliverable message.
+    pub ttl: Option<u64>,
+    /// Android notification channel, which sets importance and sound there.
+    pub channel_id: Option<String>,
+    /// Identifies the logical notification, so a re-send (an updated invoice
+    /// amount, say) replaces the earlier one on the device instead of
+    /// stacking. Delivered in the `data` payload, since Expo has no collapse
+    /// field; the app dismisses earlier notifications with the same key.
+    pub collapse_key: Option<String>,
+    /// What kind of notification this is, e.g. `billing` or `marketing`.
+    /// Marketing notifications carry a one-tap unsubscribe token.
+    pub category: Option<String>,
+    /// Values for the `{{name}}` placeholders in `title` and `body` shared
+    /// by every recipient. `{{user.<column>}}` placeholders are filled from
+    /// `users` instead, see [`crate::templates::load_user_vars`].
+    pub variables: Map<String, Value>,
+    /// Per-token placeholder values, taking precedence over `variables`.
+    pub vars: HashMap<String, Map<String, Value>>,
+    /// Platform-specific variants of the stored template the broadcast was
+    /// created from, picked per recipient by `users.platform`.
+    pub platform_variants: Option<PlatformVariants>,
+    /// Translations picked per recipient by `users.locale`, ahead of the
+    /// platform variant's text.
+    pub locales: Option<LocaleVariants>,
+    /// Caller-supplied `data` payload, e.g. the screen to open on tap.
+    pub data: Option<Map<String, Value>>,
+    /// Resolve, validate and render everything, but do not call Expo.
+    pub dry_run: bool,
+}
+
+/// Rejects keys of a JSON object body that are in none of `allowed`, so a
+/// typo such as `titel` fails loudly instead of silently being ignored.
+/// `prefix` names where the object sits, e.g. `messages[0].`.
+pub fn reject_unknown_fields(
+    body: &Value,
+    prefix: &str,
+    allowed: &[&[&str]],
+) -> Result<(), ApiError> {
+    let Some(fields) = body.as_object() else {
+        return Ok(());
+    };
+    let unknown = fields
+        .keys()
+        .filter(|key| !allowed.iter().any(|group| group.contains(&key.as_str())))
+        .map(|key| format!("{prefix}{key}"))
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::UnknownFields { fields: unknown })
+    }
 }
 
-#[instrument(skip(event))]
-pub async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    let expected_key = env::var("API_KEY").expect("API_KEY not set");
-    let expected_key_value =
-        HeaderValue::from_str(&expected_key).map_err(|_| ApiError::InvalidApiKey)?;
-    if event.headers().get("x-api-key") != Some(&expected_key_value) {
-        warn!("Invalid API key attempt");
-        return create_error_response(StatusCode::FORBIDDEN, "Forbidden: Invalid API Key");
+/// Validates a `title` or `body` (`field`) from a request body: present and
+/// at most `max_chars` characters.
+fn validate_text(field: &str, text: Option<String>, max_chars: usize) -> Result<String, ApiError> {
+    let text = text.ok_or_else(|| ApiError::BadRequest(format!("{field} is required")))?;
+    let chars = text.chars().count();
+    if chars > max_chars {
+        return Err(ApiError::BadRequest(format!(
+            "{field} is {chars} characters, over the {max_chars} character limit"
+        )));
     }
+    Ok(text)
+}
 
-    info!(
-        version = env!("CARGO_PKG_VERSION"),
-        "Expo push notification API request received"
-    );
+/// Validates `title` from a request body or template.
+pub fn validate_title(title: Option<String>) -> Result<String, ApiError> {
+    validate_text("title", title, MAX_TITLE_CHARS)
+}
 
-    let secrets = get_secrets().await?;
-    let expo_access_token = secrets
-        .get("expo-access-token")
-        .ok_or_else(|| ApiError::MissingSecret("expo-access-token".into()))?;
+/// Validates `body` from a request body or template.
+pub fn validate_body(body: Option<String>) -> Result<String, ApiError> {
+    validate_text("body", body, MAX_BODY_CHARS)
+}
 
-    let expo = Expo::new(ExpoClientOptions {
-        access_token: Some(expo_access_token.clone()),
-    });
+/// Validates `spread_over_minutes` from a request body.
+pub fn validate_spread_over_minutes(minutes: Option<u64>) -> Result<Option<u64>, ApiError> {
+    match minutes {
+        Some(minutes) if minutes > MAX_SPREAD_OVER_MINUTES => Err(ApiError::BadRequest(format!(
+            "spread_over_minutes must be an integer between 0 and {MAX_SPREAD_OVER_MINUTES}"
+        ))),
+        minutes => Ok(minutes),
+    }
+}
 
-    let title;
-    let body;
-    let mut expo_push_tokens = vec![];
-
-    match event.raw_http_path() {
-        "/scheduled" => {
-            title = "25日だよ".to_string();
-            body = "パートナーに請求しよう".to_string();
-            let supabase_client = initialize_supabase_client(&secrets)?;
-            expo_push_tokens = fetch_expo_push_tokens(&supabase_client).await?;
-        }
-        "/" => {
-            let json_body = extract_body(&event).await?;
-            title = json_body["title"]
-                .as_str()
-                .ok_or_else(|| ApiError::BadRequest("Title is required".into()))?
-                .to_string();
-            body = json_body["body"]
-                .as_str()
-                .ok_or_else(|| ApiError::BadRequest("Body is required".into()))?
-                .to_string();
-
-            let token = json_body["expo_push_token"]
-                .as_str()
-                .ok_or_else(|| ApiError::BadRequest("expo_push_token is required".into()))?;
-
-            if Expo::is_expo_push_token(token) {
-                expo_push_tokens.push(token.to_string());
-            } else {
-                return create_error_response(StatusCode::BAD_REQUEST, "Invalid expo push token");
-            }
+/// Positions of the tokens in `tokens` that are not Expo push tokens.
+pub fn invalid_token_indices<'a>(tokens: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+    tokens
+        .into_iter()
+        .enumerate()
+        .filter(|(_, token)| !Expo::is_expo_push_token(token))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Validates `category` from a request body.
+pub fn validate_category(category: Option<&str>) -> Result<Option<String>, ApiError> {
+    match category {
+        None => Ok(None),
+        Some(category) if is_valid_identifier(category) => Ok(Some(category.to_string())),
+        Some(_) => Err(ApiError::BadRequest(
+            "category must be lowercase letters, digits and underscores".into(),
+        )),
+    }
+}
+
+/// Validates `collapse_key` from a request body.
+pub fn validate_collapse_key(key: Option<&str>) -> Result<Option<String>, ApiError> {
+    match key {
+        None => Ok(None),
+        Some(key) if !key.is_empty() && key.len() <= MAX_COLLAPSE_KEY_LEN => {
+            Ok(Some(key.to_string()))
         }
-        _ => return create_error_response(StatusCode::NOT_FOUND, "Not Found"),
+        Some(_) => Err(ApiError::BadRequest(format!(
+            "collapse_key must be a non-empty string of at most {MAX_COLLAPSE_KEY_LEN} bytes"
+        ))),
+    }
+}
+
+/// Validates `channel_id` from a request body.
+pub fn validate_channel_id(channel_id: Option<&str>) -> Result<Option<String>, ApiError> {
+    match channel_id {
+        None => Ok(None),
+        Some(channel_id) if !channel_id.is_empty() => Ok(Some(channel_id.to_string())),
+        Some(_) => Err(ApiError::BadRequest(
+            "channel_id must be a non-empty string".into(),
+        )),
+    }
+}
+
+/// Validates the `data` object from a request body, which is forwarded to
+/// the app untouched.
+pub fn validate_data(
+    data: Option<&Map<String, Value>>,
+) -> Result<Option<Map<String, Value>>, ApiError> {
+    let Some(data) = data else {
+        return Ok(None);
+    };
+    if let Some(key) = RESERVED_DATA_KEYS
+        .iter()
+        .find(|key| data.contains_key(**key))
+    {
+        return Err(ApiError::BadRequest(format!("data.{key} is reserved")));
+    }
+    let size = serde_json::to_vec(data).map_or(0, |bytes| bytes.len());
+    if size > MAX_DATA_BYTES {
+        return Err(ApiError::BadRequest(format!(
+            "data is {size} bytes, over the {MAX_DATA_BYTES} byte limit"
+        )));
+    }
+    Ok(Some(data.clone()))
+}
+
+/// Validates `sound` from a request body against `allowed_sounds`, the
+/// custom sounds bundled in the app. Devices silently play nothing for a
+/// sound they don't have, so a typo is rejected instead of sent.
+pub fn validate_sound(
+    sound: Option<&str>,
+    allowed_sounds: &[String],
+) -> Result<Option<Sound>, ApiError> {
+    let Some(sound) = sound else {
+        return Ok(None);
+    };
+    if sound == "default" {
+        return Ok(Some(Sound::Default));
+    }
+    if allowed_sounds.iter().any(|allowed| allowed == sound) {
+        return Ok(Some(Sound::Custom(sound.to_string())));
+    }
+    Err(ApiError::InvalidSound {
+        sound: sound.to_string(),
+        valid_sounds: std::iter::once("default".to_string())
+            .chain(allowed_sounds.iter().cloned())
+            .collect(),
+    })
+}
+
+/// The tokens of chunk `chunk_index` out of `token_count`; the last chunk
+/// holds the remainder.
+fn chunk_range(chunk_index: usize, chunk_size: usize, token_count: usize) -> Range<usize> {
+    (chunk_index * chunk_size).min(token_count)..((chunk_index + 1) * chunk_size).min(token_count)
+}
+
+/// Splits the tickets of one multi-message Expo request back into one result
+/// per message. Expo answers in request order; a failed request, or one
+/// answered with the wrong number of tickets, fails every message in it.
+/// A mismatch is reported as a response that could not be parsed, so it is
+/// not retried: Expo answered, and may have accepted every message.
+fn per_message_results(
+    message_count: usize,
+    result: Result<Vec<ExpoPushTicket>, CustomError>,
+) -> Vec<Result<Vec<ExpoPushTicket>, CustomError>> {
+    let error = match result {
+        Ok(tickets) if tickets.len() == message_count => {
+            return tickets.into_iter().map(|ticket| Ok(vec![ticket])).collect()
+        }
+        Ok(tickets) => CustomError::DeserializeErr(format!(
+            "Expo returned {} tickets for {message_count} messages",
+            tickets.len()
+        )),
+        Err(e) => e,
+    };
+    (0..message_count)
+        .map(|_| {
+            Err(match &error {
+                CustomError::GzipErr(e) => CustomError::GzipErr(e.clone()),
+                CustomError::InvalidArgument(e) => CustomError::InvalidArgument(e.clone()),
+                CustomError::DeserializeErr(e) => CustomError::DeserializeErr(e.clone()),
+                CustomError::SerializeErr(e) => CustomError::SerializeErr(e.clone()),
+                CustomError::ServerErr(e) => CustomError::ServerErr(e.clone()),
+            })
+        })
+        .collect()
+}
+
+/// Whether a failed message may succeed if sent again: the request itself
+/// failed, or Expo or the provider were overloaded. Errors about the token or
+/// the message itself would only fail again, and so would a response that
+/// could not be parsed, which may also mean Expo already accepted the
+/// messages.
+fn is_retriable(result: &Result<Vec<ExpoPushTicket>, CustomError>) -> bool {
+    match result.as_deref() {
+        Err(CustomError::ServerErr(_)) => true,
+        Err(_) => false,
+        Ok([ExpoPushTicket::Error(ticket), ..]) => matches!(
+            ticket
+                .details
+                .as_ref()
+                .and_then(|details| details.error.as_ref()),
+            Some(
+                DetailsErrorType::MessageRateExceeded
+                    | DetailsErrorType::ExpoError
+                    | DetailsErrorType::ProviderError
+            )
+        ),
+        Ok(_) => false,
+    }
+}
+
+/// How many follow-up requests a chunk gets for its retriable failures, from
+/// `CHUNK_RETRY_ATTEMPTS`.
+fn chunk_retry_attempts() -> usize {
+    env::var("CHUNK_RETRY_ATTEMPTS")
+        .ok()
+        .and_then(|attempts| attempts.parse().ok())
+        .unwrap_or(DEFAULT_CHUNK_RETRY_ATTEMPTS)
+}
+
+/// Chunks sent to Expo at the same time, from `MAX_CONCURRENT_SENDS`.
+pub fn max_concurrent_sends() -> usize {
+    env::var("MAX_CONCURRENT_SENDS")
+        .ok()
+        .and_then(|sends| sends.parse().ok())
+        .filter(|&sends| sends > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_SENDS)
+}
+
+/// A chunk that went to Expo, with one result per token.
+struct SentChunk<'a> {
+    chunk_index: usize,
+    tokens: &'a [String],
+    results: Vec<Result<Vec<ExpoPushTicket>, CustomError>>,
+    retried_messages: usize,
+    render: Duration,
+    send: Duration,
+}
+
+/// How one chunk of a broadcast ended.
+enum ChunkOutcome<'a> {
+    Sent(SentChunk<'a>),
+    /// Not sent because the job was aborted.
+    Aborted,
+    /// Not sent because the Lambda deadline was near.
+    DeadlineReached,
+    /// Not sent because another chunk stopped the broadcast.
+    NotStarted,
+}
+
+/// Tokens [`normalize_tokens`] left out of a broadcast.
+#[derive(Debug, Default, Serialize)]
+pub struct SkippedTokens {
+    /// Repeats of a token already in the broadcast, e.g. from duplicate
+    /// `users` rows.
+    pub duplicate: usize,
+    /// Not an Expo push token even with surrounding whitespace trimmed.
+    pub invalid: usize,
+}
+
+impl SkippedTokens {
+    pub fn is_empty(&self) -> bool {
+        self.duplicate == 0 && self.invalid == 0
+    }
+}
+
+/// Trims every token and drops the invalid and repeated ones, so a user
+/// with duplicate rows is notified once. Order is kept.
+pub fn normalize_tokens(tokens: &mut Vec<String>) -> SkippedTokens {
+    let mut skipped = SkippedTokens::default();
+    let mut seen = HashSet::with_capacity(tokens.len());
+    let mut normalized = Vec::with_capacity(tokens.len());
+    for token in tokens.drain(..) {
+        let token = match token.trim() {
+            trimmed if trimmed.len() == token.len() => token,
+            trimmed => trimmed.to_string(),
+        };
+        if !Expo::is_expo_push_token(&token) {
+            skipped.invalid += 1;
+        } else if !seen.insert(token.clone()) {
+            skipped.duplicate += 1;
+        } else {
+            normalized.push(token);
+        }
+    }
+    *tokens = normalized;
+    skipped
+}
+
+/// Sends `broadcast` in chunks, up to `MAX_CONCURRENT_SENDS` at a time,
+/// resuming the checkpointed job `job_id` when given and checkpointing
+/// before `deadline` runs out. Invalid and repeated tokens are skipped
+/// first, and counted in the response's `skipped_tokens`.
+#[instrument(skip(state, broadcast, timings), fields(token_count = broadcast.tokens.len()))]
+pub async fn send_broadcast(
+    state: &AppState,
+    mut broadcast: Broadcast,
+    job_id: Option<String>,
+    deadline: Option<SystemTime>,
+    timings: Timings,
+) -> ApiResult {
+    let skipped = normalize_tokens(&mut broadcast.tokens);
+    if !skipped.is_empty() {
+        warn!(
+            duplicate = skipped.duplicate,
+            invalid = skipped.invalid,
+            "Skipping duplicate and invalid tokens"
+        );
+    }
+    let (status, Json(mut response)) =
+        deliver_broadcast(state, broadcast, job_id, deadline, timings, skipped.invalid).await?;
+    if !skipped.is_empty() {
+        response["skipped_tokens"] = json!(skipped);
+    }
+    Ok((status, Json(response)))
+}
+
+/// Schedules one `/send/batch` per chunk of `entries`, spread evenly over
+/// `minutes` from now, under `job_id` (a new one without it), and answers
+/// `202`. `/scheduled/dispatch` sends each slot once it comes due.
+async fn pace_broadcast(
+    secrets: &HashMap<String, String>,
+    job_id: Option<String>,
+    content: Value,
+    entries: Vec<Vec<Value>>,
+    minutes: u64,
+    recipient_count: usize,
+) -> ApiResult {
+    let supabase_client = initialize_supabase_client(secrets)?;
+    let job_id = job_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let chunks = entries.len();
+    let interval = chrono::Duration::seconds(minutes as i64 * 60) / chunks as i32;
+    let first_send_at = Utc::now();
+    if !has_paced_slots(&supabase_client, &job_id).await? {
+        let slots = entries
+            .into_iter()
+            .enumerate()
+            .map(|(slot, entries)| {
+                let mut body = content.clone();
+                body["entries"] = json!(entries);
+                (body, first_send_at + interval * slot as i32)
+            })
+            .collect();
+        create_paced_slots(&supabase_client, &job_id, "/send/batch", slots).await?;
+    }
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "message": format!("Scheduled in {chunks} chunks over {minutes} minutes"),
+            "result": "paced",
+            "job_id": job_id,
+            "recipient_count": recipient_count,
+            "chunks": chunks,
+            "sent": 0,
+        })),
+    ))
+}
+
+async fn deliver_broadcast(
+    state: &AppState,
+    broadcast: Broadcast,
+    mut job_id: Option<String>,
+    deadline: Option<SystemTime>,
+    mut timings: Timings,
+    malformed_tokens: usize,
+) -> ApiResult {
+    let Broadcast {
+        title,
+        body,
+        tokens: mut expo_push_tokens,
+        spread_over_minutes,
+        sound,
+        badge,
+        priority,
+        ttl,
+        channel_id,
+        collapse_key,
+        category,
+        variables,
+        vars,
+        mut platform_variants,
+        mut locales,
+        data: custom_data,
+        dry_run,
+    } = broadcast;
+    let accepted_at = Utc::now();
+    let secrets = &state.secrets;
+    let config = dynamic_config().await;
+
+    // However the recipients were chosen, users who opted out are left out.
+    if is_supabase_store() {
+        exclude_opted_out(
+            initialize_supabase_client(secrets),
+            &mut expo_push_tokens,
+            category.as_deref(),
+        )
+        .await?;
     }
 
+    // Nothing to send: skip Expo and the job machinery entirely, but leave a
+    // trace so an emptied segment is noticed.
     if expo_push_tokens.is_empty() {
-        info!("No push tokens found, skipping notification");
-        return Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(
-                json!({ "message": "No push tokens found." })
-                    .to_string()
-                    .into(),
-            )?);
-    }
-
-        // プッシュ通知メッセージの構築
+        if !dry_run {
+            record_audience_empty();
+            let mut event_log = EventLog::default();
+            event_log.record("audience_empty", json!({ "job_id": job_id }));
+            event_log.flush().await;
+        }
+        return Ok((
+            StatusCode::OK,
+            Json(json!({
+                "message": "No push tokens found.",
+                "result": "audience_empty",
+                "job_id": job_id,
+            })),
+        ));
+    }
+
+    timings.mark("fetch_tokens");
+
+    if let Some(max_recipients) = config.max_recipients {
+        if expo_push_tokens.len() > max_recipients {
+            warn!(
+                token_count = expo_push_tokens.len(),
+                max_recipients, "Audience exceeds configured quota"
+            );
+            return Err(ApiError::BadRequest(format!(
+                "Audience exceeds the maximum of {max_recipients} recipients"
+            )));
+        }
+    }
+
+    // Checkpointed jobs need a stable chunk layout across invocations, and
+    // any broadcast may turn into one when it runs out of time.
+    expo_push_tokens.sort();
+
+    if let Some(variants) = &mut platform_variants {
+        let supabase_client = initialize_supabase_client(secrets)?;
+        variants
+            .resolve_platforms(&supabase_client, &expo_push_tokens)
+            .await?;
+    }
+    if let Some(locales) = &mut locales {
+        let supabase_client = initialize_supabase_client(secrets)?;
+        locales
+            .resolve_locales(&supabase_client, &expo_push_tokens)
+            .await?;
+    }
+
+    let policy = category
+        .as_ref()
+        .and_then(|category| config.category_policies.get(category));
+    let sound = sound.or_else(|| {
+        policy
+            .and_then(|policy| policy.sound.as_deref())
+            .map(|sound| match sound {
+                "default" => Sound::Default,
+                sound => Sound::Custom(sound.to_string()),
+            })
+    });
+
+    let platform_texts = platform_variants
+        .iter()
+        .flat_map(|variants| variants.variants.values())
+        .flat_map(|variant| [variant.title.as_deref(), variant.body.as_deref()]);
+    let locale_texts = locales
+        .iter()
+        .flat_map(|locales| locales.variants.values())
+        .flat_map(|text| [text.title.as_deref(), text.body.as_deref()]);
+    let templates = [title.as_str(), body.as_str()]
+        .into_iter()
+        .chain(platform_texts.chain(locale_texts).flatten());
+    let columns = user_columns(templates)?;
+    let user_vars = if columns.is_empty() {
+        HashMap::new()
+    } else {
+        let supabase_client = initialize_supabase_client(secrets)?;
+        load_user_vars(&supabase_client, &expo_push_tokens, &columns).await?
+    };
+
+    let unsubscribe_category = category
+        .as_deref()
+        .filter(|category| is_marketing_category(category));
+    let build_message = |token: &String| {
+        let variant = platform_variants
+            .as_ref()
+            .and_then(|variants| variants.for_token(token));
+        let translation = locales
+            .as_ref()
+            .and_then(|locales| locales.for_token(token));
+        let title = translation
+            .and_then(|translation| translation.title.as_ref())
+            .or(variant.and_then(|variant| variant.title.as_ref()))
+            .unwrap_or(&title);
+        let body = translation
+            .and_then(|translation| translation.body.as_ref())
+            .or(variant.and_then(|variant| variant.body.as_ref()))
+            .unwrap_or(&body);
+        let mut token_vars = variables.clone();
+        for values in [user_vars.get(token), vars.get(token)]
+            .into_iter()
+            .flatten()
+        {
+            token_vars.extend(values.clone());
+        }
+        let (title, body) = (render(title, &token_vars)?, render(body, &token_vars)?);
+        let mut message = ExpoPushMessage::builder(vec![token.clone()])
+            .title(title)
+            .body(body);
+        if let Some(image) = variant.and_then(|variant| variant.image.as_ref()) {
+            // iOS only attaches the image through a notification service
+            // extension, which mutable-content wakes up.
+            message = message
+                .rich_content(RichContent::new().image(image))
+                .mutable_content(true);
+        }
+        if let Some(sound) = &sound {
+            message = message.sound(sound.clone());
+        }
+        if let Some(channel_id) = channel_id
+            .as_ref()
+            .or(policy.and_then(|policy| policy.channel_id.as_ref()))
+        {
+            message = message.channel_id(channel_id);
+        }
+        if let Some(priority) = priority.or(policy.and_then(|policy| policy.priority)) {
+            message = message.priority(priority);
+        }
+        if let Some(badge) = badge {
+            message = message.badge(badge);
+        }
+        if let Some(ttl) = ttl {
+            message = message.ttl(ttl);
+        }
+        let mut data = custom_data.clone().unwrap_or_default();
+        if let Some(collapse_key) = &collapse_key {
+            data.insert("collapse_key".into(), json!(collapse_key));
+        }
+        if let Some(unsubscribe) =
+            unsubscribe_category.and_then(|category| unsubscribe_token(secrets, token, category))
+        {
+            data.insert("unsubscribe_token".into(), json!(unsubscribe));
+        }
+        if !data.is_empty() {
+            message = message
+                .data(&data)
+                .map_err(|_| ApiError::PushMessageBuild)?;
+        }
+        message.build().map_err(|_| ApiError::PushMessageBuild)
+    };
+    // Without per-token vars or platform variants every message carries the
+    // same content, so the one with the longest token is the largest.
+    // Personalized messages are all rendered up front, which also rejects a
+    // missing placeholder before anything is sent.
+    let message_size =
+        |message: ExpoPushMessage| serde_json::to_vec(&message).map_or(0, |bytes| bytes.len());
+    let largest_message_bytes = if vars.is_empty()
+        && user_vars.is_empty()
+        && platform_variants.is_none()
+        && locales.is_none()
+    {
+        expo_push_tokens
+            .iter()
+            .max_by_key(|token| token.len())
+            .map(build_message)
+            .transpose()?
+            .map_or(0, message_size)
+    } else {
+        expo_push_tokens
+            .iter()
+            .map(|token| build_message(token).map(message_size))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .max()
+            .unwrap_or_default()
+    };
+    let chunk_size = chunk_size_for(largest_message_bytes);
+    let total_chunks = expo_push_tokens.len().div_ceil(chunk_size);
+
+    if dry_run {
+        let sample_message = expo_push_tokens.first().map(build_message).transpose()?;
+        info!(
+            token_count = expo_push_tokens.len(),
+            total_chunks, "Dry run; not calling Expo"
+        );
+        let mut response = json!({
+            "message": "Dry run; nothing was sent",
+            "dry_run": true,
+            "recipient_count": expo_push_tokens.len(),
+            "chunks": total_chunks,
+            "chunk_size": chunk_size,
+            "sample_message": sample_message,
+        });
+        timings.attach(&mut response);
+        return Ok((StatusCode::OK, Json(response)));
+    }
+
+    // A paced broadcast is not sent here: each chunk becomes a scheduled
+    // `/send/batch` at its slot, so no invocation waits out the spread.
+    if let Some(minutes) = spread_over_minutes.filter(|&minutes| minutes > 0 && total_chunks > 1) {
+        let content = SendRequest {
+            title: Some(title.clone()),
+            body: Some(body.clone()),
+            template_id: platform_variants
+                .as_ref()
+                .map(|variants| variants.template_id.clone()),
+            localized: locales.as_ref().map(|locales| locales.variants.clone()),
+            variables: (!variables.is_empty()).then(|| variables.clone()),
+            data: custom_data.clone(),
+            collapse_key: collapse_key.clone(),
+            category: category.clone(),
+            sound: sound.as_ref().map(|sound| match sound {
+                Sound::Default => "default".to_string(),
+                Sound::Custom(sound) => sound.clone(),
+            }),
+            badge,
+            priority,
+            ttl,
+            channel_id: channel_id.clone(),
+            ..Default::default()
+        };
+        let chunks = expo_push_tokens.chunks(chunk_size).map(|chunk| {
+            chunk
+                .iter()
+                .map(|token| {
+                    json!({
+                        "expo_push_token": token,
+                        "vars": vars.get(token).cloned().unwrap_or_default(),
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        return pace_broadcast(
+            secrets,
+            job_id,
+            json!(content),
+            chunks.collect(),
+            minutes,
+            expo_push_tokens.len(),
+        )
+        .await;
+    }
+
+    let mut job = match &job_id {
+        Some(job_id) => {
+            let supabase_client = initialize_supabase_client(secrets)?;
+            let job = load_or_create_job(&supabase_client, job_id, total_chunks).await?;
+            Some((supabase_client, job))
+        }
+        None => None,
+    };
+
+    // プッシュ通知メッセージの構築
     info!(
         token_count = expo_push_tokens.len(),
         "Building push notifications"
     );
-    let messages = expo_push_tokens
-        .into_iter()
-        .map(|token| {
-            ExpoPushMessage::builder(vec![token])
-                .title(title.clone())
-                .body(body.clone())
-                .build()
-                .map_err(|_| ApiError::PushMessageBuild)
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    let mut message_content = json!({ "title": title, "body": body });
+    if let Some(data) = &custom_data {
+        message_content["data"] = json!(data);
+    }
+    let content_hash = content_hash(&message_content);
+    if let Some(content) = PrivacyLevel::from_env().redact(&message_content) {
+        info!(content = %content, content_hash = %content_hash, "Broadcast content");
+        // History rows reference the content by hash, so a large campaign
+        // stores its body once instead of once per recipient.
+        let stored = match initialize_supabase_client(secrets) {
+            Ok(client) => store_content(&client, &content_hash, &content).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            warn!(error = %e, "Failed to store broadcast content for history");
+        }
+    }
+    let mut event_log = EventLog::default();
+    event_log.record(
+        "broadcast",
+        json!({
+            "job_id": job_id,
+            "recipient_count": expo_push_tokens.len(),
+            "content_hash": content_hash,
+            "collapse_key": collapse_key,
+            "category": category,
+            "template_id": platform_variants.as_ref().map(|variants| &variants.template_id),
+        }),
+    );
+    let mut results = vec![];
+    let mut sent_tokens = vec![];
+    let mut tickets = vec![];
+    let mut failed_tokens = vec![];
+    let mut ticket_results = vec![];
+    let mut sent_chunks = vec![];
+    let mut aborted = false;
+    let mut deadline_reached = false;
+    let retry_attempts = chunk_retry_attempts();
+    let mut retried_messages = 0;
 
-    info!("Sending push notifications");
-    let send_futures = messages
-        .into_iter()
-        .map(|msg| expo.send_push_notifications(msg))
+    let pending_chunks = (0..total_chunks)
+        .filter(|&chunk_index| {
+            !job.as_ref()
+                .is_some_and(|(_, job)| job.is_completed(chunk_index))
+        })
         .collect::<Vec<_>>();
+    let skipped_chunks = total_chunks - pending_chunks.len();
+    let concurrency = max_concurrent_sends();
+    let abort_check = job
+        .as_ref()
+        .map(|(supabase_client, job)| (supabase_client.clone(), job.id.clone()));
+    // Set once the job is aborted, the deadline is near or a chunk failed
+    // to render: chunks not yet started are left alone, while those in
+    // flight finish so their tickets are recorded.
+    let stop = AtomicBool::new(false);
+    let send_chunk = |chunk_index: usize| {
+        let chunk = &expo_push_tokens[chunk_range(chunk_index, chunk_size, expo_push_tokens.len())];
+        let (abort_check, stop, build_message) = (&abort_check, &stop, &build_message);
+        async move {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(ChunkOutcome::NotStarted);
+            }
+            if let Some((supabase_client, job_id)) = abort_check {
+                if is_job_aborted(supabase_client, job_id).await? {
+                    warn!(
+                        chunk_index,
+                        "Broadcast aborted, not sending remaining chunks"
+                    );
+                    stop.store(true, Ordering::Relaxed);
+                    return Ok(ChunkOutcome::Aborted);
+                }
+            }
+            if is_near_deadline(deadline) {
+                warn!(
+                    chunk_index,
+                    "Approaching Lambda timeout, not starting further chunks"
+                );
+                stop.store(true, Ordering::Relaxed);
+                return Ok(ChunkOutcome::DeadlineReached);
+            }
+
+            let render_started = Instant::now();
+            let messages = chunk
+                .iter()
+                .map(build_message)
+                .collect::<Result<Vec<_>, _>>()?;
+            let render = render_started.elapsed();
+
+            // One Expo request per chunk; chunks never exceed Expo's 100
+            // messages per request.
+            info!(chunk_index, "Sending push notifications");
+            let send_started = Instant::now();
+            let mut chunk_results = per_message_results(
+                chunk.len(),
+                send_with_retry(secrets, &messages, deadline).await,
+            );
+            let mut retried_messages = 0;
+            // Follow-up requests carry only the messages that failed
+            // transiently, identified by their position in the chunk, so
+            // recipients that already got a ticket are not notified twice.
+            for attempt in 1..=retry_attempts {
+                let failed = (0..chunk.len())
+                    .filter(|&position| is_retriable(&chunk_results[position]))
+                    .collect::<Vec<_>>();
+                if failed.is_empty() || is_near_deadline(deadline) {
+                    break;
+                }
+                info!(
+                    chunk_index,
+                    attempt,
+                    retry_count = failed.len(),
+                    "Retrying failed messages of chunk"
+                );
+                let messages = failed
+                    .iter()
+                    .map(|&position| build_message(&chunk[position]))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let retried = per_message_results(
+                    failed.len(),
+                    send_with_retry(secrets, &messages, deadline).await,
+                );
+                retried_messages += failed.len();
+                for (position, result) in failed.into_iter().zip(retried) {
+                    chunk_results[position] = result;
+                }
+            }
+            Ok(ChunkOutcome::Sent(SentChunk {
+                chunk_index,
+                tokens: chunk,
+                results: chunk_results,
+                retried_messages,
+                render,
+                send: send_started.elapsed(),
+            }))
+        }
+    };
 
-    let results = join_all(send_futures).await;
+    let mut outcomes = stream::iter(pending_chunks.iter().copied())
+        .map(send_chunk)
+        .buffer_unordered(concurrency);
+    let mut sent = vec![];
+    let mut first_error = None;
+    while let Some(outcome) = outcomes.next().await {
+        match outcome {
+            Ok(ChunkOutcome::Sent(chunk)) => {
+                // Checkpointed as each chunk finishes, so a resumed job
+                // skips exactly the chunks that went out.
+                if let Some((supabase_client, job)) = &mut job {
+                    if let Err(e) =
+                        mark_chunk_completed(supabase_client, job, chunk.chunk_index).await
+                    {
+                        stop.store(true, Ordering::Relaxed);
+                        first_error.get_or_insert(e);
+                    }
+                }
+                sent.push(chunk);
+            }
+            Ok(ChunkOutcome::Aborted) => aborted = true,
+            Ok(ChunkOutcome::DeadlineReached) => deadline_reached = true,
+            Ok(ChunkOutcome::NotStarted) => {}
+            Err(e) => {
+                stop.store(true, Ordering::Relaxed);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    drop(outcomes);
+    // With nothing sent there is nothing to record; otherwise the chunks
+    // that went out are recorded and checkpointed like at the deadline, so
+    // a retry with the job resumes after them instead of sending them again.
+    if sent.is_empty() {
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+    }
+
+    // In chunk order, whichever finished first.
+    sent.sort_by_key(|chunk| chunk.chunk_index);
+    for chunk in sent {
+        timings.add("render", chunk.render);
+        timings.add("send", chunk.send);
+        retried_messages += chunk.retried_messages;
+        sent_chunks.push(chunk.chunk_index);
+        for (token, result) in chunk.tokens.iter().zip(&chunk.results) {
+            ticket_results.push(ticket_result(token, result));
+            event_log.record(
+                "send",
+                send_event(token, job_id.as_deref(), &content_hash, result),
+            );
+            match result.as_deref() {
+                Ok([ExpoPushTicket::Ok(ticket), ..]) => {
+                    tickets.push((token.clone(), ticket.id.to_string()))
+                }
+                _ => failed_tokens.push(token.clone()),
+            }
+        }
+        sent_tokens.extend_from_slice(chunk.tokens);
+        results.extend(chunk.results);
+    }
+    if skipped_chunks > 0 {
+        info!(
+            skipped_chunks,
+            "Skipped chunks completed by a previous invocation"
+        );
+    }
+
+    event_log.flush().await;
+    let dead_tokens = sent_tokens
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| is_dead_token(result))
+        .map(|(token, _)| token.clone())
+        .collect::<Vec<_>>();
+    record_invalid_token_rate(dead_tokens.len(), results.len());
+    record_send(
+        tickets.len(),
+        malformed_tokens + dead_tokens.len(),
+        results
+            .iter()
+            .filter(|result| is_expo_error(result))
+            .count(),
+        timings.elapsed(),
+    );
+    // Expo sometimes reports DeviceNotRegistered right in the ticket; the
+    // rest surface later through receipts.
+    if !dead_tokens.is_empty() {
+        match initialize_supabase_client(secrets) {
+            Ok(client) => {
+                let pruned = prune_unregistered_tokens(&client, &dead_tokens).await;
+                if let Some(tenant) = current_tenant().filter(|_| pruned > 0) {
+                    dispatch_event(
+                        secrets,
+                        &tenant,
+                        "token.pruned",
+                        json!({ "pruned": pruned, "expo_push_tokens": dead_tokens }),
+                    )
+                    .await;
+                }
+            }
+            Err(e) => warn!(error = %e, "Failed to prune unregistered tokens"),
+        }
+    }
 
     let has_error = results.iter().any(|r| r.is_err());
+    let (accepted_count, failed_count) = (tickets.len(), failed_tokens.len());
+
+    // Once anything was accepted, a failed request still gets a job, so a
+    // retry resumes after the chunks that went out.
+    let partially_failed = (has_error || first_error.is_some()) && accepted_count > 0;
+    if (deadline_reached || partially_failed) && job.is_none() {
+        let created = match initialize_supabase_client(secrets) {
+            Ok(supabase_client) => {
+                create_checkpointed_job(&supabase_client, total_chunks, &sent_chunks).await
+            }
+            Err(e) => Err(e),
+        };
+        match created {
+            Ok(id) => job_id = Some(id),
+            // The error being surfaced matters more than the resume cursor.
+            Err(e) if partially_failed => {
+                warn!(error = %e, "Failed to checkpoint partially sent broadcast")
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Err(e) = match initialize_supabase_client(secrets) {
+        Ok(client) => {
+            record_tickets(
+                &client,
+                &tickets,
+                category.as_deref(),
+                job_id.as_deref(),
+                current_tenant().as_deref(),
+                accepted_at,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    } {
+        warn!(error = %e, "Failed to store push tickets for receipt checks");
+    }
 
-    if has_error {
+    // Best effort, like the content itself: history exists to recover from
+    // failed sends, so it must not cause one.
+    let mut history_id = None;
+    if !sent_tokens.is_empty() {
+        let entry = HistoryEntry {
+            job_id: job_id.clone(),
+            content_hash: content_hash.clone(),
+            sound,
+            collapse_key,
+            category: category.clone(),
+            tokens: sent_tokens,
+            failed_tokens,
+        };
+        let recorded = match initialize_supabase_client(secrets) {
+            Ok(client) => record_broadcast(&client, &entry).await,
+            Err(e) => Err(e),
+        };
+        match recorded {
+            Ok(id) => history_id = Some(id),
+            Err(e) => warn!(error = %e, "Failed to record notification history"),
+        }
+    }
+
+    let log_entry = LogEntry {
+        title: title.clone(),
+        body: body.clone(),
+        category: category.clone(),
+        job_id: job_id.clone(),
+        history_id: history_id.clone(),
+        audience_size: expo_push_tokens.len(),
+        accepted: accepted_count,
+        failed: failed_count,
+        retried: retried_messages,
+        caller: current_caller(),
+    };
+    if let Err(e) = match initialize_supabase_client(secrets) {
+        Ok(client) => record_notification(&client, &log_entry).await,
+        Err(e) => Err(e),
+    } {
+        warn!(error = %e, "Failed to record notification log");
+    }
+
+    let remaining_chunks = total_chunks - skipped_chunks - sent_chunks.len();
+    if let Some(e) = first_error {
+        error!(error = %e, "Stopped sending after an error");
+        let status = e.status();
+        let mut response = problem(status, e.code(), &e.to_string());
+        response["job_id"] = json!(job_id);
+        response["history_id"] = json!(history_id);
+        response["sent"] = json!(accepted_count);
+        response["accepted"] = json!(accepted_count);
+        response["failed"] = json!(failed_count);
+        response["completed_chunks"] = json!(total_chunks - remaining_chunks);
+        response["remaining_chunks"] = json!(remaining_chunks);
+        response["results"] = json!(ticket_results);
+        timings.attach(&mut response);
+        return Ok((status, Json(response)));
+    }
+
+    if deadline_reached {
+        // The job is the resume cursor: re-sending the same body with
+        // `?job_id=` skips every chunk it marks completed.
+        let mut response = json!({
+            "message": "Partially sent before the Lambda deadline; re-invoke with job_id to resume",
+            "job_id": job_id,
+            "history_id": history_id,
+            "sent": accepted_count,
+            "accepted": accepted_count,
+            "failed": failed_count,
+            "completed_chunks": total_chunks - remaining_chunks,
+            "remaining_chunks": remaining_chunks,
+            "results": ticket_results,
+        });
+        timings.attach(&mut response);
+        return Ok((StatusCode::ACCEPTED, Json(response)));
+    }
+
+    if aborted {
+        let mut response = json!({
+            "message": "Broadcast aborted",
+            "job_id": job_id,
+            "history_id": history_id,
+            "sent": accepted_count,
+        });
+        timings.attach(&mut response);
+        Ok((StatusCode::OK, Json(response)))
+    } else if has_error {
         error!(results = ?results, "Failed to send some push notifications");
-        create_error_response(
+        let mut response = problem(
             StatusCode::INTERNAL_SERVER_ERROR,
+            "send_failed",
             "Failed to send some push notifications",
-        )
+        );
+        response["job_id"] = json!(job_id);
+        response["history_id"] = json!(history_id);
+        response["sent"] = json!(accepted_count);
+        response["accepted"] = json!(accepted_count);
+        response["failed"] = json!(failed_count);
+        response["results"] = json!(ticket_results);
+        Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(response)))
     } else {
         info!("Push notifications sent successfully");
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(
-                json!({ "message": "Push notifications sent successfully" })
-                    .to_string()
-                    .into(),
-            )?)
+        if let Some(tenant) = current_tenant() {
+            dispatch_event(
+                secrets,
+                &tenant,
+                "send.accepted",
+                json!({ "job_id": job_id, "ticket_count": results.len() }),
+            )
+            .await;
+        }
+        let message = if failed_count == 0 {
+            "Push notifications sent successfully"
+        } else {
+            "Push notifications sent; some tokens did not get a ticket"
+        };
+        let mut response = json!(SendResponse {
+            message: message.into(),
+            job_id,
+            history_id,
+            skipped_chunks,
+            chunks: sent_chunks.len(),
+            sent: accepted_count,
+            failed: failed_count,
+            retried: retried_messages,
+            results: ticket_results,
+        });
+        timings.attach(&mut response);
+        Ok((StatusCode::OK, Json(response)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    #[test]
+    fn normalize_tokens_trims_and_keeps_order() {
+        let mut list = tokens(&[
+            " ExponentPushToken[b] ",
+            "ExponentPushToken[a]\n",
+            "ExpoPushToken[c]",
+        ]);
+        let skipped = normalize_tokens(&mut list);
+        assert!(skipped.is_empty());
+        assert_eq!(
+            list,
+            tokens(&[
+                "ExponentPushToken[b]",
+                "ExponentPushToken[a]",
+                "ExpoPushToken[c]"
+            ])
+        );
+    }
+
+    #[test]
+    fn normalize_tokens_drops_invalid_and_repeated_tokens() {
+        let mut list = tokens(&[
+            "ExponentPushToken[a]",
+            "not-a-token",
+            " ExponentPushToken[a]",
+            "",
+            "ExponentPushToken[b]",
+            "ExponentPushToken[a]",
+        ]);
+        let skipped = normalize_tokens(&mut list);
+        assert_eq!((skipped.invalid, skipped.duplicate), (2, 2));
+        assert_eq!(
+            list,
+            tokens(&["ExponentPushToken[a]", "ExponentPushToken[b]"])
+        );
+    }
+
+    #[test]
+    fn normalize_tokens_accepts_an_empty_list() {
+        let mut list = vec![];
+        assert!(normalize_tokens(&mut list).is_empty());
+        assert!(list.is_empty());
+    }
+
+    fn ticket(ticket: Value) -> ExpoPushTicket {
+        serde_json::from_value(ticket).unwrap()
+    }
+
+    #[test]
+    fn chunk_ranges_cover_every_token_once() {
+        let ranges = (0..3).map(|i| chunk_range(i, 100, 250)).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![0..100, 100..200, 200..250]);
+        assert_eq!(chunk_range(1, 100, 100), 100..100);
+    }
+
+    #[test]
+    fn per_message_results_keep_ticket_order() {
+        let results = per_message_results(
+            2,
+            Ok(vec![
+                ticket(json!({ "status": "ok", "id": "receipt-1" })),
+                ticket(json!({ "status": "error", "message": "gone" })),
+            ]),
+        );
+        assert!(
+            matches!(results[0].as_deref(), Ok([ExpoPushTicket::Ok(ok)]) if ok.id.to_string() == "receipt-1")
+        );
+        assert!(matches!(
+            results[1].as_deref(),
+            Ok([ExpoPushTicket::Error(_)])
+        ));
+    }
+
+    #[test]
+    fn per_message_results_fail_the_whole_chunk_on_a_ticket_count_mismatch() {
+        let results =
+            per_message_results(3, Ok(vec![ticket(json!({ "status": "ok", "id": "r" }))]));
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(CustomError::DeserializeErr(_)))));
+        assert!(!results.iter().any(is_retriable));
+    }
+
+    #[test]
+    fn per_message_results_repeat_a_request_error_for_every_message() {
+        let results = per_message_results(2, Err(CustomError::DeserializeErr("bad".into())));
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(CustomError::DeserializeErr(e)) if e == "bad")));
     }
 }
@@ -0,0 +1,175 @@
+use http::header::ACCEPT_LANGUAGE;
+use http::HeaderMap;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    En,
+    Ja,
+}
+
+impl Language {
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim();
+        if primary.eq_ignore_ascii_case("ja") {
+            Some(Language::Ja)
+        } else if primary.eq_ignore_ascii_case("en") {
+            Some(Language::En)
+        } else {
+            None
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Ja => "ja",
+        }
+    }
+
+    /// Best supported language from `Accept-Language`, honouring q-values.
+    /// Falls back to English.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(header) = headers.get(ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()) else {
+            return Language::En;
+        };
+        header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let language = Language::from_tag(parts.next()?)?;
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((language, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            // Ties go to the range listed first.
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(std::cmp::Ordering::Greater))
+            .map(|(language, _)| language)
+            .unwrap_or(Language::En)
+    }
+}
+
+fn japanese(error_code: &str) -> Option<&'static str> {
+    Some(match error_code {
+        "bad_request" => "リクエストが不正です",
+        "invalid_body" => "リクエストボディが不正です",
+        "not_found" => "見つかりません",
+        "conflict" => "現在の状態では実行できません",
+        "unauthorized" => "APIキーが指定されていません",
+        "forbidden" => "APIキーが無効です",
+        "insufficient_scope" => "このAPIキーには必要な権限がありません",
+        "rate_limited" => "リクエストが多すぎます。しばらくしてから再度お試しください",
+        "send_failed" => "一部のプッシュ通知の送信に失敗しました",
+        "missing_secret"
+        | "missing_env_var"
+        | "ssm_error"
+        | "secrets_manager_error"
+        | "misconfigured" => "サーバーの設定に問題があります",
+        "supabase_initialization" | "supabase_fetch" | "token_store" => {
+            "データベースからの読み込みに失敗しました"
+        }
+        "supabase_write" => "データベースへの書き込みに失敗しました",
+        "push_message_build" => "プッシュ通知の作成に失敗しました",
+        "invalid_sound" => "通知音が無効です",
+        "expo_request" => "Expoへのリクエストに失敗しました",
+        "invalid_tokens" => "無効なプッシュトークンが含まれています",
+        "unknown_fields" => "不明なフィールドが含まれています",
+        "validation_failed" => "無効なフィールドが含まれています",
+        "journal_error" => "オフラインジャーナルの読み書きに失敗しました",
+        "send_queue_error" => "送信キューへの登録に失敗しました",
+        "internal_error" => "内部エラーが発生しました",
+        _ => return None,
+    })
+}
+
+/// Operator-facing `message` for an error response. English reuses the
+/// `error` text; Japanese comes from a catalog keyed by `error_code`, with
+/// the English detail appended for codes whose text is request specific.
+pub fn localized_message(language: Language, error_code: &str, error: &str) -> String {
+    let translated = match language {
+        Language::En => None,
+        Language::Ja => japanese(error_code),
+    };
+    match translated {
+        Some(message)
+            if matches!(
+                error_code,
+                "bad_request"
+                    | "not_found"
+                    | "conflict"
+                    | "invalid_sound"
+                    | "unknown_fields"
+                    | "validation_failed"
+                    | "misconfigured"
+            ) =>
+        {
+            format!("{message}: {error}")
+        }
+        Some(message) => message.to_string(),
+        None => error.to_string(),
+    }
+}
+
+/// Stable key for a success `message`, used to look up its translation and
+/// deployment overrides. The English text itself stays in the handlers.
+pub fn success_key(message: &str) -> Option<&'static str> {
+    Some(match message {
+        "Push notifications sent successfully" => "sent",
+        "Push notifications sent; some tokens did not get a ticket" => "partially_accepted",
+        "No push tokens found." => "no_tokens",
+        "Partially sent before the Lambda deadline; re-invoke with job_id to resume" => {
+            "partially_sent"
+        }
+        "Broadcast aborted" => "aborted",
+        "Token store unavailable; request queued for delivery" => "queued",
+        "Offline mode; request journaled for a later flush" => "journaled",
+        "Queued for asynchronous sending" => "queued_async",
+        "Notification scheduled" => "scheduled",
+        "Dry run; nothing was sent" => "dry_run",
+        _ => return None,
+    })
+}
+
+fn japanese_success(key: &str) -> Option<&'static str> {
+    Some(match key {
+        "sent" => "プッシュ通知を送信しました",
+        "partially_accepted" => {
+            "プッシュ通知を送信しましたが、一部のトークンは受け付けられませんでした"
+        }
+        "no_tokens" => "プッシュトークンが見つかりません",
+        "partially_sent" => {
+            "Lambdaの制限時間までに一部のみ送信しました。job_idを指定して再実行してください"
+        }
+        "aborted" => "配信を中止しました",
+        "queued" => "データベースに接続できないため、リクエストを配信待ちにしました",
+        "journaled" => "オフラインモードのため、リクエストをジャーナルに記録しました",
+        "queued_async" => "非同期送信のためキューに登録しました",
+        "scheduled" => "通知の送信を予約しました",
+        "dry_run" => "ドライランのため送信していません",
+        _ => return None,
+    })
+}
+
+/// `message` of a success response in `language`: a deployment override
+/// from the `success-messages` config first, then the built-in catalog.
+/// `None` keeps the English text.
+pub fn localized_success(
+    language: Language,
+    message: &str,
+    overrides: &HashMap<String, HashMap<String, String>>,
+) -> Option<String> {
+    let key = success_key(message)?;
+    if let Some(text) = overrides
+        .get(language.code())
+        .and_then(|messages| messages.get(key))
+    {
+        return Some(text.clone());
+    }
+    match language {
+        Language::En => None,
+        Language::Ja => japanese_success(key).map(str::to_string),
+    }
+}
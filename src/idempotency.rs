@@ -0,0 +1,334 @@
+//! `Idempotency-Key` support for the send endpoints. The first request with
+//! a key is sent and its response stored in the `idempotency_keys` table;
+//! a retry with the same key within 24 hours gets the stored response back
+//! instead of sending again.
+
+use crate::http_handler::{initialize_supabase_client, tenant_id, ApiError};
+use crate::metrics::Timed;
+use crate::router::AppState;
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use http::{HeaderValue, StatusCode};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, warn};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const MAX_KEY_LENGTH: usize = 255;
+/// How long a stored response is replayed for.
+const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+/// How long a key stays claimed without a stored response: Lambda's
+/// maximum function timeout, after which the request that claimed it
+/// cannot still be running.
+const IN_PROGRESS_LEASE_MINUTES: i64 = 15;
+/// Largest request or response body buffered here; Lambda's payload limit
+/// for synchronous invocations.
+const MAX_BODY_BYTES: usize = 6 * 1024 * 1024;
+
+/// What the table holds for a key.
+enum KeyState {
+    Unseen,
+    /// Seen more than [`IDEMPOTENCY_TTL_HOURS`] ago, or claimed more than
+    /// [`IN_PROGRESS_LEASE_MINUTES`] ago without a response; reused as if
+    /// unseen.
+    Expired,
+    InProgress {
+        fingerprint: String,
+    },
+    Completed {
+        fingerprint: String,
+        status: StatusCode,
+        body: Value,
+    },
+}
+
+/// Identifies the request a key was first used with, so the key cannot be
+/// reused for a different one.
+fn fingerprint(method: &str, uri: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(uri.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+async fn load_key(client: &SupabaseClient, id: &str) -> Result<KeyState, ApiError> {
+    let rows = client
+        .select("idempotency_keys")
+        .eq("id", id)
+        .execute()
+        .timed("select idempotency_keys")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching idempotency key");
+            ApiError::SupabaseFetch
+        })?;
+    let Some(row) = rows.into_iter().next() else {
+        return Ok(KeyState::Unseen);
+    };
+    let Some(created_at) = row["created_at"]
+        .as_str()
+        .and_then(|created_at| DateTime::parse_from_rfc3339(created_at).ok())
+        .map(|created_at| created_at.with_timezone(&Utc))
+    else {
+        return Ok(KeyState::Expired);
+    };
+    if created_at + Duration::hours(IDEMPOTENCY_TTL_HOURS) < Utc::now() {
+        return Ok(KeyState::Expired);
+    }
+    let fingerprint = row["fingerprint"].as_str().unwrap_or_default().to_string();
+    let status = row["response_status"]
+        .as_u64()
+        .and_then(|status| StatusCode::from_u16(status as u16).ok());
+    Ok(match status {
+        Some(status) if row["status"] == "completed" => KeyState::Completed {
+            fingerprint,
+            status,
+            body: row["response_body"].clone(),
+        },
+        // The claiming invocation timed out or crashed before finishing.
+        _ if created_at + Duration::minutes(IN_PROGRESS_LEASE_MINUTES) < Utc::now() => {
+            KeyState::Expired
+        }
+        _ => KeyState::InProgress { fingerprint },
+    })
+}
+
+enum ClaimError {
+    Taken,
+    Unavailable(String),
+}
+
+/// Claims the key for this request before it is sent. [`ClaimError::Taken`]
+/// when a concurrent request with the same key inserted it first.
+async fn claim_key(
+    client: &SupabaseClient,
+    id: &str,
+    fingerprint: &str,
+    expired: bool,
+) -> Result<(), ClaimError> {
+    let row = json!({
+        "id": id,
+        "fingerprint": fingerprint,
+        "status": "in_progress",
+        "response_status": null,
+        "response_body": null,
+        "created_at": Utc::now().to_rfc3339(),
+    });
+    let result = if expired {
+        client
+            .update("idempotency_keys", id, row)
+            .timed("update idempotency_keys")
+            .await
+    } else {
+        client
+            .insert("idempotency_keys", row)
+            .timed("insert idempotency_keys")
+            .await
+    };
+    result.map(|_| ()).map_err(|e| {
+        // supabase_rs reports PostgREST's unique violation as "Error 409: …".
+        if e.contains("409") {
+            ClaimError::Taken
+        } else {
+            ClaimError::Unavailable(e)
+        }
+    })
+}
+
+/// Whether a response reports messages Expo accepted; every send endpoint
+/// counts them in `sent`.
+fn accepted_any(body: &Value) -> bool {
+    body["sent"].as_u64().is_some_and(|sent| sent > 0)
+}
+
+/// Stores the response to replay, or releases the key after a server error
+/// that sent nothing, so a retry is sent again. A server error after some
+/// messages were accepted is stored like any other response: a retry gets
+/// its `job_id` to resume from instead of sending everything again.
+async fn finish_key(client: &SupabaseClient, id: &str, status: StatusCode, body: &Value) {
+    let result = if status.is_server_error() && !accepted_any(body) {
+        client
+            .delete("idempotency_keys", id)
+            .timed("delete idempotency_keys")
+            .await
+            .map(|_| ())
+    } else {
+        client
+            .update(
+                "idempotency_keys",
+                id,
+                json!({
+                    "status": "completed",
+                    "response_status": status.as_u16(),
+                    "response_body": body,
+                }),
+            )
+            .timed("update idempotency_keys")
+            .await
+            .map(|_| ())
+    };
+    if let Err(e) = result {
+        error!(error = %e, "Error storing idempotent response");
+    }
+}
+
+fn replayed(status: StatusCode, body: Value) -> Response {
+    let mut response = (status, Json(body)).into_response();
+    response
+        .headers_mut()
+        .insert("idempotent-replayed", HeaderValue::from_static("true"));
+    response
+}
+
+/// Route layer for the send endpoints. Requests without an
+/// `Idempotency-Key` header pass through. Keys are scoped to the API key;
+/// reusing one for a different request is a `409`, as is a retry while the
+/// first request is still being sent. A key whose request never finished is
+/// reclaimed after [`IN_PROGRESS_LEASE_MINUTES`]. If Supabase cannot be
+/// reached the request is sent without the guarantee rather than rejected.
+///
+/// Use with [`axum::middleware::from_fn_with_state`].
+pub async fn idempotent_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => {
+            return ApiError::BadRequest(format!(
+                "Idempotency-Key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+            ))
+            .into_response()
+        }
+    };
+    // Hashed, since the key is caller-chosen text that ends up in a
+    // PostgREST filter.
+    let id = hex::encode(Sha256::digest(format!(
+        "{}:{key}",
+        tenant_id(request.extensions())
+    )));
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_BODY_BYTES).await else {
+        return ApiError::BadRequest(format!(
+            "Request body must be at most {MAX_BODY_BYTES} bytes"
+        ))
+        .into_response();
+    };
+    let fingerprint = fingerprint(parts.method.as_str(), &parts.uri.to_string(), &bytes);
+    let request = Request::from_parts(parts, Body::from(bytes));
+
+    let client = match initialize_supabase_client(&state.secrets) {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(error = %e, "Idempotency store unavailable; sending without it");
+            return next.run(request).await;
+        }
+    };
+    let expired = match load_key(&client, &id).await {
+        Ok(KeyState::Unseen) => false,
+        Ok(KeyState::Expired) => true,
+        Ok(
+            KeyState::InProgress { fingerprint: seen }
+            | KeyState::Completed {
+                fingerprint: seen, ..
+            },
+        ) if seen != fingerprint => {
+            return ApiError::Conflict(
+                "Idempotency-Key was already used for a different request".into(),
+            )
+            .into_response()
+        }
+        Ok(KeyState::InProgress { .. }) => {
+            return ApiError::Conflict(
+                "A request with this Idempotency-Key is still being processed".into(),
+            )
+            .into_response()
+        }
+        Ok(KeyState::Completed { status, body, .. }) => {
+            info!("Replaying stored response for idempotency key");
+            return replayed(status, body);
+        }
+        Err(_) => {
+            warn!("Idempotency store unavailable; sending without it");
+            return next.run(request).await;
+        }
+    };
+    match claim_key(&client, &id, &fingerprint, expired).await {
+        Ok(()) => {}
+        Err(ClaimError::Taken) => {
+            return ApiError::Conflict(
+                "A request with this Idempotency-Key is still being processed".into(),
+            )
+            .into_response()
+        }
+        Err(ClaimError::Unavailable(e)) => {
+            warn!(error = %e, "Could not claim idempotency key; sending without it");
+            return next.run(request).await;
+        }
+    }
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_BODY_BYTES).await else {
+        finish_key(
+            &client,
+            &id,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &Value::Null,
+        )
+        .await;
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let stored = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    finish_key(&client, &id, parts.status, &stored).await;
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_stable_for_the_same_request() {
+        assert_eq!(
+            fingerprint("POST", "/send", br#"{"title":"Hi"}"#),
+            fingerprint("POST", "/send", br#"{"title":"Hi"}"#)
+        );
+    }
+
+    #[test]
+    fn fingerprint_differs_by_method_uri_and_body() {
+        let base = fingerprint("POST", "/send", b"{}");
+        assert_ne!(base, fingerprint("PUT", "/send", b"{}"));
+        assert_ne!(base, fingerprint("POST", "/send?job_id=1", b"{}"));
+        assert_ne!(base, fingerprint("POST", "/send", b"{ }"));
+    }
+
+    #[test]
+    fn fingerprint_is_a_sha256_hex_digest() {
+        let fingerprint = fingerprint("POST", "/send", b"");
+        assert_eq!(fingerprint.len(), 64);
+        assert!(fingerprint.bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn accepted_any_reads_the_sent_count() {
+        assert!(accepted_any(&json!({ "sent": 3, "job_id": "j" })));
+        assert!(!accepted_any(&json!({ "sent": 0 })));
+        assert!(!accepted_any(&json!({ "error_code": "send_failed" })));
+        assert!(!accepted_any(&Value::Null));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Expo accepts at most 100 messages per push request.
+pub const CHUNK_SIZE: usize = 100;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 256 * 1024;
+
+/// Tokens per chunk for messages of `message_bytes` each: [`CHUNK_SIZE`],
+/// reduced so a chunk stays within `EXPO_MAX_REQUEST_BYTES` (default
+/// 256 KiB) when messages carry rich content or large data payloads. Only
+/// depends on the message, so a resumed job gets the same chunk layout.
+pub fn chunk_size_for(message_bytes: usize) -> usize {
+    let max_request_bytes = env::var("EXPO_MAX_REQUEST_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(DEFAULT_MAX_REQUEST_BYTES);
+    let chunk_size = (max_request_bytes / message_bytes.max(1)).clamp(1, CHUNK_SIZE);
+    if chunk_size < CHUNK_SIZE {
+        info!(
+            message_bytes,
+            max_request_bytes, chunk_size, "Reduced chunk size for large messages"
+        );
+    }
+    chunk_size
+}
+
+/// Progress of a broadcast, persisted in the `broadcast_jobs` table after
+/// every chunk so a re-invocation with the same id picks up where the last
+/// one stopped.
+#[derive(Debug)]
+pub struct BroadcastJob {
+    pub id: String,
+    pub total_chunks: usize,
+    completed_chunks: BTreeSet<usize>,
+}
+
+impl BroadcastJob {
+    pub fn is_completed(&self, chunk_index: usize) -> bool {
+        self.completed_chunks.contains(&chunk_index)
+    }
+
+    pub fn completed_count(&self) -> usize {
+        self.completed_chunks.len()
+    }
+}
+
+#[instrument(skip(client))]
+pub async fn load_or_create_job(
+    client: &SupabaseClient,
+    job_id: &str,
+    total_chunks: usize,
+) -> Result<BroadcastJob, ApiError> {
+    let rows = client
+        .select("broadcast_jobs")
+        .eq("id", job_id)
+        .execute()
+        .timed("select broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching broadcast job");
+            ApiError::SupabaseFetch
+        })?;
+
+    if let Some(row) = rows.first() {
+        let completed_chunks = row["completed_chunks"]
+            .as_array()
+            .map(|chunks| {
+                chunks
+                    .iter()
+                    .filter_map(|c| c.as_u64().map(|c| c as usize))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let job = BroadcastJob {
+            id: job_id.to_string(),
+            total_chunks,
+            completed_chunks,
+        };
+        info!(
+            completed = job.completed_count(),
+            total_chunks, "Resuming broadcast job"
+        );
+        return Ok(job);
+    }
+
+    client
+        .insert(
+            "broadcast_jobs",
+            json!({
+                "id": job_id,
+                "total_chunks": total_chunks,
+                "completed_chunks": [],
+                "status": "sending",
+            }),
+        )
+        .timed("insert broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error creating broadcast job");
+            ApiError::SupabaseWrite
+        })?;
+
+    Ok(BroadcastJob {
+        id: job_id.to_string(),
+        total_chunks,
+        completed_chunks: BTreeSet::new(),
+    })
+}
+
+#[instrument(skip(client, job), fields(job_id = %job.id))]
+pub async fn mark_chunk_completed(
+    client: &SupabaseClient,
+    job: &mut BroadcastJob,
+    chunk_index: usize,
+) -> Result<(), ApiError> {
+    job.completed_chunks.insert(chunk_index);
+    let status = if job.completed_count() >= job.total_chunks {
+        "done"
+    } else {
+        "sending"
+    };
+
+    client
+        .update(
+            "broadcast_jobs",
+            &job.id,
+            json!({
+                "completed_chunks": job.completed_chunks,
+                "status": status,
+            }),
+        )
+        .timed("update broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error checkpointing broadcast job");
+            ApiError::SupabaseWrite
+        })?;
+    Ok(())
+}
+
+/// Re-reads the abort flag so a broadcast can be stopped between chunks.
+#[instrument(skip(client))]
+pub async fn is_job_aborted(client: &SupabaseClient, job_id: &str) -> Result<bool, ApiError> {
+    let rows = client
+        .select("broadcast_jobs")
+        .columns(vec!["aborted"])
+        .eq("id", job_id)
+        .execute()
+        .timed("select broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error checking broadcast job abort flag");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(rows
+        .first()
+        .is_some_and(|row| row["aborted"].as_bool() == Some(true)))
+}
+
+/// A broadcast job as `GET /jobs/{id}` reports it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub id: String,
+    /// `sending`, `paused` (stopped at a deadline) or `aborted`.
+    pub status: String,
+    pub total_chunks: usize,
+    pub completed_chunks: usize,
+    pub aborted: bool,
+}
+
+/// The job's progress, or `None` when no such job exists.
+#[instrument(skip(client))]
+pub async fn load_job_status(
+    client: &SupabaseClient,
+    job_id: &str,
+) -> Result<Option<JobStatus>, ApiError> {
+    let rows = client
+        .select("broadcast_jobs")
+        .eq("id", job_id)
+        .execute()
+        .timed("select broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching broadcast job");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(rows.first().map(|row| JobStatus {
+        id: job_id.to_string(),
+        status: row["status"].as_str().unwrap_or("sending").to_string(),
+        total_chunks: row["total_chunks"].as_u64().unwrap_or_default() as usize,
+        completed_chunks: row["completed_chunks"].as_array().map_or(0, Vec::len),
+        aborted: row["aborted"].as_bool() == Some(true),
+    }))
+}
+
+/// Flags a job as aborted. Returns `false` when no such job exists.
+#[instrument(skip(client))]
+pub async fn abort_job(client: &SupabaseClient, job_id: &str) -> Result<bool, ApiError> {
+    let rows = client
+        .select("broadcast_jobs")
+        .columns(vec!["id"])
+        .eq("id", job_id)
+        .execute()
+        .timed("select broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching broadcast job");
+            ApiError::SupabaseFetch
+        })?;
+    if rows.is_empty() {
+        return Ok(false);
+    }
+
+    client
+        .update(
+            "broadcast_jobs",
+            job_id,
+            json!({ "aborted": true, "status": "aborted" }),
+        )
+        .timed("update broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error aborting broadcast job");
+            ApiError::SupabaseWrite
+        })?;
+    info!("Broadcast job flagged as aborted");
+    Ok(true)
+}
+
+/// Broadcasts still to finish, running or paused at a deadline, and how
+/// many of their chunks are left.
+#[derive(Debug, Default, Serialize)]
+pub struct InFlightJobs {
+    pub jobs: usize,
+    pub remaining_chunks: usize,
+}
+
+#[instrument(skip(client))]
+pub async fn in_flight_jobs(client: &SupabaseClient) -> Result<InFlightJobs, ApiError> {
+    let rows = client
+        .select("broadcast_jobs")
+        .columns(vec!["total_chunks", "completed_chunks"])
+        .in_("status", &["sending", "paused"])
+        .execute()
+        .timed("select broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching in-flight broadcast jobs");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(InFlightJobs {
+        jobs: rows.len(),
+        remaining_chunks: rows
+            .iter()
+            .map(|row| {
+                let total = row["total_chunks"].as_u64().unwrap_or_default() as usize;
+                let completed = row["completed_chunks"].as_array().map_or(0, Vec::len);
+                total.saturating_sub(completed)
+            })
+            .sum(),
+    })
+}
+
+/// Records a job for a broadcast that started without a `job_id` but has to
+/// stop early, so the caller can resume it with the returned id.
+#[instrument(skip(client, completed_chunks))]
+pub async fn create_checkpointed_job(
+    client: &SupabaseClient,
+    total_chunks: usize,
+    completed_chunks: &[usize],
+) -> Result<String, ApiError> {
+    let job_id = Uuid::new_v4().to_string();
+    client
+        .insert(
+            "broadcast_jobs",
+            json!({
+                "id": job_id,
+                "total_chunks": total_chunks,
+                "completed_chunks": completed_chunks,
+                "status": "paused",
+            }),
+        )
+        .timed("insert broadcast_jobs")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error creating checkpointed broadcast job");
+            ApiError::SupabaseWrite
+        })?;
+    info!(job_id = %job_id, completed = completed_chunks.len(), "Checkpointed broadcast job");
+    Ok(job_id)
+}
@@ -0,0 +1,141 @@
+use crate::http_handler::ApiError;
+use crate::router::{decode_body, dry_run_requested};
+use axum::body::to_bytes;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// A send request recorded in offline mode, one JSON line per entry in
+/// `$OFFLINE_JOURNAL`, waiting for `flush` to replay it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: String,
+    /// Route the request was sent to, e.g. `/send` or `/scheduled`.
+    pub route: String,
+    pub body: Value,
+    pub journaled_at: String,
+}
+
+/// Path of the journal when offline mode is on, i.e. `OFFLINE_JOURNAL` is set.
+pub fn journal_path() -> Option<PathBuf> {
+    env::var("OFFLINE_JOURNAL")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Appends the request to the journal and returns its journal id.
+pub fn append(route: &str, body: &Value) -> Result<String, ApiError> {
+    let path = journal_path().ok_or_else(|| ApiError::MissingEnvVar("OFFLINE_JOURNAL".into()))?;
+    let entry = JournalEntry {
+        id: Uuid::new_v4().to_string(),
+        route: route.to_string(),
+        body: body.clone(),
+        journaled_at: Utc::now().to_rfc3339(),
+    };
+    let line = serde_json::to_string(&entry).map_err(|e| ApiError::Journal(e.to_string()))?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{line}"))
+        .map_err(|e| {
+            error!(error = %e, path = %path.display(), "Failed to write offline journal");
+            ApiError::Journal("write failed".into())
+        })?;
+    info!(journal_id = %entry.id, route, "Journaled request");
+    Ok(entry.id)
+}
+
+/// Journaled entries in the order they were written. A missing journal is
+/// empty; malformed lines are skipped with a warning.
+pub fn entries() -> Vec<JournalEntry> {
+    let Some(path) = journal_path() else {
+        return vec![];
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            error!(error = %e, path = %path.display(), "Failed to read offline journal");
+            return vec![];
+        }
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!(error = %e, "Skipping malformed journal entry");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Replaces the journal with `remaining`, the entries a flush could not
+/// deliver, so the next flush retries only those.
+pub fn retain(remaining: &[JournalEntry]) -> Result<(), ApiError> {
+    let path = journal_path().ok_or_else(|| ApiError::MissingEnvVar("OFFLINE_JOURNAL".into()))?;
+    let contents = remaining
+        .iter()
+        .filter_map(|entry| serde_json::to_string(entry).ok())
+        .map(|line| line + "\n")
+        .collect::<String>();
+    fs::write(&path, contents).map_err(|e| {
+        error!(error = %e, path = %path.display(), "Failed to rewrite offline journal");
+        ApiError::Journal("rewrite failed".into())
+    })
+}
+
+/// Route layer for the send endpoints. In offline mode the request body is
+/// appended to the journal and answered with 202, so neither Expo nor
+/// Supabase is called; otherwise, and for dry runs, the request passes
+/// through untouched.
+///
+/// Use with [`axum::middleware::from_fn`].
+pub async fn journal_request(request: Request, next: Next) -> Response {
+    if journal_path().is_none() || dry_run_requested(request.uri()) {
+        return next.run(request).await;
+    }
+    // With the query string, which holds the audience of a filtered
+    // broadcast.
+    let route = request.uri().path_and_query().map_or_else(
+        || request.uri().path().to_string(),
+        |route| route.to_string(),
+    );
+    let Ok(bytes) = to_bytes(request.into_body(), usize::MAX).await else {
+        return ApiError::InvalidBody.into_response();
+    };
+    let body = if bytes.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice(&decode_body(&bytes)) {
+            Ok(body) => body,
+            Err(_) => return ApiError::InvalidBody.into_response(),
+        }
+    };
+    match append(&route, &body) {
+        Ok(journal_id) => (
+            StatusCode::ACCEPTED,
+            Json(json!({
+                "message": "Offline mode; request journaled for a later flush",
+                "journal_id": journal_id,
+            })),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
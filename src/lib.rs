@@ -0,0 +1,113 @@
+//! Expo push notification engine.
+//!
+//! The Lambda binary in `main.rs` only runs the [`router::app`] router on the
+//! Lambda runtime (or locally); services that want to embed the engine can
+//! call the same building blocks directly:
+//!
+//! - [`router`]: the axum routes, extractors and [`AppState`]
+//! - [`http_handler`]: the send pipeline and shared helpers such as
+//!   [`ApiError`]
+//! - [`secrets`]: credentials from SSM, Secrets Manager or the environment,
+//!   see [`get_secrets`]
+//! - [`models`]: typed send request and response bodies
+//! - [`audience`]: resolving recipients through [`audience::AudienceResolver`]
+//!   (Supabase filters, RPC, snapshots, static lists)
+//! - [`expo_retry`]: push requests to Expo, retried with backoff on 429 and
+//!   5xx
+//! - [`jobs`]: checkpointed, resumable and abortable broadcasts
+//! - [`bundles`]: related notifications accepted and tracked as one unit
+//! - [`templates`]: `{{name}}` placeholders in titles and bodies
+//! - [`outbox`]: requests queued while the token store is down
+//! - [`idempotency`]: `Idempotency-Key` replays of earlier send responses
+//! - [`send_queue`]: `?async=true` sends, queued on SQS for the
+//!   `send_worker` binary
+//! - [`batches`]: the state and counts of those sends, for
+//!   `GET /batches/{id}`
+//! - [`scheduling`]: `send_at` notifications, stored until they come due
+//! - [`journal`]: offline mode, where sends go to a local file that the
+//!   `flush` command later replays
+//! - [`receipts`], [`sla`]: push receipt checks and delivery latency SLAs
+//! - [`tokens`], [`unsubscribe`], [`preferences`]: token registration,
+//!   one-tap opt-out and notification preferences from the app
+//! - [`topics`]: topic subscriptions and sends to a topic's subscribers
+//! - [`token_store`]: the [`token_store::TokenStore`] trait over Supabase
+//!   or DynamoDB
+//! - [`admin`] / [`maintenance`] / [`trash`]: token table hygiene, with
+//!   soft deletion so admin deletes can be undone
+//! - [`webhooks`], [`events`], [`metrics`]: outbound callbacks, the event
+//!   log and CloudWatch metrics
+//! - [`privacy`], [`history`]: how much notification content logs and
+//!   history may hold, the deduplicated content table and per-broadcast
+//!   history used for resends
+//! - [`notification_log`]: a summary of every send for the admin
+//!   dashboard, listed by `GET /notifications`
+//! - [`response_format`]: `Accept: text/plain` one-line results for the
+//!   send endpoints
+//! - [`selftest`]: post-deploy end-to-end check against test devices
+//! - [`health`]: the Supabase and Expo probes behind `GET /health`
+//! - [`rate_limit`]: token buckets per API key, optionally shared through
+//!   DynamoDB
+//! - [`rotation`]: Secrets Manager rotation of the API key
+//! - [`api_keys`]: accepted API keys and the scopes each is limited to
+//! - [`middleware`]: the tower layers (auth, logging, correlation ids, rate
+//!   limiting, panic handling) that [`middleware::stack`] wraps around the
+//!   handler
+//! - [`features`]: feature flags, with per-request admin overrides
+//! - [`http_client`]: outbound proxy and extra CA settings
+//! - `client` (with the `client` feature): a typed client for this API
+//! - [`logging`]: the `tracing` subscriber, JSON lines on Lambda
+//! - [`config`], [`cache`], [`i18n`], [`timings`], [`trace_context`],
+//!   [`build_info`]: cross-cutting support
+pub mod admin;
+pub mod api_keys;
+pub mod audience;
+pub mod batches;
+pub mod build_info;
+pub mod bundles;
+pub mod cache;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+pub mod events;
+pub mod expo_retry;
+pub mod features;
+pub mod health;
+pub mod history;
+pub mod http_client;
+pub mod http_handler;
+pub mod i18n;
+pub mod idempotency;
+pub mod jobs;
+pub mod journal;
+pub mod logging;
+pub mod maintenance;
+pub mod metrics;
+pub mod middleware;
+pub mod models;
+pub mod notification_log;
+pub mod outbox;
+pub mod preferences;
+pub mod privacy;
+pub mod rate_limit;
+pub mod receipts;
+pub mod response_format;
+pub mod rotation;
+pub mod router;
+pub mod scheduling;
+pub mod secrets;
+pub mod selftest;
+pub mod send_queue;
+pub mod sla;
+pub mod templates;
+pub mod timings;
+pub mod token_store;
+pub mod tokens;
+pub mod topics;
+pub mod trace_context;
+pub mod trash;
+pub mod unsubscribe;
+pub mod webhooks;
+
+pub use http_handler::ApiError;
+pub use router::AppState;
+pub use secrets::get_secrets;
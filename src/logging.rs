@@ -0,0 +1,48 @@
+use std::env;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+/// Whether log lines are JSON objects. `LOG_FORMAT` (`json` or `text`)
+/// decides, then Lambda's own `AWS_LAMBDA_LOG_FORMAT`; without either, logs
+/// are JSON on Lambda, where CloudWatch Logs Insights can query the fields,
+/// and text everywhere else.
+fn json_format() -> bool {
+    match env::var("LOG_FORMAT")
+        .or_else(|_| env::var("AWS_LAMBDA_LOG_FORMAT"))
+        .map(|format| format.to_lowercase())
+        .as_deref()
+    {
+        Ok("json") => true,
+        Ok("text") => false,
+        _ => env::var("AWS_LAMBDA_FUNCTION_NAME").is_ok(),
+    }
+}
+
+/// Installs the global `tracing` subscriber. The level comes from
+/// `AWS_LAMBDA_LOG_LEVEL`, then `RUST_LOG`, defaulting to `info`. JSON lines
+/// carry the event fields at the top level and the enclosing spans under
+/// `spans`, outermost first, so every line has the request span's
+/// `request_id` and `correlation_id`.
+pub fn init() {
+    let level = env::var("AWS_LAMBDA_LOG_LEVEL")
+        .or_else(|_| env::var("RUST_LOG"))
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_env_filter(filter);
+
+    if json_format() {
+        subscriber
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .init();
+    } else {
+        subscriber.without_time().init();
+    }
+}
@@ -1,10 +1,48 @@
-use lambda_http::{run, service_fn, tracing, Error};
-mod http_handler;
-use http_handler::function_handler;
+use expo_push_notification_api::router::{
+    app, flush_journal, misconfigured_app, run_lambda, serve_local, LambdaRouter,
+};
+use expo_push_notification_api::{
+    build_info, http_client, journal, logging, middleware, ApiError, AppState,
+};
+use lambda_http::{tracing, Error};
+use std::env;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing::init_default_subscriber();
+    logging::init();
+    build_info::log_startup();
+    http_client::configure_sdk_clients();
 
-    run(service_fn(function_handler)).await
+    // `cargo run -- flush` replays the offline journal against the real
+    // services and exits.
+    if env::args().nth(1).as_deref() == Some("flush") {
+        let summary = flush_journal(&AppState::load().await?).await?;
+        println!("{summary}");
+        return Ok(());
+    }
+
+    // Set OFFLINE_JOURNAL to a file path to journal sends there instead of
+    // calling Expo or Supabase, e.g. for offline development and demos.
+    let (state, router) = match journal::journal_path() {
+        Some(path) => {
+            tracing::info!(path = %path.display(), "Offline mode, journaling sends");
+            let state = AppState::offline();
+            (state.clone(), app(state))
+        }
+        None => match AppState::load().await {
+            Ok(state) => (state.clone(), app(state)),
+            Err(ApiError::Misconfigured { missing }) => {
+                (AppState::offline(), misconfigured_app(missing))
+            }
+            Err(e) => return Err(e.into()),
+        },
+    };
+    let service = middleware::stack(LambdaRouter::new(router));
+
+    // Set DEV_SERVER_ADDR (e.g. 127.0.0.1:3000) to serve plain HTTP locally
+    // instead of polling the Lambda runtime API.
+    match env::var("DEV_SERVER_ADDR") {
+        Ok(addr) => serve_local(&addr, service).await,
+        Err(_) => run_lambda(state, service).await,
+    }
 }
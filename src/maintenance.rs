@@ -0,0 +1,357 @@
+use crate::http_handler::{max_concurrent_sends, select_all_pages, ApiError};
+use crate::jobs::CHUNK_SIZE;
+use crate::metrics::Timed;
+use crate::trash::{is_deleted, DEFAULT_TRASH_RETENTION_DAYS};
+use chrono::{Duration, Utc};
+use expo_push_notification_client::{Expo, ExpoPushMessage, ExpoPushTicket, Priority};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+const CLEANUP_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Default, Serialize)]
+pub struct RevalidationSummary {
+    pub checked: usize,
+    /// Tokens Expo took a revalidation push for. They are promoted by the
+    /// receipt check once the receipt comes back `ok`.
+    pub awaiting_receipt: usize,
+    pub still_quarantined: usize,
+}
+
+#[instrument(skip(client))]
+pub async fn fetch_quarantined_tokens(client: &SupabaseClient) -> Result<Vec<String>, ApiError> {
+    let response = select_all_pages(
+        || client.select("users").eq("quarantined", "true"),
+        "select users",
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Error fetching quarantined tokens");
+        ApiError::SupabaseFetch
+    })?;
+
+    let tokens = response
+        .iter()
+        .filter(|row| !is_deleted(row))
+        .filter_map(|row| row["expo_push_token"].as_str().map(|s| s.to_string()))
+        .collect::<Vec<String>>();
+    info!(
+        token_count = tokens.len(),
+        "Fetched quarantined tokens from Supabase"
+    );
+    Ok(tokens)
+}
+
+/// Sends a silent, low-priority push to every quarantined token and records
+/// the tickets Expo returns as revalidation tickets. An accepted ticket only
+/// means Expo took the message; [`check_receipts`] moves a token back to
+/// active once its receipt is `ok`, so devices that were only offline for a
+/// while start receiving broadcasts again.
+///
+/// [`check_receipts`]: crate::receipts::check_receipts
+#[instrument(skip(expo, client))]
+pub async fn revalidate_quarantined_tokens(
+    expo: &Expo,
+    client: &SupabaseClient,
+) -> Result<RevalidationSummary, ApiError> {
+    let tokens = fetch_quarantined_tokens(client).await?;
+    let mut summary = RevalidationSummary {
+        checked: tokens.len(),
+        ..Default::default()
+    };
+
+    // One message per chunk, with a ticket per recipient in `to` order, so
+    // a large quarantine takes as few requests as a broadcast of its size.
+    let messages = tokens
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| {
+            ExpoPushMessage::builder(chunk)
+                .data(&json!({ "type": "token_revalidation" }))
+                .map_err(|_| ApiError::PushMessageBuild)
+                .and_then(|builder| {
+                    builder
+                        .priority(Priority::Normal)
+                        .content_available(true)
+                        .build()
+                        .map_err(|_| ApiError::PushMessageBuild)
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut results = vec![];
+    for batch in messages.chunks(max_concurrent_sends()) {
+        results.extend(
+            join_all(
+                batch
+                    .iter()
+                    .map(|msg| expo.send_push_notifications(msg.clone())),
+            )
+            .await,
+        );
+    }
+
+    let mut accepted = vec![];
+    for (chunk, result) in tokens.chunks(CHUNK_SIZE).zip(results) {
+        let tickets = match result {
+            Ok(tickets) if tickets.len() == chunk.len() => tickets,
+            Ok(tickets) => {
+                warn!(
+                    ticket_count = tickets.len(),
+                    token_count = chunk.len(),
+                    "Expo returned the wrong number of revalidation tickets"
+                );
+                vec![]
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to send revalidation pushes");
+                vec![]
+            }
+        };
+        for (position, token) in chunk.iter().enumerate() {
+            match tickets.get(position) {
+                Some(ExpoPushTicket::Ok(ticket)) => {
+                    accepted.push((token.clone(), ticket.id.to_string()))
+                }
+                _ => summary.still_quarantined += 1,
+            }
+        }
+    }
+
+    match record_revalidation_tickets(client, &accepted).await {
+        Ok(()) => summary.awaiting_receipt = accepted.len(),
+        Err(e) => {
+            warn!(error = %e, "Failed to record revalidation tickets");
+            summary.still_quarantined += accepted.len();
+        }
+    }
+
+    info!(
+        checked = summary.checked,
+        awaiting_receipt = summary.awaiting_receipt,
+        still_quarantined = summary.still_quarantined,
+        "Finished revalidating quarantined tokens"
+    );
+    Ok(summary)
+}
+
+/// Stores revalidation tickets in `push_tickets`, flagged so the receipt
+/// check promotes their tokens and leaves them out of the delivery SLA.
+async fn record_revalidation_tickets(
+    client: &SupabaseClient,
+    tickets: &[(String, String)],
+) -> Result<(), ApiError> {
+    if tickets.is_empty() {
+        return Ok(());
+    }
+    let accepted_at = Utc::now().to_rfc3339();
+    let rows = tickets
+        .iter()
+        .map(|(token, ticket_id)| {
+            json!({
+                "id": ticket_id,
+                "expo_push_token": token,
+                "revalidation": true,
+                "accepted_at": accepted_at,
+                "status": "pending",
+            })
+        })
+        .collect::<Vec<_>>();
+    client
+        .bulk_insert("push_tickets", rows)
+        .timed("insert push_tickets")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error storing revalidation tickets");
+            ApiError::SupabaseWrite
+        })
+}
+
+/// Rows of `table` (matching `status`, if set) that may be deleted once
+/// their `timestamp_column` is older than the retention period.
+struct RetentionRule {
+    name: &'static str,
+    table: &'static str,
+    status: Option<&'static str>,
+    timestamp_column: &'static str,
+    retention_env: &'static str,
+    default_retention_days: i64,
+}
+
+const RETENTION_RULES: [RetentionRule; 11] = [
+    RetentionRule {
+        name: "completed_jobs",
+        table: "broadcast_jobs",
+        status: Some("done"),
+        timestamp_column: "created_at",
+        retention_env: "JOB_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    RetentionRule {
+        name: "aborted_jobs",
+        table: "broadcast_jobs",
+        status: Some("aborted"),
+        timestamp_column: "created_at",
+        retention_env: "JOB_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    RetentionRule {
+        name: "deleted_tokens",
+        table: "users",
+        status: None,
+        timestamp_column: "deleted_at",
+        retention_env: "TRASH_RETENTION_DAYS",
+        default_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+    },
+    RetentionRule {
+        name: "deleted_templates",
+        table: "notification_templates",
+        status: None,
+        timestamp_column: "deleted_at",
+        retention_env: "TRASH_RETENTION_DAYS",
+        default_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+    },
+    // Keys are only replayed for 24 hours.
+    RetentionRule {
+        name: "idempotency_keys",
+        table: "idempotency_keys",
+        status: None,
+        timestamp_column: "created_at",
+        retention_env: "IDEMPOTENCY_RETENTION_DAYS",
+        default_retention_days: 1,
+    },
+    RetentionRule {
+        name: "notification_log",
+        table: "notification_log",
+        status: None,
+        timestamp_column: "created_at",
+        retention_env: "NOTIFICATION_LOG_RETENTION_DAYS",
+        default_retention_days: 90,
+    },
+    RetentionRule {
+        name: "sent_schedules",
+        table: "scheduled_notifications",
+        status: Some("sent"),
+        timestamp_column: "dispatched_at",
+        retention_env: "SCHEDULE_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    RetentionRule {
+        name: "failed_schedules",
+        table: "scheduled_notifications",
+        status: Some("failed"),
+        timestamp_column: "dispatched_at",
+        retention_env: "SCHEDULE_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    RetentionRule {
+        name: "cancelled_schedules",
+        table: "scheduled_notifications",
+        status: Some("cancelled"),
+        timestamp_column: "cancelled_at",
+        retention_env: "SCHEDULE_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    // Expo keeps receipts for a day, so a ticket still pending by then
+    // never resolves either.
+    RetentionRule {
+        name: "push_tickets",
+        table: "push_tickets",
+        status: None,
+        timestamp_column: "accepted_at",
+        retention_env: "TICKET_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+    RetentionRule {
+        name: "webhook_deliveries",
+        table: "webhook_deliveries",
+        status: None,
+        timestamp_column: "created_at",
+        retention_env: "WEBHOOK_DELIVERY_RETENTION_DAYS",
+        default_retention_days: 30,
+    },
+];
+
+#[derive(Debug, Default, Serialize)]
+pub struct CleanupSummary {
+    /// Rows deleted per retention rule.
+    pub deleted: BTreeMap<&'static str, usize>,
+    pub failed: usize,
+}
+
+async fn apply_retention_rule(
+    client: &SupabaseClient,
+    rule: &RetentionRule,
+    summary: &mut CleanupSummary,
+) -> Result<(), ApiError> {
+    let retention_days = env::var(rule.retention_env)
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(rule.default_retention_days);
+    let cutoff = (Utc::now() - Duration::days(retention_days)).to_rfc3339();
+
+    let select = || {
+        let query = client
+            .select(rule.table)
+            .columns(vec!["id"])
+            .lt(rule.timestamp_column, &cutoff);
+        match rule.status {
+            Some(status) => query.eq("status", status),
+            None => query,
+        }
+    };
+    let rows = select_all_pages(select, &format!("select {}", rule.table))
+        .await
+        .map_err(|e| {
+            error!(error = ?e, rule = rule.name, "Error fetching expired rows");
+            ApiError::SupabaseFetch
+        })?;
+    let ids = rows
+        .iter()
+        .filter_map(|row| match &row["id"] {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let deleted = summary.deleted.entry(rule.name).or_default();
+    for batch in ids.chunks(CLEANUP_BATCH_SIZE) {
+        let results = join_all(batch.iter().map(|id| {
+            client
+                .delete_without_defined_key(rule.table, "id", id)
+                .timed(format!("delete {}", rule.table))
+        }))
+        .await;
+        for result in results {
+            match result {
+                Ok(()) => *deleted += 1,
+                Err(e) => {
+                    warn!(error = %e, rule = rule.name, "Failed to delete expired row");
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes finished records and soft-deleted rows past their retention
+/// period so the tables don't grow without bound. Meant to run from a daily schedule.
+#[instrument(skip(client))]
+pub async fn cleanup_expired_records(client: &SupabaseClient) -> Result<CleanupSummary, ApiError> {
+    let mut summary = CleanupSummary::default();
+    for rule in &RETENTION_RULES {
+        apply_retention_rule(client, rule, &mut summary).await?;
+    }
+    info!(
+        deleted = ?summary.deleted,
+        failed = summary.failed,
+        "Finished cleaning up expired records"
+    );
+    Ok(summary)
+}
@@ -0,0 +1,210 @@
+use crate::sla::SlaReport;
+use chrono::Utc;
+use expo_push_notification_client::{CustomError, DetailsErrorType, ExpoPushTicket};
+use serde_json::json;
+use std::env;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const NAMESPACE: &str = "ExpoPushNotificationApi";
+const DEFAULT_INVALID_TOKEN_RATE_THRESHOLD: f64 = 0.2;
+const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Whether Expo rejected the ticket because the token is no longer valid.
+pub fn is_dead_token(result: &Result<Vec<ExpoPushTicket>, CustomError>) -> bool {
+    matches!(
+        result.as_deref(),
+        Ok([ExpoPushTicket::Error(receipt), ..])
+            if receipt
+                .details
+                .as_ref()
+                .and_then(|details| details.error.as_ref())
+                == Some(&DetailsErrorType::DeviceNotRegistered)
+    )
+}
+
+/// Emits `InvalidTokenRate` for one broadcast as a CloudWatch Embedded
+/// Metric Format line, and logs an `invalid_token_rate_exceeded` event when
+/// the rate crosses `INVALID_TOKEN_RATE_THRESHOLD` so it can back an alarm.
+pub fn record_invalid_token_rate(invalid: usize, total: usize) {
+    if total == 0 {
+        return;
+    }
+    let rate = invalid as f64 / total as f64;
+
+    // EMF must be a bare JSON line on stdout, so it bypasses tracing.
+    println!(
+        "{}",
+        json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [[]],
+                    "Metrics": [
+                        { "Name": "InvalidTokenRate", "Unit": "None" },
+                        { "Name": "InvalidTokens", "Unit": "Count" },
+                    ],
+                }],
+            },
+            "InvalidTokenRate": rate,
+            "InvalidTokens": invalid,
+        })
+    );
+
+    let threshold = env::var("INVALID_TOKEN_RATE_THRESHOLD")
+        .ok()
+        .and_then(|t| t.parse::<f64>().ok())
+        .unwrap_or(DEFAULT_INVALID_TOKEN_RATE_THRESHOLD);
+    if rate > threshold {
+        error!(
+            event = "invalid_token_rate_exceeded",
+            invalid, total, rate, threshold, "Invalid token rate above threshold"
+        );
+    } else {
+        info!(invalid, total, rate, "Invalid token rate");
+    }
+}
+
+/// Whether a message got no ticket for a reason other than a dead token:
+/// the request to Expo failed, or Expo or the provider rejected it.
+pub fn is_expo_error(result: &Result<Vec<ExpoPushTicket>, CustomError>) -> bool {
+    !is_dead_token(result) && !matches!(result.as_deref(), Ok([ExpoPushTicket::Ok(_), ..]))
+}
+
+/// Emits `NotificationsSent` (messages Expo accepted), `TokensInvalid`
+/// (tokens dropped as malformed or reported `DeviceNotRegistered`),
+/// `ExpoErrors` (see [`is_expo_error`]) and `SendLatency` (milliseconds
+/// from the request to the last ticket) for one broadcast, to back send
+/// dashboards and failure alarms.
+pub fn record_send(sent: usize, tokens_invalid: usize, expo_errors: usize, latency: Duration) {
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+    println!(
+        "{}",
+        json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [[]],
+                    "Metrics": [
+                        { "Name": "NotificationsSent", "Unit": "Count" },
+                        { "Name": "TokensInvalid", "Unit": "Count" },
+                        { "Name": "ExpoErrors", "Unit": "Count" },
+                        { "Name": "SendLatency", "Unit": "Milliseconds" },
+                    ],
+                }],
+            },
+            "NotificationsSent": sent,
+            "TokensInvalid": tokens_invalid,
+            "ExpoErrors": expo_errors,
+            "SendLatency": latency_ms,
+        })
+    );
+    info!(
+        sent,
+        tokens_invalid, expo_errors, latency_ms, "Recorded send metrics"
+    );
+}
+
+/// Emits `AudienceEmpty` for a broadcast whose audience resolved to no
+/// tokens, which usually means a broken segment rather than a quiet day.
+pub fn record_audience_empty() {
+    println!(
+        "{}",
+        json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [[]],
+                    "Metrics": [{ "Name": "AudienceEmpty", "Unit": "Count" }],
+                }],
+            },
+            "AudienceEmpty": 1,
+        })
+    );
+    warn!(event = "audience_empty", "Broadcast audience is empty");
+}
+
+/// Emits `DeliveryP50` / `DeliveryP95` (seconds from acceptance to receipt)
+/// and `SlaBreach` for one category of a receipt check.
+pub fn record_delivery_latency(category: &str, report: &SlaReport) {
+    println!(
+        "{}",
+        json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [["Category"]],
+                    "Metrics": [
+                        { "Name": "DeliveryP50", "Unit": "Seconds" },
+                        { "Name": "DeliveryP95", "Unit": "Seconds" },
+                        { "Name": "SlaBreach", "Unit": "Count" },
+                    ],
+                }],
+            },
+            "Category": category,
+            "DeliveryP50": report.p50_secs,
+            "DeliveryP95": report.p95_secs,
+            "SlaBreach": u8::from(report.breached),
+        })
+    );
+}
+
+/// Emits `SupabaseLatency` for one store call, with the query description as
+/// the `Query` dimension, and warns with a `slow_query` event when the call
+/// took longer than `SLOW_QUERY_THRESHOLD_MS` (default 500).
+pub fn record_query_latency(description: &str, elapsed: Duration) {
+    let latency_ms = elapsed.as_secs_f64() * 1000.0;
+    println!(
+        "{}",
+        json!({
+            "_aws": {
+                "Timestamp": Utc::now().timestamp_millis(),
+                "CloudWatchMetrics": [{
+                    "Namespace": NAMESPACE,
+                    "Dimensions": [["Query"]],
+                    "Metrics": [{ "Name": "SupabaseLatency", "Unit": "Milliseconds" }],
+                }],
+            },
+            "Query": description,
+            "SupabaseLatency": latency_ms,
+        })
+    );
+
+    let threshold = env::var("SLOW_QUERY_THRESHOLD_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_SLOW_QUERY_THRESHOLD);
+    if elapsed > threshold {
+        warn!(
+            event = "slow_query",
+            query = description,
+            latency_ms,
+            threshold_ms = threshold.as_millis() as u64,
+            "Slow Supabase query"
+        );
+    } else {
+        debug!(query = description, latency_ms, "Supabase query");
+    }
+}
+
+/// Times a store call, see [`record_query_latency`]:
+/// `client.select("users").execute().timed("select users").await`.
+pub trait Timed: Future + Sized {
+    fn timed(self, description: impl Into<String>) -> impl Future<Output = Self::Output> {
+        let description = description.into();
+        async move {
+            let started = Instant::now();
+            let output = self.await;
+            record_query_latency(&description, started.elapsed());
+            output
+        }
+    }
+}
+
+impl<F: Future> Timed for F {}
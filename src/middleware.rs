@@ -0,0 +1,609 @@
+//! Cross-cutting concerns as tower layers, so every route inherits them
+//! without repeating the logic in handlers. See [`stack`] for the order they
+//! are applied in.
+
+use crate::api_keys::{api_keys, find_key, required_scope, scope_caller, Caller};
+use crate::config::dynamic_config;
+use crate::http_handler::{create_error_response, PROBLEM_CONTENT_TYPE};
+use crate::i18n::{localized_message, localized_success, Language};
+use crate::rate_limit::{rate_from_env, retry_after_secs, KeyRateLimiter, TokenBucket};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use http::StatusCode;
+use lambda_http::request::RequestContext;
+use lambda_http::{Body, Error, Request, RequestExt, Response};
+use serde_json::Value;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service, ServiceBuilder};
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+type HandlerFuture = BoxFuture<'static, Result<Response<Body>, Error>>;
+
+/// Wraps `handler` in the standard middleware stack, outermost first:
+/// error localization, panic handling, correlation ids, request logging,
+/// per source IP rate limiting, API key auth and rate limiting.
+pub fn stack<S>(
+    handler: S,
+) -> impl Service<Request, Response = Response<Body>, Error = Error, Future = HandlerFuture> + Clone
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    ServiceBuilder::new()
+        .layer(LocalizeErrorsLayer)
+        .layer(CatchPanicLayer)
+        .layer(CorrelationIdLayer)
+        .layer(RequestLogLayer)
+        .layer(SourceIpRateLimitLayer::from_env())
+        .layer(AuthLayer)
+        .layer(RateLimitLayer::from_env())
+        .service(handler)
+}
+
+/// Correlation id of the current request, available from request extensions.
+#[derive(Debug, Clone)]
+pub struct CorrelationId(pub String);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalizeErrorsLayer;
+
+impl<S> Layer<S> for LocalizeErrorsLayer {
+    type Service = LocalizeErrors<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LocalizeErrors { inner }
+    }
+}
+
+/// Adds a `message` in the caller's `Accept-Language` to JSON error bodies
+/// that carry an `error_code`, and translates the `message` of success
+/// bodies, for operators reading responses in the dashboard. Error bodies
+/// also get the `request_id` and are served as `application/problem+json`.
+/// Other fields are left as they are.
+#[derive(Debug, Clone)]
+pub struct LocalizeErrors<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for LocalizeErrors<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let language = Language::from_headers(request.headers());
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let response = future.await?;
+            let failed = response.status().is_client_error() || response.status().is_server_error();
+            if !failed && !response.status().is_success() {
+                return Ok(response);
+            }
+            let (mut parts, body) = response.into_parts();
+            let Body::Text(text) = &body else {
+                return Ok(Response::from_parts(parts, body));
+            };
+            let Ok(Value::Object(mut fields)) = serde_json::from_str::<Value>(text) else {
+                return Ok(Response::from_parts(parts, body));
+            };
+            if failed {
+                if let (Some(error_code), Some(message)) = (
+                    fields.get("error_code").and_then(Value::as_str),
+                    fields.get("error").and_then(Value::as_str),
+                ) {
+                    let message = localized_message(language, error_code, message);
+                    fields.insert("message".into(), Value::String(message));
+                    if let Some(request_id) = parts
+                        .headers
+                        .get(CORRELATION_ID_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                    {
+                        fields.insert("request_id".into(), Value::String(request_id.into()));
+                    }
+                    parts
+                        .headers
+                        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+                }
+            } else {
+                let Some(message) = fields.get("message").and_then(Value::as_str) else {
+                    return Ok(Response::from_parts(parts, body));
+                };
+                let config = dynamic_config().await;
+                match localized_success(language, message, &config.success_messages) {
+                    Some(message) => {
+                        fields.insert("message".into(), Value::String(message));
+                    }
+                    None => return Ok(Response::from_parts(parts, body)),
+                }
+            }
+            // The body changed length.
+            parts.headers.remove(CONTENT_LENGTH);
+            Ok(Response::from_parts(
+                parts,
+                Body::Text(Value::Object(fields).to_string()),
+            ))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CatchPanicLayer;
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic { inner }
+    }
+}
+
+/// Turns a panic anywhere below into a 500 JSON response instead of an
+/// opaque runtime error.
+#[derive(Debug, Clone)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for CatchPanic<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Inner layers may panic while building their future, not only while
+        // it is polled.
+        let future = match panic::catch_unwind(AssertUnwindSafe(|| self.inner.call(request))) {
+            Ok(future) => future,
+            Err(payload) => return Box::pin(async move { panic_response(payload) }),
+        };
+        Box::pin(async move {
+            match AssertUnwindSafe(future).catch_unwind().await {
+                Ok(result) => result,
+                Err(payload) => panic_response(payload),
+            }
+        })
+    }
+}
+
+fn panic_response(payload: Box<dyn Any + Send>) -> Result<Response<Body>, Error> {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    error!(panic = %message, "Handler panicked");
+    create_error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "Internal error",
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CorrelationIdLayer;
+
+impl<S> Layer<S> for CorrelationIdLayer {
+    type Service = CorrelationIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CorrelationIdService { inner }
+    }
+}
+
+/// Takes the id from `x-correlation-id`, falling back to the Lambda request
+/// id, and echoes it on the response.
+#[derive(Debug, Clone)]
+pub struct CorrelationIdService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for CorrelationIdService<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let correlation_id = request
+            .headers()
+            .get(CORRELATION_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .or_else(|| {
+                request
+                    .lambda_context_ref()
+                    .map(|context| context.request_id.clone())
+            })
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        request
+            .extensions_mut()
+            .insert(CorrelationId(correlation_id.clone()));
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+                response.headers_mut().insert(CORRELATION_ID_HEADER, value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLogLayer;
+
+impl<S> Layer<S> for RequestLogLayer {
+    type Service = RequestLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestLog { inner }
+    }
+}
+
+/// Opens the per-request span and logs method, path, status and latency.
+#[derive(Debug, Clone)]
+pub struct RequestLog<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for RequestLog<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let correlation_id = request
+            .extensions()
+            .get::<CorrelationId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_default();
+        let request_id = request
+            .lambda_context_ref()
+            .map(|context| context.request_id.clone())
+            .unwrap_or_default();
+        let path = match request.raw_http_path() {
+            "" => request.uri().path().to_string(),
+            path => path.to_string(),
+        };
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            correlation_id = %correlation_id,
+            method = %request.method(),
+            path = %path,
+        );
+        let started = Instant::now();
+        let future = span.in_scope(|| self.inner.call(request));
+
+        Box::pin(
+            async move {
+                let result = future.await;
+                let latency_ms = started.elapsed().as_millis() as u64;
+                match &result {
+                    Ok(response) => {
+                        info!(
+                            status = response.status().as_u16(),
+                            latency_ms, "Request completed"
+                        )
+                    }
+                    Err(e) => error!(error = %e, latency_ms, "Request failed"),
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AuthLayer;
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = Auth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Auth { inner }
+    }
+}
+
+/// Rejects requests without an `x-api-key` (401), with one that is not one
+/// of [`crate::api_keys::api_keys`], or whose key lacks the
+/// [`required_scope`] of the route (both 403).
+#[derive(Debug, Clone)]
+pub struct Auth<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for Auth<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let provided = request
+            .headers()
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        // `raw_http_path` is empty for requests from `serve_local`.
+        let scope = match request.raw_http_path() {
+            "" => required_scope(request.method(), request.uri().path()),
+            path => required_scope(request.method(), path),
+        };
+        // The inner service was readied by `poll_ready`; keep that instance.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let keys = api_keys().await;
+            if keys.is_empty() {
+                error!("No API key is configured");
+                return create_error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "misconfigured",
+                    "Service is misconfigured; missing api-key (secret, API_KEY, API_KEY_SECRET_ID or API_KEYS)",
+                );
+            }
+            let Some(provided) = provided else {
+                let mut response = create_error_response(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "Unauthorized: missing x-api-key header",
+                )?;
+                response.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("ApiKey header=\"x-api-key\""),
+                );
+                return Ok(response);
+            };
+            let Some(key) = find_key(keys, &provided) else {
+                warn!("Invalid API key attempt");
+                return create_error_response(
+                    StatusCode::FORBIDDEN,
+                    "forbidden",
+                    "Forbidden: Invalid API Key",
+                );
+            };
+            if let Some(scope) = scope.filter(|scope| !key.allows(*scope)) {
+                warn!(api_key = %key.name, scope = %scope, "API key lacks the required scope");
+                return create_error_response(
+                    StatusCode::FORBIDDEN,
+                    "insufficient_scope",
+                    &format!("Forbidden: API key lacks the {scope} scope"),
+                );
+            }
+            let tenant = key.tenant().to_string();
+            request.extensions_mut().insert(Caller {
+                name: key.name.clone(),
+                tenant: tenant.clone(),
+                scopes: key.scopes.clone(),
+            });
+            scope_caller(key.name, tenant, inner.call(request)).await
+        })
+    }
+}
+
+/// One in-memory token bucket per source IP (`SOURCE_IP_RATE_LIMIT_PER_SECOND`,
+/// `SOURCE_IP_RATE_LIMIT_BURST`), applied before auth so a client guessing
+/// API keys is throttled too. Disabled when the rate is unset.
+#[derive(Debug, Clone)]
+pub struct SourceIpRateLimitLayer {
+    limiter: Option<Arc<KeyRateLimiter>>,
+}
+
+impl SourceIpRateLimitLayer {
+    pub fn from_env() -> Self {
+        Self {
+            limiter: KeyRateLimiter::per_source_ip_from_env().map(Arc::new),
+        }
+    }
+}
+
+impl<S> Layer<S> for SourceIpRateLimitLayer {
+    type Service = SourceIpRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SourceIpRateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// Answers 429 with a `Retry-After` once the bucket of the request's source
+/// IP is empty. Requests without a known source IP are not limited here.
+#[derive(Debug, Clone)]
+pub struct SourceIpRateLimit<S> {
+    inner: S,
+    limiter: Option<Arc<KeyRateLimiter>>,
+}
+
+/// The client address API Gateway saw, or else the last `X-Forwarded-For`
+/// hop, the one added by the proxy in front (an ALB, or none locally).
+fn source_ip(request: &Request) -> Option<String> {
+    let from_context = match request.request_context_ref() {
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.clone(),
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.clone(),
+        _ => None,
+    };
+    from_context.or_else(|| {
+        let forwarded = request.headers().get("x-forwarded-for")?.to_str().ok()?;
+        Some(forwarded.rsplit(',').next()?.trim().to_string()).filter(|ip| !ip.is_empty())
+    })
+}
+
+impl<S> Service<Request> for SourceIpRateLimit<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if let Some((limiter, ip)) = self.limiter.as_ref().zip(source_ip(&request)) {
+            if let Err(wait) = limiter.try_acquire(&ip) {
+                warn!(source_ip = %ip, "Source IP rate limit exceeded");
+                return Box::pin(async move { rate_limited(wait) });
+            }
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+/// In-memory token buckets for a warm container: one shared by every
+/// request (`RATE_LIMIT_PER_SECOND`, `RATE_LIMIT_BURST`) and one per API key
+/// (see [`crate::rate_limit`]). Each is disabled when its rate is unset.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+    keys: Option<Arc<KeyRateLimiter>>,
+}
+
+impl RateLimitLayer {
+    pub fn from_env() -> Self {
+        let bucket = rate_from_env("RATE_LIMIT_PER_SECOND", "RATE_LIMIT_BURST")
+            .map(|(rate, burst)| Arc::new(Mutex::new(TokenBucket::new(rate, burst))));
+        Self {
+            bucket,
+            keys: KeyRateLimiter::from_env().map(Arc::new),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            bucket: self.bucket.clone(),
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+/// Answers 429 with a `Retry-After` once a bucket of [`RateLimitLayer`] is
+/// empty, or, with the `dynamodb` feature, once a key used up its minute in
+/// `RATE_LIMIT_TABLE`.
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+    keys: Option<Arc<KeyRateLimiter>>,
+}
+
+fn rate_limited(wait: Duration) -> Result<Response<Body>, Error> {
+    let mut response = create_error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        "Too Many Requests",
+    )?;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs(wait)));
+    Ok(response)
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = HandlerFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if let Some(bucket) = &self.bucket {
+            if let Err(wait) = bucket.lock().expect("rate limiter poisoned").try_acquire() {
+                warn!("Rate limit exceeded");
+                return Box::pin(async move { rate_limited(wait) });
+            }
+        }
+        let caller = request
+            .extensions()
+            .get::<Caller>()
+            .map(|caller| caller.name.clone());
+        let keys = self.keys.clone().zip(caller);
+        if let Some((keys, caller)) = &keys {
+            if let Err(wait) = keys.try_acquire(caller) {
+                warn!(api_key = %caller, "API key rate limit exceeded");
+                return Box::pin(async move { rate_limited(wait) });
+            }
+        }
+        // The inner service was readied by `poll_ready`; keep that instance.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            #[cfg(feature = "dynamodb")]
+            if let Some((keys, caller)) = &keys {
+                if let Err(wait) =
+                    crate::rate_limit::shared_window_allows(caller, keys.per_minute()).await
+                {
+                    warn!(api_key = %caller, "Shared API key rate limit exceeded");
+                    return rate_limited(wait);
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
@@ -0,0 +1,195 @@
+//! Typed request and response bodies of the send endpoints. Requests reject
+//! unknown fields, and a field of the wrong type is reported by name (see
+//! [`from_json`]) instead of surfacing as a missing value further down.
+
+use crate::http_handler::ApiError;
+use crate::templates::LocalizedText;
+use expo_push_notification_client::Priority;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// The body of `POST /send`, and of each message of `POST /bundles`.
+/// Recipients are either an audience selector or `expo_push_token(s)`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Stored template whose title and body are used when `title` or `body`
+    /// is left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template_id: Option<String>,
+    /// Translations of `title` and `body` by `users.locale`, e.g.
+    /// `{"en": {"title": ..., "body": ...}}`; they take precedence over the
+    /// template's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub localized: Option<HashMap<String, LocalizedText>>,
+    /// Values for the `{{name}}` placeholders in `title` and `body`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expo_push_token: Option<String>,
+    /// Kept as raw values so invalid entries can be reported by position
+    /// rather than failing the whole body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expo_push_tokens: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_rpc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_rpc_args: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_snapshot: Option<String>,
+    /// Users whose registered devices all get the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_ids: Option<Vec<String>>,
+    /// Sends the chunks in slots spread evenly over this many minutes
+    /// instead of at once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spread_over_minutes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collapse_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// `default` or one of the custom sounds in `allowed-sounds`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub badge: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// Seconds Expo keeps retrying delivery to an offline device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    /// ISO-8601 time to send at instead of now; `POST /send` only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<String>,
+    /// Answer with the audience size and a rendered sample message instead
+    /// of sending, as `?dry_run=1` does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+}
+
+impl SendRequest {
+    pub fn audience(&self) -> AudienceSelector {
+        AudienceSelector {
+            audience: self.audience.clone(),
+            audience_rpc: self.audience_rpc.clone(),
+            audience_rpc_args: self.audience_rpc_args.clone(),
+            audience_snapshot: self.audience_snapshot.clone(),
+            user_ids: self.user_ids.clone(),
+        }
+    }
+}
+
+/// Which stored audience a request targets, as accepted by the audience
+/// estimate and snapshot endpoints. All `None` means no selector.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudienceSelector {
+    /// A named audience (segment).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// A Postgres function returning the recipients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_rpc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_rpc_args: Option<Value>,
+    /// A frozen audience from `POST /audience/snapshots`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience_snapshot: Option<String>,
+    /// `users.user_id`s, resolved to every token each user registered. With
+    /// `audience_rpc`, passed to the function to narrow its audience.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketStatus {
+    Ok,
+    Error,
+}
+
+/// One recipient's outcome: a ticket id, or why Expo gave none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TicketResult {
+    pub token: String,
+    pub status: TicketStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The body of a successful send. Other 2xx outcomes (an empty audience, a
+/// broadcast cut short by the deadline) carry only some of the counts,
+/// which then default to zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendResponse {
+    pub message: String,
+    #[serde(default)]
+    pub job_id: Option<String>,
+    #[serde(default)]
+    pub history_id: Option<String>,
+    #[serde(default)]
+    pub skipped_chunks: usize,
+    #[serde(default)]
+    pub chunks: usize,
+    #[serde(default)]
+    pub sent: usize,
+    #[serde(default)]
+    pub failed: usize,
+    #[serde(default)]
+    pub retried: usize,
+    #[serde(default)]
+    pub results: Vec<TicketResult>,
+}
+
+/// The field named by a `deny_unknown_fields` rejection. serde only reports
+/// it in the message: "unknown field `titel`, expected one of ...".
+pub fn unknown_field(error: &serde_json::Error) -> Option<String> {
+    let message = error.to_string();
+    let rest = message.strip_prefix("unknown field `")?;
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+/// Deserializes a request body, naming the offending field on failure:
+/// unknown fields become [`ApiError::UnknownFields`], anything else a
+/// `400` such as `ttl: invalid type: string "1h", expected u64`. `prefix`
+/// names where the object sits, e.g. `messages[0].`.
+pub fn from_json<T: DeserializeOwned>(body: &Value, prefix: &str) -> Result<T, ApiError> {
+    // serde would otherwise read an array positionally into the fields.
+    if !body.is_object() {
+        let name = prefix.strip_suffix('.').unwrap_or("body");
+        return Err(ApiError::BadRequest(format!(
+            "{name} must be a JSON object"
+        )));
+    }
+    serde_path_to_error::deserialize(body).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        if let Some(field) = unknown_field(&inner) {
+            let parent = path
+                .rsplit_once('.')
+                .map(|(parent, _)| format!("{parent}."))
+                .unwrap_or_default();
+            return ApiError::UnknownFields {
+                fields: vec![format!("{prefix}{parent}{field}")],
+            };
+        }
+        match path.as_str() {
+            "." => ApiError::BadRequest(format!("{prefix}{inner}")),
+            _ => ApiError::BadRequest(format!("{prefix}{path}: {inner}")),
+        }
+    })
+}
@@ -0,0 +1,117 @@
+//! What was sent and when, for the admin dashboard: every send adds a row
+//! to the `notification_log` table, listed newest first by
+//! `GET /notifications`. Unlike `notification_history`, which keeps the
+//! recipients for resends, a row holds only a summary.
+
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use crate::privacy::PrivacyLevel;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+/// One send, as [`record_notification`] stores it.
+#[derive(Debug)]
+pub struct LogEntry {
+    pub title: String,
+    pub body: String,
+    pub category: Option<String>,
+    pub job_id: Option<String>,
+    pub history_id: Option<String>,
+    pub audience_size: usize,
+    pub accepted: usize,
+    pub failed: usize,
+    pub retried: usize,
+    /// The [`crate::api_keys::ApiKey::name`] of the caller.
+    pub caller: Option<String>,
+}
+
+/// Stores `entry`. The title and body are kept only under
+/// `LOG_PRIVACY_LEVEL=full`, like the content itself.
+#[instrument(skip(client, entry), fields(audience_size = entry.audience_size))]
+pub async fn record_notification(
+    client: &SupabaseClient,
+    entry: &LogEntry,
+) -> Result<(), ApiError> {
+    let full = PrivacyLevel::from_env() == PrivacyLevel::Full;
+    client
+        .insert(
+            "notification_log",
+            json!({
+                "id": Uuid::new_v4().to_string(),
+                "title": full.then_some(&entry.title),
+                "body": full.then_some(&entry.body),
+                "category": entry.category,
+                "job_id": entry.job_id,
+                "history_id": entry.history_id,
+                "audience_size": entry.audience_size,
+                "ticket_summary": {
+                    "accepted": entry.accepted,
+                    "failed": entry.failed,
+                    "retried": entry.retried,
+                },
+                "caller_key": entry.caller,
+                "created_at": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            }),
+        )
+        .timed("insert notification_log")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error recording notification log");
+            ApiError::SupabaseWrite
+        })?;
+    info!("Recorded notification log");
+    Ok(())
+}
+
+fn invalid_cursor() -> ApiError {
+    ApiError::BadRequest("Invalid cursor".into())
+}
+
+/// Up to `limit` logged sends older than `cursor`, newest first, and the
+/// cursor of the next page (`null` on the last one). Cursors are opaque to
+/// callers; they encode the `created_at` of the last row returned.
+#[instrument(skip(client))]
+pub async fn list_notifications(
+    client: &SupabaseClient,
+    limit: usize,
+    cursor: Option<&str>,
+) -> Result<Value, ApiError> {
+    let mut select = client.select("notification_log");
+    if let Some(cursor) = cursor {
+        let created_at = BASE64_URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(invalid_cursor)?;
+        select = select.lt("created_at", &created_at);
+    }
+    let rows = select
+        .order("created_at", false)
+        .limit(limit)
+        .execute()
+        .timed("select notification_log")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching notification log");
+            ApiError::SupabaseFetch
+        })?;
+    let next_cursor = rows
+        .last()
+        .filter(|_| rows.len() == limit)
+        .and_then(|row| DateTime::parse_from_rfc3339(row["created_at"].as_str()?).ok())
+        // In UTC with a `Z`, since a `+` is not escaped in the filter.
+        .map(|created_at| {
+            let created_at = created_at
+                .with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Micros, true);
+            BASE64_URL_SAFE_NO_PAD.encode(created_at)
+        });
+    Ok(json!({ "items": rows, "next_cursor": next_cursor }))
+}
@@ -0,0 +1,147 @@
+use crate::http_client::aws_sdk_config;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+const OUTBOX_PREFIX: &str = "outbox/";
+
+/// A broadcast request accepted while the token store was down, kept in
+/// `s3://$OUTBOX_BUCKET/outbox/` until it can be replayed. S3 rather than
+/// Supabase, since Supabase is exactly what is unavailable.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// Route the request was sent to, `/` or `/scheduled`.
+    pub route: String,
+    pub body: Value,
+    pub queued_at: String,
+}
+
+async fn s3() -> S3Client {
+    let config = aws_sdk_config().await;
+    S3Client::new(config)
+}
+
+/// Stores the request and returns its outbox id, or `None` when
+/// `OUTBOX_BUCKET` is unset or the write failed.
+#[instrument(skip(body))]
+pub async fn enqueue(route: &str, body: &Value) -> Option<String> {
+    let bucket = env::var("OUTBOX_BUCKET").ok()?;
+    let id = Uuid::new_v4().to_string();
+    let entry = OutboxEntry {
+        route: route.to_string(),
+        body: body.clone(),
+        queued_at: Utc::now().to_rfc3339(),
+    };
+    let bytes = serde_json::to_vec(&entry).ok()?;
+
+    match s3()
+        .await
+        .put_object()
+        .bucket(&bucket)
+        .key(format!("{OUTBOX_PREFIX}{id}.json"))
+        .content_type("application/json")
+        .body(ByteStream::from(bytes))
+        .send()
+        .await
+    {
+        Ok(_) => {
+            info!(outbox_id = %id, "Queued request in outbox");
+            Some(id)
+        }
+        Err(e) => {
+            error!(error = ?e, "Failed to queue request in outbox");
+            None
+        }
+    }
+}
+
+/// Queued entries, oldest first, keyed by their S3 object key. Entries that
+/// cannot be read are skipped and left in place.
+#[instrument]
+pub async fn pending_entries() -> Vec<(String, OutboxEntry)> {
+    let Ok(bucket) = env::var("OUTBOX_BUCKET") else {
+        return vec![];
+    };
+    let client = s3().await;
+    let listing = match client
+        .list_objects_v2()
+        .bucket(&bucket)
+        .prefix(OUTBOX_PREFIX)
+        .send()
+        .await
+    {
+        Ok(listing) => listing,
+        Err(e) => {
+            error!(error = ?e, "Failed to list outbox");
+            return vec![];
+        }
+    };
+
+    let mut objects = listing.contents().to_vec();
+    objects.sort_by_key(|object| object.last_modified().copied());
+
+    let mut entries = vec![];
+    for key in objects.iter().filter_map(|object| object.key()) {
+        let object = match client.get_object().bucket(&bucket).key(key).send().await {
+            Ok(object) => object,
+            Err(e) => {
+                warn!(error = ?e, key, "Failed to read outbox entry");
+                continue;
+            }
+        };
+        let entry = match object.body.collect().await {
+            Ok(bytes) => serde_json::from_slice::<OutboxEntry>(&bytes.into_bytes()),
+            Err(e) => {
+                warn!(error = ?e, key, "Failed to read outbox entry");
+                continue;
+            }
+        };
+        match entry {
+            Ok(entry) => entries.push((key.to_string(), entry)),
+            Err(e) => warn!(error = %e, key, "Skipping malformed outbox entry"),
+        }
+    }
+    entries
+}
+
+/// Number of queued entries, or `None` when `OUTBOX_BUCKET` is unset or the
+/// bucket cannot be listed. Counts at most 1000, one listing page.
+#[instrument]
+pub async fn depth() -> Option<usize> {
+    let bucket = env::var("OUTBOX_BUCKET").ok()?;
+    match s3()
+        .await
+        .list_objects_v2()
+        .bucket(&bucket)
+        .prefix(OUTBOX_PREFIX)
+        .send()
+        .await
+    {
+        Ok(listing) => Some(listing.key_count().unwrap_or_default() as usize),
+        Err(e) => {
+            error!(error = ?e, "Failed to list outbox");
+            None
+        }
+    }
+}
+
+pub async fn remove(key: &str) {
+    let Ok(bucket) = env::var("OUTBOX_BUCKET") else {
+        return;
+    };
+    if let Err(e) = s3()
+        .await
+        .delete_object()
+        .bucket(&bucket)
+        .key(key)
+        .send()
+        .await
+    {
+        warn!(error = ?e, key, "Failed to remove replayed outbox entry");
+    }
+}
@@ -0,0 +1,154 @@
+//! What each user has agreed to receive: `users.notifications_enabled`
+//! turns everything off, and `users.preferences` maps a category to
+//! `false` to turn just that category off (see [`crate::unsubscribe`]).
+//! Missing values mean opted in. The app changes both with
+//! `PUT /preferences`.
+
+use crate::http_handler::{validate_category, ApiError};
+use crate::metrics::Timed;
+use crate::templates::select_recipient_rows;
+use crate::unsubscribe::is_marketing_category;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+/// Body of `PUT /preferences`. Categories left out keep their setting.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreferencesRequest {
+    pub expo_push_token: String,
+    pub notifications_enabled: Option<bool>,
+    #[serde(default)]
+    pub categories: HashMap<String, bool>,
+}
+
+/// `false` only for a row that turned notifications off.
+pub fn notifications_enabled(row: &Value) -> bool {
+    row["notifications_enabled"].as_bool() != Some(false)
+}
+
+fn row_id(row: &Value) -> Option<String> {
+    match &row["id"] {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Applies `request` to every row holding its token and returns the
+/// resulting settings.
+#[instrument(skip(client, request))]
+pub async fn update_preferences(
+    client: &SupabaseClient,
+    request: PreferencesRequest,
+) -> Result<Value, ApiError> {
+    for category in request.categories.keys() {
+        validate_category(Some(category))?;
+    }
+    let rows = client
+        .select("users")
+        .eq("expo_push_token", &request.expo_push_token)
+        .execute()
+        .timed("select users")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching users to update preferences");
+            ApiError::SupabaseFetch
+        })?;
+    if rows.is_empty() {
+        return Err(ApiError::NotFound("Token is not registered".into()));
+    }
+
+    let mut settings = json!({});
+    for row in &rows {
+        let Some(id) = row_id(row) else {
+            continue;
+        };
+        let mut preferences = row["preferences"]
+            .as_object()
+            .cloned()
+            .unwrap_or_else(Map::new);
+        for (category, enabled) in &request.categories {
+            preferences.insert(category.clone(), json!(enabled));
+        }
+        let enabled = request
+            .notifications_enabled
+            .unwrap_or_else(|| notifications_enabled(row));
+        settings = json!({ "notifications_enabled": enabled, "preferences": preferences });
+        client
+            .update("users", &id, settings.clone())
+            .timed("update users")
+            .await
+            .map_err(|e| {
+                error!(error = %e, row_id = %id, "Error updating preferences");
+                ApiError::SupabaseWrite
+            })?;
+    }
+    info!(updated = rows.len(), "Updated notification preferences");
+    Ok(settings)
+}
+
+/// Which of `tokens` must not get a notification of `category`: those with
+/// notifications off, and with a category those that turned it off. Only
+/// the recipients' rows are read.
+async fn opted_out_tokens(
+    client: &SupabaseClient,
+    tokens: &[String],
+    category: Option<&str>,
+) -> Result<HashSet<String>, ApiError> {
+    let rows = select_recipient_rows(
+        client,
+        tokens,
+        vec!["expo_push_token", "notifications_enabled", "preferences"],
+    )
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Error fetching notification preferences");
+        ApiError::SupabaseFetch
+    })?;
+    Ok(rows
+        .iter()
+        .filter(|row| {
+            !notifications_enabled(row)
+                || category.is_some_and(|category| row["preferences"][category] == json!(false))
+        })
+        .filter_map(|row| row["expo_push_token"].as_str().map(str::to_string))
+        .collect())
+}
+
+/// Drops the tokens [`opted_out_tokens`] names from `tokens`, whatever
+/// they were resolved from, and returns how many were dropped. If the
+/// preferences cannot be read, a marketing send (see
+/// [`is_marketing_category`]) fails rather than reach users who opted out;
+/// any other send goes ahead, as explicit tokens do during a Supabase
+/// outage.
+pub async fn exclude_opted_out(
+    client: Result<SupabaseClient, ApiError>,
+    tokens: &mut Vec<String>,
+    category: Option<&str>,
+) -> Result<usize, ApiError> {
+    let opted_out = match client {
+        Ok(client) => opted_out_tokens(&client, tokens, category).await,
+        Err(e) => Err(e),
+    };
+    let opted_out = match opted_out {
+        Ok(opted_out) => opted_out,
+        Err(e) if category.is_some_and(is_marketing_category) => {
+            error!(error = %e, "Could not read notification preferences; not sending marketing");
+            return Err(e.store_unavailable());
+        }
+        Err(e) => {
+            warn!(error = %e, "Could not read notification preferences; sending to all");
+            return Ok(0);
+        }
+    };
+    let before = tokens.len();
+    tokens.retain(|token| !opted_out.contains(token));
+    let excluded = before - tokens.len();
+    if excluded > 0 {
+        info!(excluded, "Excluded opted-out tokens");
+    }
+    Ok(excluded)
+}
@@ -0,0 +1,52 @@
+use serde_json::{json, Map, Value};
+use std::env;
+
+/// How much notification content (titles, bodies, data payloads) may appear
+/// in logs and the event history. Set with `LOG_PRIVACY_LEVEL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivacyLevel {
+    /// Content is logged verbatim; meant for dev environments.
+    Full,
+    /// Only lengths and payload keys are logged. The default.
+    MetadataOnly,
+    /// Content is left out entirely.
+    None,
+}
+
+impl PrivacyLevel {
+    pub fn from_env() -> Self {
+        match env::var("LOG_PRIVACY_LEVEL").as_deref() {
+            Ok("full") => PrivacyLevel::Full,
+            Ok("none") => PrivacyLevel::None,
+            _ => PrivacyLevel::MetadataOnly,
+        }
+    }
+
+    /// What may be logged of `content`, an object of content fields such as
+    /// `{"title": ..., "body": ..., "data": {...}}`. `None` when nothing may.
+    pub fn redact(self, content: &Value) -> Option<Value> {
+        match self {
+            PrivacyLevel::Full => Some(content.clone()),
+            PrivacyLevel::None => None,
+            PrivacyLevel::MetadataOnly => {
+                let fields = content.as_object()?;
+                Some(Value::Object(
+                    fields
+                        .iter()
+                        .map(|(key, value)| (key.clone(), metadata(value)))
+                        .collect::<Map<_, _>>(),
+                ))
+            }
+        }
+    }
+}
+
+fn metadata(value: &Value) -> Value {
+    match value {
+        Value::String(s) => json!({ "length": s.chars().count() }),
+        Value::Object(fields) => json!({ "keys": fields.keys().collect::<Vec<_>>() }),
+        Value::Array(items) => json!({ "items": items.len() }),
+        Value::Null => Value::Null,
+        _ => json!({ "present": true }),
+    }
+}
@@ -0,0 +1,201 @@
+//! Request limits per API key, on top of the container-wide limit in
+//! [`crate::middleware::RateLimitLayer`]. Each key gets its own token
+//! bucket in every warm container (`API_KEY_RATE_LIMIT_PER_SECOND`,
+//! `API_KEY_RATE_LIMIT_BURST`), and so does each source IP, before its API
+//! key is even checked (`SOURCE_IP_RATE_LIMIT_PER_SECOND`,
+//! `SOURCE_IP_RATE_LIMIT_BURST`). Since Lambda runs many containers, builds
+//! with the `dynamodb` feature can also count requests per key and minute
+//! in the DynamoDB table `RATE_LIMIT_TABLE`, which every container shares.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Refills continuously at `refill_per_second` up to `capacity`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(refill_per_second: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Whether the bucket has refilled completely, i.e. forgetting it
+    /// changes nothing.
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    pub fn try_acquire(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.refill_per_second,
+            ))
+        }
+    }
+}
+
+/// A positive rate from `rate_var` and the burst from `burst_var`, which
+/// defaults to the rate. `None` when the rate is unset.
+pub fn rate_from_env(rate_var: &str, burst_var: &str) -> Option<(f64, f64)> {
+    let rate = env::var(rate_var)
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok())
+        .filter(|rate| *rate > 0.0)?;
+    let burst = env::var(burst_var)
+        .ok()
+        .and_then(|burst| burst.parse::<f64>().ok())
+        .unwrap_or(rate)
+        .max(1.0);
+    Some((rate, burst))
+}
+
+/// Buckets kept before full ones are forgotten, so a container hit from
+/// many addresses does not grow without bound.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// One [`TokenBucket`] per [`crate::api_keys::ApiKey::name`], or per source
+/// IP.
+#[derive(Debug)]
+pub struct KeyRateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl KeyRateLimiter {
+    /// From `API_KEY_RATE_LIMIT_PER_SECOND` and `API_KEY_RATE_LIMIT_BURST`;
+    /// `None` when the rate is unset.
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars("API_KEY_RATE_LIMIT_PER_SECOND", "API_KEY_RATE_LIMIT_BURST")
+    }
+
+    /// From `SOURCE_IP_RATE_LIMIT_PER_SECOND` and
+    /// `SOURCE_IP_RATE_LIMIT_BURST`; `None` when the rate is unset.
+    pub fn per_source_ip_from_env() -> Option<Self> {
+        Self::from_vars(
+            "SOURCE_IP_RATE_LIMIT_PER_SECOND",
+            "SOURCE_IP_RATE_LIMIT_BURST",
+        )
+    }
+
+    fn from_vars(rate_var: &str, burst_var: &str) -> Option<Self> {
+        let (rate, burst) = rate_from_env(rate_var, burst_var)?;
+        Some(Self {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn try_acquire(&self, key_name: &str) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("rate limiter poisoned");
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+        buckets
+            .entry(key_name.to_string())
+            .or_insert_with(|| TokenBucket::new(self.rate, self.burst))
+            .try_acquire()
+    }
+
+    /// Requests a key may make per minute across all containers: a
+    /// minute's refill plus the burst.
+    pub fn per_minute(&self) -> u64 {
+        (self.rate * 60.0 + self.burst).ceil() as u64
+    }
+}
+
+/// The `Retry-After` value for `wait`, in whole seconds and at least 1.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(feature = "dynamodb")]
+pub use shared::shared_window_allows;
+
+#[cfg(feature = "dynamodb")]
+mod shared {
+    use crate::http_client::aws_sdk_config;
+    use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+    use aws_sdk_dynamodb::Client as DynamoDbClient;
+    use std::env;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::sync::OnceCell;
+    use tracing::warn;
+
+    static CLIENT: OnceCell<DynamoDbClient> = OnceCell::const_new();
+
+    /// Counts a request by `key_name` in the current minute of
+    /// `RATE_LIMIT_TABLE` (partition key `id`, a string; enable TTL on
+    /// `expires_at`). `Err` with the wait until the next minute once
+    /// `limit` is exceeded. Without the table, or if DynamoDB fails, the
+    /// request is allowed.
+    pub async fn shared_window_allows(key_name: &str, limit: u64) -> Result<(), Duration> {
+        let Ok(table) = env::var("RATE_LIMIT_TABLE") else {
+            return Ok(());
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let minute = now / 60;
+        let client = CLIENT
+            .get_or_init(|| async { DynamoDbClient::new(aws_sdk_config().await) })
+            .await;
+        let updated = client
+            .update_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(format!("{key_name}#{minute}")))
+            .update_expression("ADD request_count :one SET expires_at = :expires_at")
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(
+                ":expires_at",
+                AttributeValue::N(((minute + 2) * 60).to_string()),
+            )
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await;
+        let count = match updated {
+            Ok(output) => output
+                .attributes()
+                .and_then(|attributes| attributes.get("request_count"))
+                .and_then(|count| count.as_n().ok())
+                .and_then(|count| count.parse::<u64>().ok())
+                .unwrap_or_default(),
+            Err(e) => {
+                warn!(error = ?e, "Shared rate limit unavailable; allowing request");
+                return Ok(());
+            }
+        };
+        if count > limit {
+            return Err(Duration::from_secs((minute + 1) * 60 - now));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use crate::sla::{evaluate_sla, sla_reports, SlaReport};
+use crate::trash::prune_unregistered_tokens;
+use chrono::{DateTime, Utc};
+use expo_push_notification_client::{DetailsErrorType, Expo, ExpoPushReceipt, ExpoPushReceiptId};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+/// Expo accepts at most 1000 receipt ids per request.
+const RECEIPT_BATCH_SIZE: usize = 1000;
+const UNCATEGORIZED: &str = "uncategorized";
+
+/// Stores the tickets Expo returned for one broadcast in `push_tickets`, so
+/// their receipts can be checked once Expo has handed them to APNs / FCM.
+/// `tenant` gets the webhook events of their receipts.
+#[instrument(skip(client, tickets), fields(ticket_count = tickets.len()))]
+pub async fn record_tickets(
+    client: &SupabaseClient,
+    tickets: &[(String, String)],
+    category: Option<&str>,
+    job_id: Option<&str>,
+    tenant: Option<&str>,
+    accepted_at: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    if tickets.is_empty() {
+        return Ok(());
+    }
+    let rows = tickets
+        .iter()
+        .map(|(token, ticket_id)| {
+            json!({
+                "id": ticket_id,
+                "expo_push_token": token,
+                "category": category,
+                "job_id": job_id,
+                "tenant": tenant,
+                "accepted_at": accepted_at.to_rfc3339(),
+                "status": "pending",
+            })
+        })
+        .collect::<Vec<_>>();
+    client
+        .bulk_insert("push_tickets", rows)
+        .timed("insert push_tickets")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error storing push tickets");
+            ApiError::SupabaseWrite
+        })
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReceiptSummary {
+    pub checked: usize,
+    pub resolved: usize,
+    pub still_pending: usize,
+    /// Resolved error receipts by Expo error type (`DeviceNotRegistered`,
+    /// `MessageRateExceeded`, ...).
+    pub errors: BTreeMap<String, usize>,
+    /// Tokens Expo reported as no longer registered to a device.
+    pub device_not_registered: Vec<String>,
+    /// How many of those were soft-deleted from `users`.
+    pub pruned: usize,
+    /// Quarantined tokens moved back to active because the receipt of their
+    /// revalidation push was `ok`.
+    pub promoted: usize,
+    /// Per category, for the tickets resolved by this check.
+    pub sla: BTreeMap<String, SlaReport>,
+    /// The same, for the tickets of each tenant that sent them, so each
+    /// tenant's webhooks only hear about its own tokens. Tickets stored
+    /// without a tenant are only counted above.
+    #[serde(skip)]
+    pub tenants: BTreeMap<String, ReceiptSummary>,
+}
+
+/// Resolves the receipts of up to 1000 pending tickets, oldest first, and
+/// evaluates the delivery SLA on the time each took from acceptance to
+/// resolution, overall and per tenant. Quarantined tokens whose
+/// revalidation ticket gets an `ok` receipt are moved back to active.
+#[instrument(skip(expo, client))]
+pub async fn check_receipts(
+    expo: &Expo,
+    client: &SupabaseClient,
+) -> Result<ReceiptSummary, ApiError> {
+    let rows = client
+        .select("push_tickets")
+        .eq("status", "pending")
+        .order("accepted_at", true)
+        .limit(RECEIPT_BATCH_SIZE)
+        .execute()
+        .timed("select push_tickets")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching pending push tickets");
+            ApiError::SupabaseFetch
+        })?;
+    let ids = rows
+        .iter()
+        .filter_map(|row| row["id"].as_str())
+        .collect::<Vec<_>>();
+    let mut summary = ReceiptSummary {
+        checked: ids.len(),
+        ..Default::default()
+    };
+    if ids.is_empty() {
+        return Ok(summary);
+    }
+    let mut tenants: BTreeMap<String, ReceiptSummary> = BTreeMap::new();
+    for tenant in rows.iter().filter_map(tenant_of) {
+        tenants.entry(tenant.to_string()).or_default().checked += 1;
+    }
+
+    let receipts = expo
+        .get_push_notification_receipts(ids.iter().copied())
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching push receipts from Expo");
+            ApiError::ExpoRequest
+        })?;
+
+    let resolved_at = Utc::now();
+    let mut latencies: HashMap<String, Vec<f64>> = HashMap::new();
+    let mut tenant_latencies: HashMap<String, HashMap<String, Vec<f64>>> = HashMap::new();
+    let mut unowned_unregistered = vec![];
+    let mut revalidated = vec![];
+    let mut updates = vec![];
+    let mut deliveries = HashMap::new();
+    for row in &rows {
+        let Some(id) = row["id"].as_str() else {
+            continue;
+        };
+        let Some(receipt) = ExpoPushReceiptId::try_from(id)
+            .ok()
+            .and_then(|receipt_id| receipts.get(&receipt_id))
+        else {
+            // Expo has not processed this one yet.
+            continue;
+        };
+        let tenant = tenant_of(row);
+        let mut tenant_summary = tenant.and_then(|tenant| tenants.get_mut(tenant));
+        let fields = match receipt {
+            ExpoPushReceipt::Ok => json!({ "status": "ok" }),
+            ExpoPushReceipt::Error(receipt) => {
+                let error_type = receipt
+                    .details
+                    .as_ref()
+                    .and_then(|details| details.error.clone());
+                let error_name = error_type
+                    .as_ref()
+                    .and_then(|error_type| json!(error_type).as_str().map(str::to_string))
+                    .unwrap_or_else(|| "Unknown".to_string());
+                if let Some(tenant_summary) = tenant_summary.as_deref_mut() {
+                    *tenant_summary.errors.entry(error_name.clone()).or_default() += 1;
+                }
+                *summary.errors.entry(error_name).or_default() += 1;
+                if error_type == Some(DetailsErrorType::DeviceNotRegistered) {
+                    if let Some(token) = row["expo_push_token"].as_str() {
+                        summary.device_not_registered.push(token.to_string());
+                        match tenant_summary {
+                            Some(tenant_summary) => {
+                                tenant_summary.device_not_registered.push(token.to_string())
+                            }
+                            None => unowned_unregistered.push(token.to_string()),
+                        }
+                    }
+                }
+                json!({
+                    "status": "error",
+                    "error": receipt.message,
+                    "error_type": error_type,
+                })
+            }
+        };
+        if let Some(token) = row["expo_push_token"].as_str() {
+            // Tickets are oldest first, so a token's latest receipt wins.
+            deliveries.insert(token.to_string(), fields.clone());
+        }
+        // Revalidation pushes are not sends, so they stay out of the SLA.
+        if is_revalidation(row) {
+            if let (ExpoPushReceipt::Ok, Some(token)) = (receipt, row["expo_push_token"].as_str()) {
+                revalidated.push(token.to_string());
+            }
+        } else if let Some(accepted_at) = row["accepted_at"]
+            .as_str()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        {
+            let latency = resolved_at.signed_duration_since(accepted_at);
+            let latency = latency.num_milliseconds() as f64 / 1000.0;
+            latencies.entry(category_of(row)).or_default().push(latency);
+            if let Some(tenant) = tenant {
+                tenant_latencies
+                    .entry(tenant.to_string())
+                    .or_default()
+                    .entry(category_of(row))
+                    .or_default()
+                    .push(latency);
+            }
+        }
+        updates.push((id, tenant, fields));
+    }
+
+    let results = join_all(updates.iter().map(|(id, _, fields)| {
+        let mut fields = fields.clone();
+        fields["resolved_at"] = json!(resolved_at.to_rfc3339());
+        client
+            .update("push_tickets", id, fields)
+            .timed("update push_tickets")
+    }))
+    .await;
+    for ((_, tenant, _), result) in updates.iter().zip(results) {
+        match result {
+            Ok(_) => {
+                summary.resolved += 1;
+                if let Some(tenant_summary) = tenant.and_then(|tenant| tenants.get_mut(tenant)) {
+                    tenant_summary.resolved += 1;
+                }
+            }
+            Err(e) => warn!(error = %e, "Failed to record push receipt"),
+        }
+    }
+    summary.still_pending = summary.checked - summary.resolved;
+    record_deliveries(client, &deliveries, resolved_at).await;
+    summary.promoted = promote_revalidated_tokens(client, &revalidated).await;
+    summary.pruned = prune_unregistered_tokens(client, &unowned_unregistered).await;
+    for (tenant, tenant_summary) in tenants.iter_mut() {
+        tenant_summary.still_pending = tenant_summary.checked - tenant_summary.resolved;
+        tenant_summary.pruned =
+            prune_unregistered_tokens(client, &tenant_summary.device_not_registered).await;
+        summary.pruned += tenant_summary.pruned;
+        tenant_summary.sla = sla_reports(tenant_latencies.remove(tenant).unwrap_or_default());
+    }
+    summary.sla = evaluate_sla(latencies);
+    summary.tenants = tenants;
+
+    info!(
+        checked = summary.checked,
+        resolved = summary.resolved,
+        promoted = summary.promoted,
+        "Checked push receipts"
+    );
+    Ok(summary)
+}
+
+/// Stores each token's latest delivery outcome on its `users` row, so the
+/// admin tools can see which devices are failing without joining tickets.
+async fn record_deliveries(
+    client: &SupabaseClient,
+    deliveries: &HashMap<String, Value>,
+    resolved_at: DateTime<Utc>,
+) {
+    let results = join_all(deliveries.iter().map(|(token, fields)| {
+        client
+            .update_with_column_name(
+                "users",
+                "expo_push_token",
+                token,
+                json!({
+                    "last_delivery_status": fields["status"],
+                    "last_delivery_error": fields.get("error_type"),
+                    "last_delivery_at": resolved_at.to_rfc3339(),
+                }),
+            )
+            .timed("update users")
+    }))
+    .await;
+    for result in results {
+        if let Err(e) = result {
+            warn!(error = %e, "Failed to record delivery status on token");
+        }
+    }
+}
+
+/// Moves `tokens` out of quarantine, returning how many were updated.
+async fn promote_revalidated_tokens(client: &SupabaseClient, tokens: &[String]) -> usize {
+    let results = join_all(tokens.iter().map(|token| {
+        client
+            .update_with_column_name(
+                "users",
+                "expo_push_token",
+                token,
+                json!({ "quarantined": false }),
+            )
+            .timed("update users")
+    }))
+    .await;
+    results
+        .into_iter()
+        .filter(|result| match result {
+            Ok(_) => true,
+            Err(e) => {
+                warn!(error = %e, "Failed to promote revalidated token");
+                false
+            }
+        })
+        .count()
+}
+
+fn is_revalidation(row: &Value) -> bool {
+    row["revalidation"].as_bool().unwrap_or(false)
+}
+
+fn tenant_of(row: &Value) -> Option<&str> {
+    row["tenant"].as_str()
+}
+
+fn category_of(row: &Value) -> String {
+    row["category"]
+        .as_str()
+        .unwrap_or(UNCATEGORIZED)
+        .to_string()
+}
@@ -0,0 +1,116 @@
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use serde_json::Value;
+use tracing::warn;
+
+/// Response bodies the send endpoints can be serialized as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    /// One line such as `ok Push notifications sent successfully job_id=...`,
+    /// for shell scripts and cron jobs that only grep the result.
+    Text,
+}
+
+impl ResponseFormat {
+    /// `Text` only when `Accept` ranks `text/plain` above `application/json`;
+    /// JSON otherwise, including when `Accept` is missing or `*/*`.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(header) = headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return ResponseFormat::Json;
+        };
+        header
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let format = match parts.next()?.trim() {
+                    "text/plain" => ResponseFormat::Text,
+                    "application/json" => ResponseFormat::Json,
+                    _ => return None,
+                };
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            // Ties go to JSON, which carries everything.
+            .max_by(|a, b| {
+                a.1.total_cmp(&b.1)
+                    .then((a.0 == ResponseFormat::Json).cmp(&(b.0 == ResponseFormat::Json)))
+            })
+            .map(|(format, _)| format)
+            .unwrap_or(ResponseFormat::Json)
+    }
+}
+
+/// Renders a JSON response body as a single line: `ok` or `error`, the
+/// `message` (or `error`) text, then the remaining scalar fields as
+/// `key=value`. Nested objects and arrays are left out; clients that need
+/// them should ask for JSON.
+pub fn to_text_line(status: StatusCode, body: &Value) -> String {
+    let mut line = vec![if status.is_success() { "ok" } else { "error" }.to_string()];
+    let Some(fields) = body.as_object() else {
+        return line.remove(0);
+    };
+    if let Some(text) = fields
+        .get("message")
+        .filter(|_| status.is_success())
+        .or_else(|| fields.get("error"))
+        .and_then(Value::as_str)
+    {
+        line.push(text.replace('\n', " "));
+    }
+    for (key, value) in fields {
+        if matches!(key.as_str(), "message" | "error") {
+            continue;
+        }
+        let value = match value {
+            Value::String(s) => s.replace(char::is_whitespace, "_"),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => continue,
+        };
+        line.push(format!("{key}={value}"));
+    }
+    line.join(" ")
+}
+
+/// Route layer for the send endpoints: rewrites their JSON response as a
+/// [`to_text_line`] line when the client asked for `text/plain`. Status code
+/// and headers are kept, so `curl -f` still detects failures.
+///
+/// Use with [`axum::middleware::from_fn`].
+pub async fn shape_response(request: Request, next: Next) -> Response {
+    let format = ResponseFormat::from_headers(request.headers());
+    let response = next.run(request).await;
+    if format == ResponseFormat::Json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(error = %e, "Failed to buffer response for text rendering");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let Ok(json) = serde_json::from_slice::<Value>(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+
+    let mut line = to_text_line(parts.status, &json);
+    line.push('\n');
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Response::from_parts(parts, Body::from(line))
+}
@@ -0,0 +1,212 @@
+use crate::http_client::aws_sdk_config;
+use crate::http_handler::ApiError;
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use serde::Deserialize;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+const API_KEYS_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_ROTATION_GRACE_HOURS: u64 = 24;
+
+/// The event Secrets Manager sends to a rotation Lambda, once per step.
+#[derive(Debug, Deserialize)]
+pub struct RotationEvent {
+    #[serde(rename = "SecretId")]
+    pub secret_id: String,
+    #[serde(rename = "ClientRequestToken")]
+    pub client_request_token: String,
+    #[serde(rename = "Step")]
+    pub step: String,
+}
+
+pub async fn secrets_manager() -> SecretsManagerClient {
+    let config = aws_sdk_config().await;
+    SecretsManagerClient::new(config)
+}
+
+fn rotation_error(step: &str, e: impl std::fmt::Display) -> ApiError {
+    error!(error = %e, step, "Secret rotation step failed");
+    ApiError::SecretRotation(format!("{step} failed"))
+}
+
+/// Rotates the API key stored as the plain `SecretString` of `SecretId`,
+/// following the createSecret / setSecret / testSecret / finishSecret
+/// protocol. The key is only consumed by this function, so there is no
+/// downstream service to update in setSecret.
+#[instrument(skip(event), fields(step = %event.step))]
+pub async fn handle_rotation(event: RotationEvent) -> Result<(), ApiError> {
+    let client = secrets_manager().await;
+    let secret_id = event.secret_id.as_str();
+    let token = event.client_request_token.as_str();
+
+    let metadata = client
+        .describe_secret()
+        .secret_id(secret_id)
+        .send()
+        .await
+        .map_err(|e| rotation_error("describeSecret", e))?;
+    let versions = metadata
+        .version_ids_to_stages()
+        .cloned()
+        .unwrap_or_default();
+    let Some(stages) = versions.get(token) else {
+        return Err(ApiError::SecretRotation(format!(
+            "Version {token} is not a version of the secret"
+        )));
+    };
+    if stages.iter().any(|stage| stage == "AWSCURRENT") {
+        info!("Version is already AWSCURRENT, nothing to do");
+        return Ok(());
+    }
+
+    match event.step.as_str() {
+        "createSecret" => {
+            let pending = client
+                .get_secret_value()
+                .secret_id(secret_id)
+                .version_id(token)
+                .version_stage("AWSPENDING")
+                .send()
+                .await;
+            if pending.is_ok() {
+                info!("Pending version already exists");
+                return Ok(());
+            }
+            let new_key = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+            client
+                .put_secret_value()
+                .secret_id(secret_id)
+                .client_request_token(token)
+                .secret_string(new_key)
+                .version_stages("AWSPENDING")
+                .send()
+                .await
+                .map_err(|e| rotation_error("createSecret", e))?;
+            info!("Created pending API key");
+        }
+        "setSecret" => info!("API key is only used by this function, nothing to set"),
+        "testSecret" => {
+            let pending = client
+                .get_secret_value()
+                .secret_id(secret_id)
+                .version_id(token)
+                .version_stage("AWSPENDING")
+                .send()
+                .await
+                .map_err(|e| rotation_error("testSecret", e))?;
+            if pending.secret_string().is_none_or(str::is_empty) {
+                return Err(ApiError::SecretRotation("Pending API key is empty".into()));
+            }
+        }
+        "finishSecret" => {
+            let current = versions
+                .iter()
+                .find(|(_, stages)| stages.iter().any(|stage| stage == "AWSCURRENT"))
+                .map(|(version, _)| version.clone());
+            let mut request = client
+                .update_secret_version_stage()
+                .secret_id(secret_id)
+                .version_stage("AWSCURRENT")
+                .move_to_version_id(token);
+            if let Some(current) = current {
+                request = request.remove_from_version_id(current);
+            }
+            request
+                .send()
+                .await
+                .map_err(|e| rotation_error("finishSecret", e))?;
+            info!("Promoted pending API key to AWSCURRENT");
+        }
+        step => {
+            return Err(ApiError::SecretRotation(format!(
+                "Unknown rotation step {step}"
+            )))
+        }
+    }
+    Ok(())
+}
+
+static API_KEYS: Mutex<Option<(Instant, Arc<Vec<String>>)>> = Mutex::new(None);
+
+/// How long the outgoing key (`AWSPREVIOUS`) is still accepted after a
+/// rotation, from `API_KEY_ROTATION_GRACE_HOURS`; `0` drops it at once.
+/// Counted from when the current key was created, which is no later than
+/// when it was promoted.
+fn rotation_grace() -> Duration {
+    let hours = env::var("API_KEY_ROTATION_GRACE_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(DEFAULT_ROTATION_GRACE_HOURS);
+    Duration::from_secs(hours * 60 * 60)
+}
+
+async fn fetch_api_keys(secret_id: &str) -> Option<Vec<String>> {
+    let client = secrets_manager().await;
+    let fetch = |stage: &'static str| {
+        client
+            .get_secret_value()
+            .secret_id(secret_id)
+            .version_stage(stage)
+            .send()
+    };
+    let current = match fetch("AWSCURRENT").await {
+        Ok(current) => current,
+        Err(e) => {
+            error!(error = ?e, "Failed to fetch API key from Secrets Manager");
+            return None;
+        }
+    };
+    let mut keys = current
+        .secret_string()
+        .map(str::to_string)
+        .into_iter()
+        .collect::<Vec<_>>();
+    // During a rotation the incoming key is valid as well, and for a while
+    // after it the outgoing one, so clients can switch over.
+    let current_age = current.created_date().and_then(|created| {
+        let created = UNIX_EPOCH + Duration::from_secs(created.secs().max(0) as u64);
+        SystemTime::now().duration_since(created).ok()
+    });
+    let mut stages = vec!["AWSPENDING"];
+    if current_age.is_some_and(|age| age < rotation_grace()) {
+        stages.push("AWSPREVIOUS");
+    }
+    for stage in stages {
+        if let Ok(secret) = fetch(stage).await {
+            keys.extend(secret.secret_string().map(str::to_string));
+        }
+    }
+    Some(keys)
+}
+
+/// API keys managed by Secrets Manager when `API_KEY_SECRET_ID` is set,
+/// refreshed every minute. `None` means keys come from `API_KEY` instead.
+/// If the secret cannot be read and nothing is cached, no key is accepted.
+pub async fn rotating_api_keys() -> Option<Arc<Vec<String>>> {
+    let secret_id = env::var("API_KEY_SECRET_ID").ok()?;
+    let cached = API_KEYS.lock().expect("API key cache poisoned").clone();
+    if let Some((loaded_at, keys)) = &cached {
+        if loaded_at.elapsed() < API_KEYS_TTL {
+            return Some(keys.clone());
+        }
+    }
+
+    match fetch_api_keys(&secret_id).await {
+        Some(keys) => {
+            let keys = Arc::new(keys);
+            *API_KEYS.lock().expect("API key cache poisoned") =
+                Some((Instant::now(), keys.clone()));
+            Some(keys)
+        }
+        None => {
+            warn!(
+                cached = cached.is_some(),
+                "Falling back to previously loaded API keys"
+            );
+            Some(cached.map(|(_, keys)| keys).unwrap_or_default())
+        }
+    }
+}
@@ -0,0 +1,1879 @@
+//! The axum [`Router`] behind every endpoint, plus the glue that runs it on
+//! Lambda ([`LambdaRouter`]) and as a plain HTTP server for local development
+//! ([`serve_local`]).
+
+use crate::admin::{
+    delete_tokens_by_filter, merge_duplicate_tokens, snapshot_token_stats, token_stats,
+    token_stats_trend, MergeDuplicatesRequest, TokenDeleteFilter,
+};
+use crate::api_keys::{scope_caller, Caller};
+use crate::audience::{
+    audience_from_query_params, create_audience_snapshot, estimate_audience, requested_audience,
+    resolve_requested_audience, sample_audience, AudienceQuery, AudienceResolver,
+    SupabaseFilterResolver,
+};
+use crate::batches::{load_batch, set_batch_state, BatchState};
+use crate::build_info::build_info;
+use crate::bundles::{create_bundle, finish_bundle, load_bundle, MAX_BUNDLE_MESSAGES};
+use crate::cache::cache_response;
+use crate::config::dynamic_config;
+use crate::events::EventLog;
+use crate::features;
+use crate::health::check_health;
+use crate::history::{load_content, load_history, ResendRequest};
+use crate::http_handler::{
+    initialize_supabase_client, invalid_token_indices, is_near_deadline, problem,
+    reject_unknown_fields, send_broadcast, tenant_id, validate_body, validate_category,
+    validate_channel_id, validate_collapse_key, validate_data, validate_sound,
+    validate_spread_over_minutes, validate_title, ApiError, ApiResult, Broadcast, Violations,
+    PROBLEM_CONTENT_TYPE,
+};
+use crate::idempotency::idempotent_request;
+use crate::jobs::{abort_job, in_flight_jobs, load_job_status, CHUNK_SIZE};
+use crate::journal::{self, journal_request};
+use crate::maintenance::{cleanup_expired_records, revalidate_quarantined_tokens};
+use crate::models::{from_json, unknown_field, AudienceSelector, SendRequest};
+use crate::notification_log::{list_notifications, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::outbox;
+use crate::preferences::{update_preferences, PreferencesRequest};
+use crate::receipts::check_receipts;
+use crate::response_format::shape_response;
+use crate::rotation::{handle_rotation, RotationEvent};
+use crate::scheduling::{
+    cancel_scheduled, create_scheduled, due_scheduled, finish_scheduled, parse_send_at,
+};
+use crate::secrets::{get_secrets, missing_configuration};
+use crate::selftest::run_selftest;
+use crate::send_queue::{enqueue_async_request, QueuedSend};
+use crate::templates::{load_template, LocaleVariants, PlatformVariants};
+use crate::timings::Timings;
+use crate::token_store::token_store;
+use crate::tokens::{RegisterTokenRequest, RegistrationStatus};
+use crate::topics::{
+    subscribe, unsubscribe as unsubscribe_from_topic, validate_topic, TopicResolver,
+    TopicSubscriptionRequest,
+};
+use crate::trace_context::{self, TraceContext};
+use crate::trash::{list_deleted, restore, soft_delete, TrashKind};
+use crate::unsubscribe::{opt_out, verify_unsubscribe_token};
+use crate::webhooks::{self, dispatch_event};
+use aws_lambda_events::event::sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent};
+use axum::body::{to_bytes, Bytes};
+use axum::extract::{FromRequest, FromRequestParts, Path, Query, State};
+use axum::middleware::{from_fn, from_fn_with_state};
+use axum::response::IntoResponse;
+use axum::routing::{any, delete, get, post, put};
+use axum::{Json, Router};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use expo_push_notification_client::{Expo, ExpoClientOptions};
+use futures::future::BoxFuture;
+use http::header::CONTENT_TYPE;
+use http::request::Parts;
+use http::{StatusCode, Uri};
+use lambda_http::request::LambdaRequest;
+use lambda_http::{
+    lambda_runtime, service_fn, Adapter, Body, Error, LambdaEvent, Request, RequestExt, Response,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tower::{Service, ServiceExt};
+use tracing::{error, info, info_span, instrument, warn, Instrument, Span};
+
+const DEFAULT_EXPO_SEND_RATE: f64 = 600.0;
+
+/// Shared by every request a container serves.
+#[derive(Clone)]
+pub struct AppState {
+    pub secrets: Arc<HashMap<String, String>>,
+    pub expo: Expo,
+}
+
+impl AppState {
+    /// Loads the secrets and builds the clients. Fails with
+    /// [`ApiError::Misconfigured`], listing everything that is missing at
+    /// once, rather than at the first request that needs it.
+    pub async fn load() -> Result<Self, ApiError> {
+        let secrets = get_secrets().await?;
+        let missing = missing_configuration(&secrets);
+        if !missing.is_empty() {
+            error!(missing = ?missing, "Missing required configuration");
+            return Err(ApiError::Misconfigured { missing });
+        }
+        let expo_access_token = secrets
+            .get("expo-access-token")
+            .ok_or_else(|| ApiError::MissingSecret("expo-access-token".into()))?;
+        let expo = Expo::new(ExpoClientOptions {
+            access_token: Some(expo_access_token.clone()),
+        });
+        Ok(Self {
+            secrets: Arc::new(secrets),
+            expo,
+        })
+    }
+
+    /// State for offline mode, where send routes only write the journal:
+    /// no secrets are loaded, so anything that does reach Expo or Supabase
+    /// fails with [`ApiError::MissingSecret`].
+    pub fn offline() -> Self {
+        Self {
+            secrets: Arc::new(HashMap::new()),
+            expo: Expo::new(ExpoClientOptions { access_token: None }),
+        }
+    }
+}
+
+/// A JSON request body. Unlike [`Json`] it does not require a
+/// `Content-Type` header, accepts a base64-encoded body (see
+/// [`decode_body`]), and rejects with [`ApiError::InvalidBody`].
+pub struct JsonBody<T>(pub T);
+
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: axum::extract::Request, state: &S) -> Result<Self, ApiError> {
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(|_| ApiError::InvalidBody)?;
+        serde_json::from_slice(&decode_body(&bytes))
+            .map(JsonBody)
+            .map_err(|e| match unknown_field(&e) {
+                Some(field) => ApiError::UnknownFields {
+                    fields: vec![field],
+                },
+                None => ApiError::InvalidBody,
+            })
+    }
+}
+
+/// The JSON in a request body that may still be base64 encoded. API Gateway
+/// encodes bodies whose content type is configured as a binary media type;
+/// lambda_http decodes them only when the event sets `isBase64Encoded`, which
+/// some integrations leave out. A body that does not start like a JSON
+/// object or array but decodes to one is taken as encoded; anything else is
+/// passed through for the JSON parser to accept or reject.
+pub fn decode_body(bytes: &[u8]) -> Cow<'_, [u8]> {
+    let trimmed = bytes.trim_ascii();
+    if trimmed.is_empty() || matches!(trimmed[0], b'{' | b'[') {
+        return Cow::Borrowed(bytes);
+    }
+    match BASE64_STANDARD.decode(trimmed) {
+        Ok(decoded) if matches!(decoded.trim_ascii().first(), Some(b'{' | b'[')) => {
+            Cow::Owned(decoded)
+        }
+        _ => Cow::Borrowed(bytes),
+    }
+}
+
+/// The caller's tenant, see [`tenant_id`].
+pub struct Tenant(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        Ok(Tenant(tenant_id(&parts.extensions)))
+    }
+}
+
+/// When the Lambda invocation times out; `None` outside Lambda.
+pub struct Deadline(pub Option<SystemTime>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Deadline {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        Ok(Deadline(
+            parts.lambda_context_ref().map(|context| context.deadline()),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendQuery {
+    pub job_id: Option<String>,
+    /// Queue the request in the outbox instead of failing with 503 when the
+    /// token store is down.
+    #[serde(default)]
+    pub queue_if_unavailable: bool,
+    /// `?dry_run=1`: see [`SendRequest::dry_run`].
+    #[serde(default, deserialize_with = "query_flag")]
+    pub dry_run: bool,
+}
+
+/// A flag such as `?dry_run=1` or `?dry_run=true`.
+fn query_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(matches!(value.as_str(), "1" | "true"))
+}
+
+/// Whether `uri` asks for a dry run. The journal and the send queue let
+/// such requests through, since they send nothing.
+pub fn dry_run_requested(uri: &Uri) -> bool {
+    uri.query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|pair| matches!(pair, "dry_run=true" | "dry_run=1"))
+    })
+}
+
+/// Every endpoint, dispatched on path and method. `POST /send` (also served
+/// at `/` for existing callers) sends the message in the body, or with
+/// `send_at` stores it for `/scheduled/dispatch`; `GET` or `POST
+/// /broadcast` (also `/scheduled`, which the schedule invokes) sends the
+/// configured message to every active token, or to the users its query
+/// parameters filter down to.
+pub fn app(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/",
+            any(send)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route(
+            "/send",
+            post(send)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route(
+            "/broadcast",
+            get(scheduled)
+                .post(scheduled)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route(
+            "/send/batch",
+            post(send_batch)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route(
+            "/scheduled",
+            any(scheduled)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route(
+            "/topics/{topic}/send",
+            post(send_to_topic)
+                .layer(from_fn(enqueue_async_request))
+                .layer(from_fn_with_state(state.clone(), idempotent_request))
+                .layer(from_fn(journal_request))
+                .layer(from_fn(shape_response)),
+        )
+        .route("/topics/{topic}/subscribe", post(subscribe_topic))
+        .route("/topics/{topic}/unsubscribe", post(unsubscribe_topic))
+        .route("/scheduled/dispatch", any(dispatch_scheduled))
+        .route("/scheduled/{id}", delete(cancel))
+        .route("/bundles", post(send_bundle).layer(from_fn(shape_response)))
+        .route("/bundles/{id}", get(bundle_status))
+        .route("/tokens", post(tokens).delete(delete_token))
+        .route("/unsubscribe", post(unsubscribe))
+        .route("/preferences", put(preferences))
+        .route("/version", get(version))
+        .route("/health", get(health))
+        .route("/maintenance/revalidate-tokens", any(revalidate_tokens))
+        .route("/maintenance/cleanup", any(cleanup))
+        .route("/maintenance/drain-outbox", any(drain_outbox))
+        .route("/maintenance/check-receipts", any(receipts))
+        .route("/maintenance/retry-webhooks", any(retry_webhooks))
+        .route("/receipts", post(receipts))
+        .route(
+            "/maintenance/snapshot-token-stats",
+            any(snapshot_token_stats_job),
+        )
+        .route("/stats/tokens", get(token_stats_history))
+        .route("/notifications", get(notifications))
+        .route(
+            "/admin/sampled-broadcast",
+            post(sampled_broadcast).layer(from_fn(shape_response)),
+        )
+        .route("/admin/queue", get(queue_depth))
+        .route("/admin/selftest", post(selftest))
+        .route("/admin/tokens/delete", post(delete_tokens))
+        .route("/admin/tokens/merge-duplicates", post(merge_duplicates))
+        .route("/admin/templates/{id}", delete(delete_template))
+        .route("/admin/trash/{kind}", get(trash))
+        .route("/admin/trash/{kind}/{id}/restore", post(restore_deleted))
+        .route(
+            "/admin/tokens/stats",
+            get(tokens_stats).layer(from_fn(cache_response)),
+        )
+        .route("/audience/estimate", post(audience_estimate))
+        .route("/audience/snapshots", post(audience_snapshot))
+        .route("/jobs/{id}", get(job_status))
+        .route("/batches/{id}", get(batch_status))
+        .route("/jobs/{id}/abort", post(abort))
+        .route(
+            "/history/{id}/resend",
+            post(resend).layer(from_fn(shape_response)),
+        )
+        .nest("/webhooks", webhooks::router())
+        .fallback(|| async { ApiError::NotFound("Not Found".into()) })
+        .with_state(state)
+}
+
+/// What a container serves when [`AppState::load`] found configuration
+/// missing: every route answers 500 with the list, so the problem shows in
+/// the response instead of as an opaque Lambda init failure.
+pub fn misconfigured_app(missing: Vec<String>) -> Router {
+    Router::new().fallback(move || {
+        let missing = missing.clone();
+        async move { ApiError::Misconfigured { missing } }
+    })
+}
+
+/// The [`SendRequest`] fields [`broadcast_content`] reads.
+const CONTENT_FIELDS: &[&str] = &[
+    "title",
+    "body",
+    "template_id",
+    "variables",
+    "localized",
+    "spread_over_minutes",
+    "collapse_key",
+    "category",
+    "data",
+    "sound",
+    "badge",
+    "priority",
+    "ttl",
+    "channel_id",
+    "dry_run",
+];
+/// The [`SendRequest`] fields [`resolve_requested_audience`] reads.
+const AUDIENCE_FIELDS: &[&str] = &[
+    "audience",
+    "audience_rpc",
+    "audience_rpc_args",
+    "audience_snapshot",
+    "user_ids",
+];
+/// Fields `POST /send/batch` accepts next to [`CONTENT_FIELDS`].
+const BATCH_FIELDS: &[&str] = &["entries", "skip_invalid"];
+/// Fields a sampled broadcast accepts next to a [`SendRequest`]'s.
+const SAMPLE_FIELDS: &[&str] = &["sample_percent", "seed"];
+
+/// `body` without `fields`, so the rest can be read as a [`SendRequest`].
+fn without_fields(body: &Value, fields: &[&str]) -> Value {
+    let mut body = body.clone();
+    if let Some(object) = body.as_object_mut() {
+        object.retain(|key, _| !fields.contains(&key.as_str()));
+    }
+    body
+}
+
+/// The message fields shared by every send route, with no recipients yet.
+/// With `template_id` the title and body default to the stored template's.
+async fn broadcast_content(state: &AppState, request: &SendRequest) -> Result<Broadcast, ApiError> {
+    let template = match &request.template_id {
+        Some(template_id) => {
+            let supabase_client = initialize_supabase_client(&state.secrets)?;
+            Some(load_template(&supabase_client, template_id).await?)
+        }
+        None => None,
+    };
+    let mut translations = template
+        .as_ref()
+        .map(|template| template.locales.clone())
+        .unwrap_or_default();
+    translations.extend(request.localized.clone().unwrap_or_default());
+    let locales = (!translations.is_empty()).then(|| LocaleVariants::new(translations));
+    let mut violations = Violations::default();
+    let title = violations.check(
+        "title",
+        validate_title(
+            request
+                .title
+                .clone()
+                .or_else(|| template.as_ref().map(|template| template.title.clone())),
+        ),
+    );
+    let body = violations.check(
+        "body",
+        validate_body(
+            request
+                .body
+                .clone()
+                .or_else(|| template.as_ref().map(|template| template.body.clone())),
+        ),
+    );
+    if let Some(token) = &request.expo_push_token {
+        if !Expo::is_expo_push_token(token) {
+            violations.push("expo_push_token", "Invalid expo push token");
+        }
+    }
+    let sound = violations.check(
+        "sound",
+        validate_sound(
+            request.sound.as_deref(),
+            &dynamic_config().await.allowed_sounds,
+        ),
+    );
+    let spread_over_minutes = violations.check(
+        "spread_over_minutes",
+        validate_spread_over_minutes(request.spread_over_minutes),
+    );
+    let channel_id = violations.check(
+        "channel_id",
+        validate_channel_id(request.channel_id.as_deref()),
+    );
+    let collapse_key = violations.check(
+        "collapse_key",
+        validate_collapse_key(request.collapse_key.as_deref()),
+    );
+    let category = violations.check("category", validate_category(request.category.as_deref()));
+    let data = violations.check("data", validate_data(request.data.as_ref()));
+    violations.into_result()?;
+    Ok(Broadcast {
+        title,
+        body,
+        tokens: vec![],
+        spread_over_minutes,
+        sound,
+        badge: request.badge,
+        priority: request.priority,
+        ttl: request.ttl,
+        channel_id,
+        collapse_key,
+        category,
+        variables: request.variables.clone().unwrap_or_default(),
+        vars: HashMap::new(),
+        platform_variants: template.map(|template| PlatformVariants {
+            template_id: template.id,
+            variants: template.platforms,
+            ..Default::default()
+        }),
+        locales,
+        data,
+        dry_run: request.dry_run.unwrap_or(false),
+    })
+}
+
+fn explicit_tokens(request: &SendRequest) -> Result<(Vec<String>, Vec<Value>), ApiError> {
+    let Some(entries) = &request.expo_push_tokens else {
+        let token = request.expo_push_token.as_deref().ok_or_else(|| {
+            ApiError::BadRequest("expo_push_token or expo_push_tokens is required".into())
+        })?;
+        if !Expo::is_expo_push_token(token) {
+            return Err(ApiError::BadRequest("Invalid expo push token".into()));
+        }
+        return Ok((vec![token.to_string()], vec![]));
+    };
+    if entries.is_empty() {
+        return Err(ApiError::BadRequest(
+            "expo_push_tokens must be a non-empty array".into(),
+        ));
+    }
+
+    let mut tokens = Vec::with_capacity(entries.len());
+    let mut rejected = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        match entry.as_str() {
+            Some(token) if Expo::is_expo_push_token(token) => tokens.push(token.to_string()),
+            _ => rejected.push(json!({ "index": index, "expo_push_token": entry })),
+        }
+    }
+    if tokens.is_empty() {
+        return Err(ApiError::InvalidTokens {
+            indices: (0..entries.len()).collect(),
+        });
+    }
+    if !rejected.is_empty() {
+        info!(
+            rejected = rejected.len(),
+            "Rejected invalid expo push tokens"
+        );
+    }
+    Ok((tokens, rejected))
+}
+
+/// The broadcast a `POST /` body asks for, and the explicit tokens that were
+/// rejected as invalid.
+async fn broadcast_from_body(
+    state: &AppState,
+    request: &SendRequest,
+) -> Result<(Broadcast, Vec<Value>), ApiError> {
+    let content = broadcast_content(state, request).await?;
+
+    // Explicit tokens never touch Supabase, so they keep working during an
+    // outage; only audience lookups depend on the token store.
+    let (tokens, rejected) = match resolve_requested_audience(&state.secrets, &request.audience())
+        .await
+        .map_err(ApiError::store_unavailable)?
+    {
+        Some(tokens) => (tokens, vec![]),
+        None => explicit_tokens(request)?,
+    };
+
+    Ok((Broadcast { tokens, ..content }, rejected))
+}
+
+/// One recipient of `POST /send/batch`, with the values for its
+/// placeholders.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchEntry {
+    expo_push_token: String,
+    #[serde(default)]
+    vars: Map<String, Value>,
+}
+
+/// The broadcast a `POST /send/batch` body asks for: `title` and `body` are
+/// templates rendered per entry from its `vars`, so callers can personalize
+/// without this service reading their data.
+///
+/// Every token is validated before anything is sent. Invalid ones fail the
+/// request with their indices, or with `skip_invalid: true` are dropped and
+/// returned as the second element.
+async fn batch_from_body(
+    state: &AppState,
+    json_body: &Value,
+) -> Result<(Broadcast, Vec<Value>), ApiError> {
+    let request = from_json::<SendRequest>(&without_fields(json_body, BATCH_FIELDS), "")?;
+    let content = broadcast_content(state, &request).await?;
+    let entries =
+        serde_json::from_value::<Vec<BatchEntry>>(json_body["entries"].clone()).map_err(|e| {
+            match unknown_field(&e) {
+                Some(field) => ApiError::UnknownFields {
+                    fields: vec![format!("entries[].{field}")],
+                },
+                None => ApiError::BadRequest(
+                    "entries must be an array of {expo_push_token, vars}".into(),
+                ),
+            }
+        })?;
+
+    let invalid = invalid_token_indices(entries.iter().map(|entry| entry.expo_push_token.as_str()));
+    let skip_invalid = json_body["skip_invalid"].as_bool().unwrap_or(false);
+    if !invalid.is_empty() && !skip_invalid {
+        return Err(ApiError::InvalidTokens { indices: invalid });
+    }
+
+    let mut tokens = Vec::with_capacity(entries.len());
+    let mut vars = HashMap::with_capacity(entries.len());
+    let mut skipped = vec![];
+    for (index, entry) in entries.into_iter().enumerate() {
+        if invalid.contains(&index) {
+            skipped.push(json!({ "index": index, "expo_push_token": entry.expo_push_token }));
+            continue;
+        }
+        if vars
+            .insert(entry.expo_push_token.clone(), entry.vars)
+            .is_some()
+        {
+            return Err(ApiError::BadRequest(format!(
+                "Duplicate expo_push_token in entries[{index}]"
+            )));
+        }
+        tokens.push(entry.expo_push_token);
+    }
+    if !skipped.is_empty() {
+        info!(skipped = skipped.len(), "Skipping invalid expo push tokens");
+    }
+    Ok((
+        Broadcast {
+            tokens,
+            vars,
+            ..content
+        },
+        skipped,
+    ))
+}
+
+/// The broadcast `/scheduled` sends: the configured message to every active
+/// token, or to the users `audience` filters down to, in the recipient's
+/// language when it has a translation.
+async fn scheduled_broadcast(
+    state: &AppState,
+    audience: &AudienceQuery,
+) -> Result<Broadcast, ApiError> {
+    let config = dynamic_config().await;
+    let (title, body) = config.default_message()?;
+    let translations = config.default_message_locales();
+    let tokens = if audience.is_empty() {
+        token_store(&state.secrets)
+            .await
+            .map_err(ApiError::store_unavailable)?
+            .fetch_tokens()
+            .await
+            .map_err(ApiError::store_unavailable)?
+    } else {
+        SupabaseFilterResolver(
+            initialize_supabase_client(&state.secrets).map_err(ApiError::store_unavailable)?,
+        )
+        .resolve(audience)
+        .await
+        .map_err(ApiError::store_unavailable)?
+        .into_iter()
+        .map(|recipient| recipient.expo_push_token)
+        .collect()
+    };
+    Ok(Broadcast {
+        title,
+        body,
+        tokens,
+        spread_over_minutes: None,
+        sound: None,
+        badge: None,
+        priority: None,
+        ttl: None,
+        channel_id: None,
+        collapse_key: None,
+        category: None,
+        variables: Map::new(),
+        vars: HashMap::new(),
+        platform_variants: None,
+        locales: (!translations.is_empty()).then(|| LocaleVariants::new(translations)),
+        data: None,
+        dry_run: false,
+    })
+}
+
+/// The broadcast `POST /topics/{topic}/send` sends: the content in `body`
+/// to every subscriber of `topic`.
+async fn topic_broadcast(
+    state: &AppState,
+    topic: &str,
+    body: &Value,
+) -> Result<Broadcast, ApiError> {
+    validate_topic(topic)?;
+    reject_unknown_fields(body, "", &[CONTENT_FIELDS])?;
+    let request = from_json::<SendRequest>(body, "")?;
+    let content = broadcast_content(state, &request).await?;
+    let query = AudienceQuery {
+        topic: Some(topic.to_string()),
+        ..AudienceQuery::default()
+    };
+    let tokens = TopicResolver(initialize_supabase_client(&state.secrets)?)
+        .resolve(&query)
+        .await
+        .map_err(ApiError::store_unavailable)?
+        .into_iter()
+        .map(|recipient| recipient.expo_push_token)
+        .collect();
+    Ok(Broadcast { tokens, ..content })
+}
+
+/// Answers a request that hit a token store outage: queued in the outbox
+/// when the caller asked for that and it is configured, 503 otherwise.
+async fn queue_or_unavailable(queue: bool, route: &str, body: &Value) -> ApiResult {
+    if queue {
+        if let Some(outbox_id) = outbox::enqueue(route, body).await {
+            return Ok((
+                StatusCode::ACCEPTED,
+                Json(json!({
+                    "message": "Token store unavailable; request queued for delivery",
+                    "outbox_id": outbox_id,
+                })),
+            ));
+        }
+    }
+    Err(ApiError::StoreUnavailable)
+}
+
+async fn send(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    Deadline(deadline): Deadline,
+    JsonBody(json_body): JsonBody<Value>,
+) -> ApiResult {
+    let request = from_json::<SendRequest>(&json_body, "")?;
+    let dry_run = query.dry_run || request.dry_run == Some(true);
+    if let Some(send_at) = &request.send_at {
+        let send_at = parse_send_at(send_at)?;
+        if send_at <= Utc::now() {
+            return Err(ApiError::BadRequest("send_at must be in the future".into()));
+        }
+        // A dry run previews the send now rather than storing it.
+        if !dry_run {
+            return schedule_send(&state, &request, &json_body, send_at).await;
+        }
+    }
+    let timings = Timings::start();
+    match broadcast_from_body(&state, &request).await {
+        Ok((mut broadcast, rejected)) => {
+            broadcast.dry_run = dry_run;
+            let (status, Json(mut response)) =
+                send_broadcast(&state, broadcast, query.job_id, deadline, timings).await?;
+            if !rejected.is_empty() {
+                response["rejected"] = json!(rejected);
+            }
+            Ok((status, Json(response)))
+        }
+        Err(ApiError::StoreUnavailable) => {
+            queue_or_unavailable(query.queue_if_unavailable && !dry_run, "/", &json_body).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Validates a `send_at` request as [`send`] would and stores it. The
+/// audience is resolved when it is dispatched, so it reaches whoever is in
+/// it by then.
+async fn schedule_send(
+    state: &AppState,
+    request: &SendRequest,
+    json_body: &Value,
+    send_at: DateTime<Utc>,
+) -> ApiResult {
+    broadcast_content(state, request).await?;
+    if requested_audience(&state.secrets, &request.audience())?.is_none() {
+        explicit_tokens(request)?;
+    }
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let scheduled_id = create_scheduled(
+        &supabase_client,
+        "/send",
+        &without_fields(json_body, &["send_at"]),
+        send_at,
+    )
+    .await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "message": "Notification scheduled",
+            "scheduled_id": scheduled_id,
+            "send_at": send_at.to_rfc3339(),
+        })),
+    ))
+}
+
+async fn send_to_topic(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+    Query(query): Query<SendQuery>,
+    Deadline(deadline): Deadline,
+    JsonBody(json_body): JsonBody<Value>,
+) -> ApiResult {
+    let timings = Timings::start();
+    match topic_broadcast(&state, &topic, &json_body).await {
+        Ok(mut broadcast) => {
+            broadcast.dry_run |= query.dry_run;
+            send_broadcast(&state, broadcast, query.job_id, deadline, timings).await
+        }
+        Err(ApiError::StoreUnavailable) => {
+            let route = format!("/topics/{topic}/send");
+            let queue = query.queue_if_unavailable && !query.dry_run;
+            queue_or_unavailable(queue, &route, &json_body).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn send_batch(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    Deadline(deadline): Deadline,
+    JsonBody(json_body): JsonBody<Value>,
+) -> ApiResult {
+    reject_unknown_fields(&json_body, "", &[CONTENT_FIELDS, BATCH_FIELDS])?;
+    let timings = Timings::start();
+    let (mut broadcast, skipped) = batch_from_body(&state, &json_body).await?;
+    broadcast.dry_run |= query.dry_run;
+    let (status, Json(mut response)) =
+        send_broadcast(&state, broadcast, query.job_id, deadline, timings).await?;
+    response["skipped"] = json!(skipped);
+    Ok((status, Json(response)))
+}
+
+/// Accepts related notifications as one unit: every message is validated
+/// (and its audience resolved) before any is sent, so a bundle is either
+/// rejected as a whole or recorded under one id and sent.
+async fn send_bundle(
+    State(state): State<AppState>,
+    Deadline(deadline): Deadline,
+    JsonBody(json_body): JsonBody<Value>,
+) -> ApiResult {
+    reject_unknown_fields(&json_body, "", &[&["messages"]])?;
+    let messages = json_body["messages"]
+        .as_array()
+        .filter(|messages| !messages.is_empty())
+        .ok_or_else(|| ApiError::BadRequest("messages must be a non-empty array".into()))?;
+    if messages.len() > MAX_BUNDLE_MESSAGES {
+        return Err(ApiError::BadRequest(format!(
+            "A bundle holds at most {MAX_BUNDLE_MESSAGES} messages"
+        )));
+    }
+
+    let mut broadcasts = Vec::with_capacity(messages.len());
+    for (index, message) in messages.iter().enumerate() {
+        let request = from_json::<SendRequest>(message, &format!("messages[{index}]."))?;
+        if request.send_at.is_some() {
+            return Err(ApiError::BadRequest(format!(
+                "messages[{index}]: send_at is only supported by POST /send"
+            )));
+        }
+        if request.dry_run.is_some() {
+            return Err(ApiError::BadRequest(format!(
+                "messages[{index}]: dry_run is not supported by bundles"
+            )));
+        }
+        match broadcast_from_body(&state, &request).await {
+            // All or nothing: a bundle is not sent to a subset of its tokens.
+            Ok((_, rejected)) if !rejected.is_empty() => {
+                return Err(ApiError::BadRequest(format!(
+                    "messages[{index}]: Invalid expo push token in expo_push_tokens"
+                )))
+            }
+            Ok((broadcast, _)) => broadcasts.push(broadcast),
+            Err(ApiError::BadRequest(message)) => {
+                return Err(ApiError::BadRequest(format!(
+                    "messages[{index}]: {message}"
+                )))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let bundle_id = create_bundle(&supabase_client, broadcasts.len()).await?;
+    let mut results = Vec::with_capacity(broadcasts.len());
+    for (index, broadcast) in broadcasts.into_iter().enumerate() {
+        let result = send_broadcast(&state, broadcast, None, deadline, Timings::start()).await;
+        results.push(match result {
+            Ok((StatusCode::OK, Json(response))) => json!({
+                "index": index,
+                "status": "sent",
+                "history_id": response["history_id"],
+            }),
+            Ok((status, Json(response))) => json!({
+                "index": index,
+                "status": "failed",
+                "http_status": status.as_u16(),
+                "history_id": response["history_id"],
+            }),
+            Err(e) => json!({ "index": index, "status": "failed", "error_code": e.code() }),
+        });
+    }
+    let status = finish_bundle(&supabase_client, &bundle_id, &results).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "bundle_id": bundle_id, "status": status, "messages": results })),
+    ))
+}
+
+async fn bundle_status(State(state): State<AppState>, Path(bundle_id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let bundle = load_bundle(&supabase_client, &bundle_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Bundle not found".into()))?;
+    Ok((StatusCode::OK, Json(bundle)))
+}
+
+/// A broadcast to a deterministic sample of its audience, for load and
+/// latency tests against a fraction of production users. Without an audience
+/// selector the sample is drawn from every active token.
+async fn sampled_broadcast(
+    State(state): State<AppState>,
+    Deadline(deadline): Deadline,
+    JsonBody(json_body): JsonBody<Value>,
+) -> ApiResult {
+    reject_unknown_fields(
+        &json_body,
+        "",
+        &[CONTENT_FIELDS, AUDIENCE_FIELDS, SAMPLE_FIELDS],
+    )?;
+    let timings = Timings::start();
+    let percent = json_body["sample_percent"]
+        .as_f64()
+        .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+        .ok_or_else(|| {
+            ApiError::BadRequest("sample_percent must be a number in (0, 100]".into())
+        })?;
+    let seed = match &json_body["seed"] {
+        Value::String(seed) => seed.clone(),
+        Value::Number(seed) => seed.to_string(),
+        _ => return Err(ApiError::BadRequest("seed is required".into())),
+    };
+
+    let request = from_json::<SendRequest>(&without_fields(&json_body, SAMPLE_FIELDS), "")?;
+    let content = broadcast_content(&state, &request).await?;
+    let audience = match resolve_requested_audience(&state.secrets, &request.audience()).await? {
+        Some(tokens) => tokens,
+        None => token_store(&state.secrets).await?.fetch_tokens().await?,
+    };
+    let audience_size = audience.len();
+    let tokens = sample_audience(audience, percent, &seed);
+    info!(
+        audience_size,
+        sampled = tokens.len(),
+        percent,
+        "Sampled broadcast audience"
+    );
+
+    let (status, Json(mut response)) = send_broadcast(
+        &state,
+        Broadcast { tokens, ..content },
+        None,
+        deadline,
+        timings,
+    )
+    .await?;
+    response["sample"] = json!({
+        "percent": percent,
+        "seed": seed,
+        "audience_size": audience_size,
+    });
+    Ok((status, Json(response)))
+}
+
+async fn scheduled(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    Query(params): Query<Vec<(String, String)>>,
+    uri: Uri,
+    Deadline(deadline): Deadline,
+) -> ApiResult {
+    let audience = audience_from_query_params(&params)?;
+    let timings = Timings::start();
+    match scheduled_broadcast(&state, &audience).await {
+        Ok(mut broadcast) => {
+            broadcast.dry_run = query.dry_run;
+            send_broadcast(&state, broadcast, query.job_id, deadline, timings).await
+        }
+        Err(ApiError::StoreUnavailable) => {
+            let route = match uri.query() {
+                Some(filters) => format!("/scheduled?{filters}"),
+                None => "/scheduled".to_string(),
+            };
+            let queue = query.queue_if_unavailable && !query.dry_run;
+            queue_or_unavailable(queue, &route, &Value::Null).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// How replaying a queued request ended.
+enum Replay {
+    Sent,
+    /// Cut short by the deadline; re-running it with the same `job_id`
+    /// resumes from the last completed chunk.
+    Partial,
+    /// Rejected for good, e.g. a body that no longer validates.
+    Dropped,
+    Failed,
+    /// The token store is still down; later entries would fail too.
+    StoreUnavailable,
+}
+
+/// Sends a request recorded by the outbox or the offline journal as if it
+/// had just arrived on `route`, which keeps the query string of a filtered
+/// broadcast.
+async fn replay(
+    state: &AppState,
+    route: &str,
+    body: &Value,
+    job_id: Option<String>,
+    deadline: Option<SystemTime>,
+) -> Replay {
+    let uri = route.parse::<Uri>().unwrap_or_default();
+    let broadcast = match uri.path() {
+        "/scheduled" | "/broadcast" => {
+            let params = Query::<Vec<(String, String)>>::try_from_uri(&uri)
+                .map_err(|e| ApiError::BadRequest(e.body_text()))
+                .and_then(|Query(params)| audience_from_query_params(&params));
+            match params {
+                Ok(audience) => scheduled_broadcast(state, &audience).await,
+                Err(e) => Err(e),
+            }
+        }
+        "/send/batch" => batch_from_body(state, body)
+            .await
+            .map(|(broadcast, _)| broadcast),
+        path if path.starts_with("/topics/") && path.ends_with("/send") => {
+            let topic = &path["/topics/".len()..path.len() - "/send".len()];
+            topic_broadcast(state, topic, body).await
+        }
+        _ => match from_json::<SendRequest>(body, "") {
+            Ok(request) => {
+                // Still ahead: schedule it as the original request would
+                // have been. One that has come due is sent now, late.
+                let send_at = request
+                    .send_at
+                    .as_deref()
+                    .and_then(|send_at| parse_send_at(send_at).ok())
+                    .filter(|send_at| *send_at > Utc::now());
+                if let Some(send_at) = send_at {
+                    return match schedule_send(state, &request, body, send_at).await {
+                        Ok(_) => Replay::Sent,
+                        Err(e) if !e.status().is_server_error() => Replay::Dropped,
+                        Err(_) => Replay::Failed,
+                    };
+                }
+                broadcast_from_body(state, &request)
+                    .await
+                    .map(|(broadcast, _)| broadcast)
+            }
+            Err(e) => Err(e),
+        },
+    };
+    let result = match broadcast {
+        Err(ApiError::StoreUnavailable) => return Replay::StoreUnavailable,
+        Err(e) => Err(e),
+        Ok(broadcast) => send_broadcast(state, broadcast, job_id, deadline, Timings::start()).await,
+    };
+    match result {
+        // Paced broadcasts answer `202` once their slots are scheduled.
+        Ok((StatusCode::ACCEPTED, Json(body))) if body["result"] == "paced" => Replay::Sent,
+        Ok((StatusCode::ACCEPTED, _)) => Replay::Partial,
+        Ok((status, _)) if !status.is_server_error() => Replay::Sent,
+        Err(e) if !e.status().is_server_error() => Replay::Dropped,
+        _ => Replay::Failed,
+    }
+}
+
+/// Replays outbox entries queued during a token store outage, oldest first.
+/// Stops at the first entry whose audience still cannot be resolved.
+async fn drain_outbox(State(state): State<AppState>, Deadline(deadline): Deadline) -> ApiResult {
+    let entries = outbox::pending_entries().await;
+    let mut replayed = 0;
+    let mut dropped = 0;
+    let mut failed = 0;
+    for (key, entry) in &entries {
+        match replay(&state, &entry.route, &entry.body, None, deadline).await {
+            Replay::Sent | Replay::Partial => replayed += 1,
+            Replay::Dropped => dropped += 1,
+            Replay::Failed => {
+                failed += 1;
+                continue;
+            }
+            Replay::StoreUnavailable => break,
+        }
+        outbox::remove(key).await;
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "replayed": replayed,
+            "dropped": dropped,
+            "failed": failed,
+            "remaining": entries.len() - replayed - dropped,
+        })),
+    ))
+}
+
+/// Sends the scheduled notifications that have come due, earliest first;
+/// EventBridge invokes it every minute. Each is sent under its scheduled id
+/// as `job_id`, so one cut short by the deadline, or failing transiently,
+/// stays pending and resumes on the next run.
+async fn dispatch_scheduled(
+    State(state): State<AppState>,
+    Deadline(deadline): Deadline,
+) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let due = due_scheduled(&supabase_client).await?;
+    let mut dispatched = 0;
+    let mut failed = 0;
+    for row in &due {
+        if is_near_deadline(deadline) {
+            warn!("Stopping scheduled dispatch before the Lambda deadline");
+            break;
+        }
+        let id = row["id"].as_str().unwrap_or_default();
+        let route = row["route"].as_str().unwrap_or_default();
+        let span = info_span!("scheduled_send", scheduled_id = %id);
+        let outcome = replay(&state, route, &row["body"], Some(id.to_string()), deadline)
+            .instrument(span.clone())
+            .await;
+        match outcome {
+            Replay::Sent => {
+                finish_scheduled(&supabase_client, id, "sent").await?;
+                dispatched += 1;
+            }
+            Replay::Dropped => {
+                warn!(scheduled_id = %id, "Scheduled notification no longer validates");
+                finish_scheduled(&supabase_client, id, "failed").await?;
+                failed += 1;
+            }
+            Replay::Partial | Replay::Failed => {}
+            Replay::StoreUnavailable => break,
+        }
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "dispatched": dispatched,
+            "failed": failed,
+            "remaining": due.len() - dispatched - failed,
+        })),
+    ))
+}
+
+/// Replays the offline journal against the real Expo and Supabase, in the
+/// order requests were journaled, then rewrites it with only the entries
+/// that failed and may be retried. Run by the binary's `flush` command.
+pub async fn flush_journal(state: &AppState) -> Result<Value, ApiError> {
+    let entries = journal::entries();
+    let mut replayed = 0;
+    let mut dropped = 0;
+    let mut remaining = vec![];
+    let mut entries = entries.into_iter();
+    while let Some(entry) = entries.next() {
+        match replay(state, &entry.route, &entry.body, None, None).await {
+            Replay::Sent | Replay::Partial => replayed += 1,
+            Replay::Dropped => {
+                warn!(journal_id = %entry.id, "Dropping journaled request that no longer validates");
+                dropped += 1;
+            }
+            Replay::Failed => remaining.push(entry),
+            Replay::StoreUnavailable => {
+                remaining.push(entry);
+                remaining.extend(entries.by_ref());
+            }
+        }
+    }
+    journal::retain(&remaining)?;
+    Ok(json!({
+        "replayed": replayed,
+        "dropped": dropped,
+        "remaining": remaining.len(),
+    }))
+}
+
+/// Performs the sends queued by [`crate::send_queue::enqueue`], one
+/// broadcast per record; the `send_worker` binary runs it on the queue's
+/// event source mapping. Records that fail transiently, or run out of time,
+/// are reported as batch item failures so SQS redelivers only those, and a
+/// redelivered broadcast resumes under its batch id. Requests that no longer
+/// validate are dropped.
+pub async fn consume_send_queue(
+    state: &AppState,
+    event: LambdaEvent<SqsEvent>,
+) -> Result<SqsBatchResponse, Error> {
+    let deadline = Some(event.context.deadline());
+    let mut failures = vec![];
+    for record in event.payload.records {
+        let message_id = record.message_id.unwrap_or_default();
+        let queued = match record
+            .body
+            .as_deref()
+            .map(serde_json::from_str::<QueuedSend>)
+        {
+            Some(Ok(queued)) => queued,
+            _ => {
+                warn!(message_id = %message_id, "Dropping malformed send queue message");
+                continue;
+            }
+        };
+        let span = info_span!("queued_send", batch_id = %queued.batch_id, route = %queued.route);
+        let supabase_client = initialize_supabase_client(&state.secrets).ok();
+        let set_state = |batch_state| {
+            let (supabase_client, batch_id) = (&supabase_client, &queued.batch_id);
+            async move {
+                let Some(client) = supabase_client else {
+                    return;
+                };
+                if let Err(e) = set_batch_state(client, batch_id, batch_state, None).await {
+                    warn!(error = %e, batch_id = %batch_id, "Failed to record batch state");
+                }
+            }
+        };
+        set_state(BatchState::Sending)
+            .instrument(span.clone())
+            .await;
+        let send = replay(
+            state,
+            &queued.route,
+            &queued.body,
+            Some(queued.batch_id.clone()),
+            deadline,
+        )
+        .instrument(span.clone());
+        let outcome = match (queued.caller.clone(), queued.tenant.clone()) {
+            (Some(caller), Some(tenant)) => scope_caller(caller, tenant, send).await,
+            _ => send.await,
+        };
+        let batch_state = match outcome {
+            Replay::Sent => BatchState::Done,
+            Replay::Dropped => BatchState::Failed,
+            Replay::Partial | Replay::Failed | Replay::StoreUnavailable => BatchState::Queued,
+        };
+        set_state(batch_state).instrument(span).await;
+        match outcome {
+            Replay::Sent => info!(batch_id = %queued.batch_id, "Queued send completed"),
+            Replay::Dropped => {
+                warn!(batch_id = %queued.batch_id, "Dropping queued send that no longer validates")
+            }
+            Replay::Partial | Replay::Failed | Replay::StoreUnavailable => {
+                warn!(batch_id = %queued.batch_id, "Queued send incomplete; leaving it for redelivery");
+                let mut failure = BatchItemFailure::default();
+                failure.item_identifier = message_id;
+                failures.push(failure);
+            }
+        }
+    }
+    let mut response = SqsBatchResponse::default();
+    response.batch_item_failures = failures;
+    Ok(response)
+}
+
+async fn tokens(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<RegisterTokenRequest>,
+) -> ApiResult {
+    let registration = token_store(&state.secrets)
+        .await?
+        .save_token(request)
+        .await?;
+    let status = match registration.status {
+        RegistrationStatus::Created => StatusCode::CREATED,
+        _ => StatusCode::OK,
+    };
+    Ok((status, Json(json!(registration))))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteTokenRequest {
+    expo_push_token: String,
+}
+
+/// Sent by the app on sign-out, so the device stops getting notifications.
+async fn delete_token(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<DeleteTokenRequest>,
+) -> ApiResult {
+    let deleted = token_store(&state.secrets)
+        .await?
+        .delete_token(&request.expo_push_token)
+        .await?;
+    if !deleted {
+        return Err(ApiError::NotFound("Token is not registered".into()));
+    }
+    Ok((StatusCode::OK, Json(json!({ "deleted": true }))))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnsubscribeRequest {
+    token: String,
+}
+
+/// Sent by the app when the user changes their notification settings.
+async fn preferences(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<PreferencesRequest>,
+) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let settings = update_preferences(&supabase_client, request).await?;
+    Ok((StatusCode::OK, Json(settings)))
+}
+
+/// One-tap opt-out: the app posts the `unsubscribe_token` from a marketing
+/// notification's data payload.
+async fn unsubscribe(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<UnsubscribeRequest>,
+) -> ApiResult {
+    let (expo_push_token, category) = verify_unsubscribe_token(&state.secrets, &request.token)?;
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let updated = opt_out(&supabase_client, &expo_push_token, &category).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "category": category, "unsubscribed": updated > 0 })),
+    ))
+}
+
+async fn version() -> ApiResult {
+    Ok((StatusCode::OK, Json(json!(build_info()))))
+}
+
+/// Readiness check: `200` when Supabase and Expo both answer, `503` with
+/// the failing probe otherwise. Answering at all means the container and
+/// router are up.
+async fn health(State(state): State<AppState>) -> ApiResult {
+    let report = check_health(&state.secrets).await;
+    let status = if report.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((status, Json(json!(report))))
+}
+
+/// Resolves pending receipts and sends each tenant the events of the
+/// tickets it sent, whichever key (or schedule) triggered the check.
+async fn receipts(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let summary = check_receipts(&state.expo, &supabase_client).await?;
+    for (tenant, receipts) in &summary.tenants {
+        if receipts.resolved > 0 {
+            dispatch_event(
+                &state.secrets,
+                tenant,
+                "receipts.resolved",
+                json!({
+                    "checked": receipts.checked,
+                    "resolved": receipts.resolved,
+                    "still_pending": receipts.still_pending,
+                    "errors": receipts.errors,
+                }),
+            )
+            .await;
+        }
+        if receipts.pruned > 0 {
+            dispatch_event(
+                &state.secrets,
+                tenant,
+                "token.pruned",
+                json!({
+                    "pruned": receipts.pruned,
+                    "expo_push_tokens": receipts.device_not_registered,
+                }),
+            )
+            .await;
+        }
+        for (category, report) in receipts.sla.iter().filter(|(_, report)| report.breached) {
+            dispatch_event(
+                &state.secrets,
+                tenant,
+                "sla.breached",
+                json!({ "category": category, "report": report }),
+            )
+            .await;
+        }
+    }
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+async fn retry_webhooks(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let summary = webhooks::retry_failed_deliveries(&state.secrets, &supabase_client).await?;
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+async fn revalidate_tokens(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let summary = revalidate_quarantined_tokens(&state.expo, &supabase_client).await?;
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+async fn cleanup(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let summary = cleanup_expired_records(&supabase_client).await?;
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+async fn selftest(State(state): State<AppState>) -> ApiResult {
+    let report = run_selftest(&state).await?;
+    // A failing report is a failed call, so `curl -f` in a deploy script
+    // stops the rollout.
+    let status = if report.passed {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok((status, Json(json!(report))))
+}
+
+/// Whether campaigns are backed up: queued outbox requests, broadcasts
+/// still to finish, and a rough estimate of how long the backlog takes to
+/// drain at `EXPO_SEND_RATE_PER_SECOND` (default 600, Expo's per-project
+/// limit).
+async fn queue_depth(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let in_flight = in_flight_jobs(&supabase_client).await?;
+    let outbox_depth = outbox::depth().await;
+
+    let send_rate = env::var("EXPO_SEND_RATE_PER_SECOND")
+        .ok()
+        .and_then(|rate| rate.parse::<f64>().ok())
+        .filter(|rate| *rate > 0.0)
+        .unwrap_or(DEFAULT_EXPO_SEND_RATE);
+    // Chunks may be smaller than CHUNK_SIZE, so this errs on the long side.
+    let remaining_messages = in_flight.remaining_chunks * CHUNK_SIZE;
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "outbox_depth": outbox_depth,
+            "in_flight_jobs": in_flight.jobs,
+            "in_flight_chunks": in_flight.remaining_chunks,
+            "estimated_drain_secs": (remaining_messages as f64 / send_rate).ceil(),
+        })),
+    ))
+}
+
+async fn delete_tokens(
+    State(state): State<AppState>,
+    JsonBody(filter): JsonBody<TokenDeleteFilter>,
+) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let mut event_log = EventLog::default();
+    let result = delete_tokens_by_filter(&supabase_client, filter, &mut event_log).await;
+    event_log.flush().await;
+    Ok((StatusCode::OK, Json(json!(result?))))
+}
+
+async fn merge_duplicates(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<MergeDuplicatesRequest>,
+) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let summary = merge_duplicate_tokens(&supabase_client, request).await?;
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+/// Soft-deletes a template; sends referencing it fail until it is restored.
+async fn delete_template(State(state): State<AppState>, Path(id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    load_template(&supabase_client, &id)
+        .await
+        .map_err(|e| match e {
+            ApiError::BadRequest(_) => ApiError::NotFound("Template not found".into()),
+            e => e,
+        })?;
+    soft_delete(&supabase_client, TrashKind::Templates.table(), &id)
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error deleting template");
+            ApiError::SupabaseWrite
+        })?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "template_id": id, "status": "deleted" })),
+    ))
+}
+
+/// Recently soft-deleted tokens or templates, restorable until the cleanup
+/// job purges them after `TRASH_RETENTION_DAYS`.
+async fn trash(State(state): State<AppState>, Path(kind): Path<String>) -> ApiResult {
+    let kind = TrashKind::parse(&kind)?;
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let rows = list_deleted(&supabase_client, kind).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "count": rows.len(), "items": rows })),
+    ))
+}
+
+async fn restore_deleted(
+    State(state): State<AppState>,
+    Path((kind, id)): Path<(String, String)>,
+) -> ApiResult {
+    let kind = TrashKind::parse(&kind)?;
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    if !restore(&supabase_client, kind, &id).await? {
+        return Err(ApiError::NotFound("No deleted row with that id".into()));
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "id": id, "status": "restored" })),
+    ))
+}
+
+async fn tokens_stats(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let stats = token_stats(&supabase_client).await?;
+    Ok((StatusCode::OK, Json(json!(stats))))
+}
+
+async fn snapshot_token_stats_job(State(state): State<AppState>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let snapshot = snapshot_token_stats(&supabase_client).await?;
+    Ok((StatusCode::OK, Json(json!(snapshot))))
+}
+
+#[derive(Debug, Deserialize)]
+struct NotificationsQuery {
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+/// Logged sends, newest first, for the admin dashboard.
+async fn notifications(
+    State(state): State<AppState>,
+    Query(query): Query<NotificationsQuery>,
+) -> ApiResult {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}"
+        )));
+    }
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let page = list_notifications(&supabase_client, limit, query.cursor.as_deref()).await?;
+    Ok((StatusCode::OK, Json(page)))
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenStatsQuery {
+    /// How far back the trend goes, default 30.
+    days: Option<i64>,
+}
+
+/// Nightly token table snapshots, for registration growth and churn charts.
+async fn token_stats_history(
+    State(state): State<AppState>,
+    Query(query): Query<TokenStatsQuery>,
+) -> ApiResult {
+    let days = query.days.unwrap_or(30);
+    if !(1..=366).contains(&days) {
+        return Err(ApiError::BadRequest(
+            "days must be between 1 and 366".into(),
+        ));
+    }
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let points = token_stats_trend(&supabase_client, days).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "days": days, "snapshots": points })),
+    ))
+}
+
+/// Fields of an `/audience/estimate` body beside its [`AudienceSelector`].
+#[derive(Debug, Default, Deserialize)]
+struct EstimateTarget {
+    topic: Option<String>,
+    /// The category the send would carry, for the opt-out check.
+    category: Option<String>,
+}
+
+/// Estimates the audience a send would reach: the body takes an audience
+/// selector or a `topic`, the query string the filters `/scheduled` does.
+async fn audience_estimate(
+    State(state): State<AppState>,
+    Query(params): Query<Vec<(String, String)>>,
+    JsonBody(mut body): JsonBody<Value>,
+) -> ApiResult {
+    let mut target = Map::new();
+    if let Some(fields) = body.as_object_mut() {
+        for name in ["topic", "category"] {
+            if let Some(value) = fields.remove(name) {
+                target.insert(name.into(), value);
+            }
+        }
+    }
+    let selector = from_json::<AudienceSelector>(&body, "")?;
+    let target = from_json::<EstimateTarget>(&Value::Object(target), "")?;
+    let mut query = audience_from_query_params(&params)?;
+    if let Some(topic) = target.topic {
+        validate_topic(&topic)?;
+        query.topic = Some(topic);
+    }
+    let category = validate_category(target.category.as_deref())?;
+    let estimate =
+        estimate_audience(&state.secrets, &selector, &query, category.as_deref()).await?;
+    Ok((StatusCode::OK, Json(estimate)))
+}
+
+async fn audience_snapshot(
+    State(state): State<AppState>,
+    JsonBody(body): JsonBody<Value>,
+) -> ApiResult {
+    let selector = from_json::<AudienceSelector>(&body, "")?;
+    let snapshot = create_audience_snapshot(&state.secrets, &selector).await?;
+    Ok((StatusCode::CREATED, Json(snapshot)))
+}
+
+async fn job_status(State(state): State<AppState>, Path(job_id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let job = load_job_status(&supabase_client, &job_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Job not found".into()))?;
+    Ok((StatusCode::OK, Json(json!(job))))
+}
+
+async fn batch_status(State(state): State<AppState>, Path(batch_id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let batch = load_batch(&supabase_client, &batch_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Batch not found".into()))?;
+    Ok((StatusCode::OK, Json(batch)))
+}
+
+async fn abort(State(state): State<AppState>, Path(job_id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    if !abort_job(&supabase_client, &job_id).await? {
+        return Err(ApiError::NotFound("Job not found".into()));
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "job_id": job_id, "status": "aborted" })),
+    ))
+}
+
+async fn subscribe_topic(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+    JsonBody(request): JsonBody<TopicSubscriptionRequest>,
+) -> ApiResult {
+    validate_topic(&topic)?;
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let created = subscribe(&supabase_client, &topic, &request.expo_push_token).await?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(json!({ "topic": topic, "subscribed": true }))))
+}
+
+async fn unsubscribe_topic(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+    JsonBody(request): JsonBody<TopicSubscriptionRequest>,
+) -> ApiResult {
+    validate_topic(&topic)?;
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let removed =
+        unsubscribe_from_topic(&supabase_client, &topic, &request.expo_push_token).await?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "topic": topic, "unsubscribed": removed })),
+    ))
+}
+
+async fn cancel(State(state): State<AppState>, Path(id): Path<String>) -> ApiResult {
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let cancelled = cancel_scheduled(&supabase_client, &id).await?;
+    Ok((StatusCode::OK, Json(cancelled)))
+}
+
+/// Sends a recorded notification again, to all or some of its recipients.
+/// The content comes from `notification_contents`, so this only works for
+/// content stored under `LOG_PRIVACY_LEVEL=full`.
+async fn resend(
+    State(state): State<AppState>,
+    Path(history_id): Path<String>,
+    Deadline(deadline): Deadline,
+    JsonBody(request): JsonBody<ResendRequest>,
+) -> ApiResult {
+    let timings = Timings::start();
+    let supabase_client = initialize_supabase_client(&state.secrets)?;
+    let entry = load_history(&supabase_client, &history_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("History entry not found".into()))?;
+    let (title, body) = load_content(&supabase_client, &entry.content_hash)
+        .await?
+        .ok_or_else(|| {
+            ApiError::BadRequest("The content of this notification was not retained".into())
+        })?;
+    info!(history_id = %history_id, only_failed = request.only_failed, "Resending notification");
+
+    let broadcast = Broadcast {
+        title,
+        body,
+        tokens: request.recipients(&entry),
+        spread_over_minutes: None,
+        sound: entry.sound,
+        badge: None,
+        priority: None,
+        ttl: None,
+        channel_id: None,
+        // Same key, so the resend replaces the original where it arrived.
+        collapse_key: entry.collapse_key,
+        category: entry.category,
+        variables: Map::new(),
+        vars: HashMap::new(),
+        platform_variants: None,
+        locales: None,
+        data: None,
+        dry_run: false,
+    };
+    send_broadcast(&state, broadcast, None, deadline, timings).await
+}
+
+/// Adapts [`app`] to the request and response types `lambda_http::run`
+/// expects, routing on the path the client called (without the API Gateway
+/// stage) inside the caller's W3C trace context.
+#[derive(Clone)]
+pub struct LambdaRouter {
+    router: Router,
+}
+
+impl LambdaRouter {
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+}
+
+impl Service<Request> for LambdaRouter {
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        Box::pin(dispatch(self.router.clone(), request))
+    }
+}
+
+#[instrument(skip(router, request), fields(trace_id = tracing::field::Empty))]
+async fn dispatch(router: Router, mut request: Request) -> Result<Response<Body>, Error> {
+    let trace_context = TraceContext::from_headers(request.headers());
+    if let Some(context) = &trace_context {
+        Span::current().record("trace_id", context.trace_id());
+    }
+
+    // `raw_http_path` is empty for requests from `serve_local`.
+    let path = match request.raw_http_path() {
+        "" => request.uri().path().to_string(),
+        path => path.to_string(),
+    };
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+    *request.uri_mut() = path_and_query.parse()?;
+
+    let flags = features::resolve(request.headers(), request.extensions().get::<Caller>()).await;
+    let response = match trace_context::scope(
+        trace_context,
+        features::scope(flags, router.oneshot(request)),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    let (parts, body) = response.into_parts();
+    let bytes = to_bytes(body, usize::MAX).await?;
+    let body = match String::from_utf8(bytes.to_vec()) {
+        Ok(text) if text.is_empty() => Body::Empty,
+        Ok(text) => Body::Text(text),
+        Err(e) => Body::Binary(e.into_bytes()),
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+/// An EventBridge event, e.g. from a cron rule for the monthly reminder.
+/// `detail` is a `POST /send` body without recipients, such as
+/// `{"template_id": "monthly-reminder"}`, sent to every active token; the
+/// empty detail of a plain `Scheduled Event` sends the configured message,
+/// as `/scheduled` does.
+#[derive(Debug, Deserialize)]
+pub struct ScheduledEvent {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(rename = "detail-type")]
+    pub detail_type: String,
+    pub source: String,
+    #[serde(default)]
+    pub detail: Value,
+}
+
+/// Sends the broadcast a [`ScheduledEvent`] asks for. The event id is the
+/// `job_id`, so a retried invocation resumes rather than sending twice.
+#[instrument(skip(state, event), fields(event_id = ?event.id, source = %event.source))]
+pub async fn handle_scheduled_event(
+    state: &AppState,
+    event: ScheduledEvent,
+    deadline: Option<SystemTime>,
+) -> Result<Value, ApiError> {
+    let broadcast = match &event.detail {
+        Value::Null => scheduled_broadcast(state, &AudienceQuery::default()).await?,
+        Value::Object(detail) if detail.is_empty() => {
+            scheduled_broadcast(state, &AudienceQuery::default()).await?
+        }
+        detail => {
+            reject_unknown_fields(detail, "detail.", &[CONTENT_FIELDS])?;
+            let request = from_json::<SendRequest>(detail, "detail.")?;
+            let content = broadcast_content(state, &request).await?;
+            let tokens = token_store(&state.secrets).await?.fetch_tokens().await?;
+            Broadcast { tokens, ..content }
+        }
+    };
+    let (status, Json(response)) =
+        send_broadcast(state, broadcast, event.id, deadline, Timings::start()).await?;
+    info!(status = status.as_u16(), "Handled scheduled event");
+    Ok(response)
+}
+
+/// Runs `service` (normally [`crate::middleware::stack`] around a
+/// [`LambdaRouter`]) on the Lambda runtime. The same function also handles
+/// the Secrets Manager rotation events for its own API key, dispatched to
+/// [`handle_rotation`], and EventBridge events, which send with `state` via
+/// [`handle_scheduled_event`] without going through the API key check.
+pub async fn run_lambda<S>(state: AppState, service: S) -> Result<(), Error>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    lambda_runtime::run(service_fn(move |event: LambdaEvent<Box<RawValue>>| {
+        let service = service.clone();
+        let state = state.clone();
+        async move {
+            let LambdaEvent { payload, context } = event;
+            if let Ok(rotation) = serde_json::from_str::<RotationEvent>(payload.get()) {
+                handle_rotation(rotation).await?;
+                return Ok::<_, Error>(Value::Null);
+            }
+            if let Ok(scheduled) = serde_json::from_str::<ScheduledEvent>(payload.get()) {
+                let deadline = Some(context.deadline());
+                return Ok(handle_scheduled_event(&state, scheduled, deadline).await?);
+            }
+            let request = serde_json::from_str::<LambdaRequest>(payload.get())?;
+            let response = Adapter::from(service)
+                .oneshot(LambdaEvent::new(request, context))
+                .await?;
+            Ok(serde_json::to_value(response)?)
+        }
+    }))
+    .await
+}
+
+/// Serves `service` (normally [`crate::middleware::stack`] around a
+/// [`LambdaRouter`]) on `addr`, so the API can be exercised with curl
+/// without `cargo lambda watch`.
+pub async fn serve_local<S>(addr: &str, service: S) -> Result<(), Error>
+where
+    S: Service<Request, Response = Response<Body>, Error = Error> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(addr, "Serving the API locally");
+
+    let bridge = Router::new().fallback(move |request: axum::extract::Request| {
+        let service = service.clone();
+        async move {
+            let (parts, body) = request.into_parts();
+            let bytes = match to_bytes(body, usize::MAX).await {
+                Ok(bytes) => bytes,
+                Err(_) => return ApiError::InvalidBody.into_response(),
+            };
+            let request = Request::from_parts(parts, Body::from(bytes.to_vec()));
+            match service.oneshot(request).await {
+                Ok(response) => response.map(axum::body::Body::new).into_response(),
+                Err(e) => {
+                    error!(error = %e, "Request failed");
+                    let status = StatusCode::INTERNAL_SERVER_ERROR;
+                    let body = problem(status, "internal_error", "Internal error");
+                    (status, [(CONTENT_TYPE, PROBLEM_CONTENT_TYPE)], Json(body)).into_response()
+                }
+            }
+        }
+    });
+    axum::serve(listener, bridge).await?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Notifications sent later: a `POST /send` with `send_at` is stored in the
+//! `scheduled_notifications` table instead of being sent, and
+//! `/scheduled/dispatch`, invoked every minute by an EventBridge schedule,
+//! sends the ones that have come due.
+
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+/// Parses `send_at`, an ISO-8601 (RFC 3339) timestamp with an offset, e.g.
+/// `2026-05-01T09:00:00+09:00`.
+pub fn parse_send_at(send_at: &str) -> Result<DateTime<Utc>, ApiError> {
+    DateTime::parse_from_rfc3339(send_at)
+        .map(|send_at| send_at.with_timezone(&Utc))
+        .map_err(|_| {
+            ApiError::BadRequest(
+                "send_at must be an ISO-8601 timestamp with an offset, e.g. 2026-05-01T09:00:00Z"
+                    .into(),
+            )
+        })
+}
+
+/// Stores a request to be replayed on `route` at `send_at` and returns its
+/// id. `body` must not carry `send_at` itself, or dispatching it would
+/// schedule it again.
+#[instrument(skip(client, body))]
+pub async fn create_scheduled(
+    client: &SupabaseClient,
+    route: &str,
+    body: &Value,
+    send_at: DateTime<Utc>,
+) -> Result<String, ApiError> {
+    let id = Uuid::new_v4().to_string();
+    client
+        .insert(
+            "scheduled_notifications",
+            json!({
+                "id": id,
+                "route": route,
+                "body": body,
+                "send_at": send_at.to_rfc3339(),
+                "status": "pending",
+                "created_at": Utc::now().to_rfc3339(),
+            }),
+        )
+        .timed("insert scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error scheduling notification");
+            ApiError::SupabaseWrite
+        })?;
+    info!(scheduled_id = %id, send_at = %send_at.to_rfc3339(), "Scheduled notification");
+    Ok(id)
+}
+
+/// Stores one scheduled request per `(body, send_at)` in `slots`, all under
+/// `job_id`. Used to pace a broadcast: each slot holds one chunk of its
+/// recipients, and is sent under its own id like any scheduled request.
+#[instrument(skip(client, slots), fields(slot_count = slots.len()))]
+pub async fn create_paced_slots(
+    client: &SupabaseClient,
+    job_id: &str,
+    route: &str,
+    slots: Vec<(Value, DateTime<Utc>)>,
+) -> Result<(), ApiError> {
+    let created_at = Utc::now().to_rfc3339();
+    let slot_count = slots.len();
+    let rows = slots
+        .into_iter()
+        .map(|(body, send_at)| {
+            json!({
+                "id": Uuid::new_v4().to_string(),
+                "job_id": job_id,
+                "route": route,
+                "body": body,
+                "send_at": send_at.to_rfc3339(),
+                "status": "pending",
+                "created_at": created_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    client
+        .bulk_insert("scheduled_notifications", rows)
+        .timed("insert scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error scheduling paced broadcast");
+            ApiError::SupabaseWrite
+        })?;
+    info!(job_id = %job_id, slot_count, "Scheduled paced broadcast");
+    Ok(())
+}
+
+/// Whether slots were already scheduled under `job_id`, so a redelivered
+/// request does not schedule its broadcast twice.
+#[instrument(skip(client))]
+pub async fn has_paced_slots(client: &SupabaseClient, job_id: &str) -> Result<bool, ApiError> {
+    let rows = client
+        .select("scheduled_notifications")
+        .columns(vec!["id"])
+        .eq("job_id", job_id)
+        .limit(1)
+        .execute()
+        .timed("select scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching paced broadcast slots");
+            ApiError::SupabaseFetch
+        })?;
+    Ok(!rows.is_empty())
+}
+
+/// Pending notifications whose `send_at` has passed, earliest first.
+#[instrument(skip(client))]
+pub async fn due_scheduled(client: &SupabaseClient) -> Result<Vec<Value>, ApiError> {
+    let mut rows = client
+        .select("scheduled_notifications")
+        .eq("status", "pending")
+        .lte("send_at", &Utc::now().to_rfc3339())
+        .order("send_at", true)
+        .execute()
+        .timed("select scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching due scheduled notifications");
+            ApiError::SupabaseFetch
+        })?;
+    rows.retain(|row| row["id"].is_string() && row["route"].is_string());
+    Ok(rows)
+}
+
+/// Records that a scheduled notification was sent (`sent`) or rejected for
+/// good (`failed`), so it is not dispatched again.
+#[instrument(skip(client))]
+pub async fn finish_scheduled(
+    client: &SupabaseClient,
+    id: &str,
+    status: &str,
+) -> Result<(), ApiError> {
+    client
+        .update(
+            "scheduled_notifications",
+            id,
+            json!({ "status": status, "dispatched_at": Utc::now().to_rfc3339() }),
+        )
+        .timed("update scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error updating scheduled notification");
+            ApiError::SupabaseWrite
+        })?;
+    Ok(())
+}
+
+/// Cancels a pending notification. `NotFound` for an unknown id, `Conflict`
+/// once it has been dispatched or cancelled.
+#[instrument(skip(client))]
+pub async fn cancel_scheduled(client: &SupabaseClient, id: &str) -> Result<Value, ApiError> {
+    let rows = client
+        .select("scheduled_notifications")
+        .columns(vec!["id", "status", "send_at"])
+        .eq("id", id)
+        .execute()
+        .timed("select scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching scheduled notification");
+            ApiError::SupabaseFetch
+        })?;
+    let row = rows
+        .into_iter()
+        .next()
+        .ok_or_else(|| ApiError::NotFound("Scheduled notification not found".into()))?;
+    if row["status"] != "pending" {
+        return Err(ApiError::Conflict(format!(
+            "Scheduled notification is already {}",
+            row["status"].as_str().unwrap_or("dispatched")
+        )));
+    }
+
+    client
+        .update(
+            "scheduled_notifications",
+            id,
+            json!({ "status": "cancelled", "cancelled_at": Utc::now().to_rfc3339() }),
+        )
+        .timed("update scheduled_notifications")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error cancelling scheduled notification");
+            ApiError::SupabaseWrite
+        })?;
+    info!("Cancelled scheduled notification");
+    Ok(json!({ "id": id, "status": "cancelled", "send_at": row["send_at"] }))
+}
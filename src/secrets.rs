@@ -0,0 +1,153 @@
+//! Credentials (the Supabase URL and key, the Expo access token and the API
+//! key), loaded once per container at cold start:
+//!
+//! 1. the parameters under `SSM_PARAMETER_PATH`, keyed by their last path
+//!    segment, e.g. `/expo-push-api/supabase-key`;
+//! 2. the JSON object in the Secrets Manager secret `SECRETS_SECRET_ID`,
+//!    with the same keys, which wins over SSM;
+//! 3. for local development, environment variables such as `SUPABASE_KEY`
+//!    fill in whatever neither store has.
+
+use crate::http_client::aws_sdk_config;
+use crate::http_handler::ApiError;
+use crate::rotation::secrets_manager;
+use crate::token_store::is_supabase_store;
+use aws_sdk_ssm::Client as SsmClient;
+use std::collections::HashMap;
+use std::env;
+use tokio::sync::OnceCell;
+use tracing::{error, info, instrument};
+
+/// Secrets that can also come from the environment, as the upper snake case
+/// of their name.
+const ENV_FALLBACKS: [&str; 4] = [
+    "supabase-url",
+    "supabase-key",
+    "expo-access-token",
+    "api-key",
+];
+
+static SECRETS: OnceCell<HashMap<String, String>> = OnceCell::const_new();
+
+/// `supabase-key` is read from `SUPABASE_KEY`.
+fn env_name(secret: &str) -> String {
+    secret.to_uppercase().replace('-', "_")
+}
+
+/// The secrets, fetched on first use and reused by warm invocations.
+/// Fails only when a configured store cannot be read; a secret that is
+/// missing everywhere surfaces later as [`ApiError::MissingSecret`].
+#[instrument]
+pub async fn get_secrets() -> Result<HashMap<String, String>, ApiError> {
+    SECRETS
+        .get_or_try_init(|| async {
+            let mut secrets = match env::var("SSM_PARAMETER_PATH") {
+                Ok(path) => {
+                    let secrets = fetch_parameters_by_path(&path).await?;
+                    info!("Successfully fetched secrets from SSM");
+                    secrets
+                }
+                Err(_) => HashMap::new(),
+            };
+            if let Ok(secret_id) = env::var("SECRETS_SECRET_ID") {
+                secrets.extend(fetch_secret_json(&secret_id).await?);
+                info!("Successfully fetched secrets from Secrets Manager");
+            }
+            for name in ENV_FALLBACKS {
+                if secrets.contains_key(name) {
+                    continue;
+                }
+                if let Ok(value) = env::var(env_name(name)) {
+                    info!(secret = name, "Using secret from the environment");
+                    secrets.insert(name.to_string(), value);
+                }
+            }
+            Ok(secrets)
+        })
+        .await
+        .cloned()
+}
+
+/// Everything the service cannot run without that `secrets` and the
+/// environment leave unset, described by where it can be set. Supabase is
+/// only required as the token store (`TOKEN_STORE` unset or `supabase`).
+pub fn missing_configuration(secrets: &HashMap<String, String>) -> Vec<String> {
+    let mut required = vec!["expo-access-token"];
+    if is_supabase_store() {
+        required.extend(["supabase-url", "supabase-key"]);
+    }
+    let mut missing = required
+        .into_iter()
+        .filter(|name| !secrets.contains_key(*name))
+        .map(|name| format!("{name} (secret or {})", env_name(name)))
+        .collect::<Vec<_>>();
+    let has_api_key = secrets.contains_key("api-key")
+        || ["API_KEY_SECRET_ID", "API_KEYS"]
+            .iter()
+            .any(|var| env::var(var).is_ok_and(|value| !value.is_empty()));
+    if !has_api_key {
+        missing.push("api-key (secret, API_KEY, API_KEY_SECRET_ID or API_KEYS)".into());
+    }
+    missing
+}
+
+/// The secrets if [`get_secrets`] has loaded them, without loading them,
+/// e.g. in offline mode where no store is read.
+pub fn loaded_secrets() -> Option<&'static HashMap<String, String>> {
+    SECRETS.get()
+}
+
+/// Fetches every parameter directly under `path`, keyed by the last path
+/// segment.
+pub async fn fetch_parameters_by_path(path: &str) -> Result<HashMap<String, String>, ApiError> {
+    let config = aws_sdk_config().await;
+    let ssm_client = SsmClient::new(config);
+
+    info!(ssm_parameter_path = %path, "Fetching parameters from SSM");
+
+    let mut parameters = HashMap::new();
+
+    // SSM returns at most 10 parameters per call, so follow `next_token`.
+    let mut pages = ssm_client
+        .get_parameters_by_path()
+        .path(path)
+        .with_decryption(true)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let response = page.map_err(|e| {
+            error!(error = ?e, "Failed to get parameters from SSM");
+            ApiError::SsmError
+        })?;
+        for param in response.parameters.unwrap_or_default() {
+            if let (Some(name), Some(value)) = (param.name, param.value) {
+                info!(parameter_name = %name, "Fetched parameter from SSM");
+                // Extract only the key name from the path (e.g., /expo-push-api/supabase-key -> supabase-key)
+                if let Some(key) = name.split('/').next_back() {
+                    parameters.insert(key.to_string(), value);
+                }
+            }
+        }
+    }
+
+    Ok(parameters)
+}
+
+/// Reads a Secrets Manager secret holding a JSON object of strings.
+#[instrument]
+async fn fetch_secret_json(secret_id: &str) -> Result<HashMap<String, String>, ApiError> {
+    let secret = secrets_manager()
+        .await
+        .get_secret_value()
+        .secret_id(secret_id)
+        .send()
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Failed to get secret from Secrets Manager");
+            ApiError::SecretsManager("get_secret_value failed".into())
+        })?;
+    serde_json::from_str(secret.secret_string().unwrap_or_default()).map_err(|e| {
+        error!(error = %e, "Secret is not a JSON object of strings");
+        ApiError::SecretsManager("secret is not a JSON object of strings".into())
+    })
+}
@@ -0,0 +1,248 @@
+use crate::build_info::build_info;
+use crate::expo_retry::send_with_retry;
+use crate::http_handler::{initialize_supabase_client, ApiError};
+use crate::metrics::Timed;
+use crate::router::AppState;
+use crate::templates::render;
+use expo_push_notification_client::{
+    ExpoPushMessage, ExpoPushReceipt, ExpoPushReceiptId, ExpoPushTicket, Priority,
+};
+use serde::Serialize;
+use serde_json::{json, Map};
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{info, instrument, warn};
+
+const DEFAULT_RECEIPT_WAIT_SECS: u64 = 10;
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StageStatus {
+    Pass,
+    Fail,
+    /// Not run because an earlier stage failed.
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub status: StageStatus,
+    pub detail: String,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelftestReport {
+    pub passed: bool,
+    pub tokens: usize,
+    pub stages: Vec<StageReport>,
+}
+
+/// Devices the self-test may notify, from `SELFTEST_TOKENS`
+/// (comma-separated). Real users are never contacted.
+fn selftest_tokens() -> Vec<String> {
+    env::var("SELFTEST_TOKENS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn receipt_wait() -> Duration {
+    env::var("SELFTEST_RECEIPT_WAIT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(DEFAULT_RECEIPT_WAIT_SECS))
+}
+
+struct Stages {
+    reports: Vec<StageReport>,
+    started: Instant,
+}
+
+impl Stages {
+    fn finish(&mut self, stage: &'static str, result: Result<String, String>) -> bool {
+        let (status, detail) = match result {
+            Ok(detail) => (StageStatus::Pass, detail),
+            Err(detail) => (StageStatus::Fail, detail),
+        };
+        self.reports.push(StageReport {
+            stage,
+            status,
+            detail,
+            duration_ms: self.started.elapsed().as_millis(),
+        });
+        self.started = Instant::now();
+        status == StageStatus::Pass
+    }
+
+    fn skip(&mut self, remaining: &[&'static str]) {
+        self.reports
+            .extend(remaining.iter().map(|&stage| StageReport {
+                stage,
+                status: StageStatus::Skipped,
+                detail: "An earlier stage failed".into(),
+                duration_ms: 0,
+            }));
+    }
+}
+
+/// Post-deploy check of the whole pipeline against the `SELFTEST_TOKENS`
+/// devices: looks them up in the token store, renders and sends a
+/// normal-priority notification, and polls Expo for the receipts for up to
+/// `SELFTEST_RECEIPT_WAIT_SECS` (default 10). Stops at the first failing
+/// stage.
+#[instrument(skip(state))]
+pub async fn run_selftest(state: &AppState) -> Result<SelftestReport, ApiError> {
+    let tokens = selftest_tokens();
+    if tokens.is_empty() {
+        return Err(ApiError::BadRequest(
+            "SELFTEST_TOKENS is not configured".into(),
+        ));
+    }
+    let mut stages = Stages {
+        reports: vec![],
+        started: Instant::now(),
+    };
+
+    let fetched = match initialize_supabase_client(&state.secrets) {
+        Ok(client) => client
+            .select("users")
+            .columns(vec!["expo_push_token"])
+            .in_("expo_push_token", &tokens)
+            .execute()
+            .timed("select users")
+            .await
+            .map(|rows| format!("{} of {} test tokens registered", rows.len(), tokens.len()))
+            .map_err(|e| format!("Token store query failed: {e:?}")),
+        Err(e) => Err(e.to_string()),
+    };
+    if !stages.finish("fetch", fetched) {
+        stages.skip(&["render", "send", "receipt"]);
+        return Ok(report(tokens.len(), stages));
+    }
+
+    let vars = Map::from_iter([("version".to_string(), json!(build_info().version))]);
+    let messages = render("Self-test {{version}}", &vars)
+        .map_err(|e| e.to_string())
+        .and_then(|title| {
+            tokens
+                .iter()
+                .map(|token| {
+                    ExpoPushMessage::builder(vec![token.clone()])
+                        .title(title.clone())
+                        .priority(Priority::Normal)
+                        .build()
+                        .map_err(|e| format!("{token}: {e}"))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        });
+    let messages = match messages {
+        Ok(messages) => {
+            stages.finish(
+                "render",
+                Ok(format!("Rendered {} messages", messages.len())),
+            );
+            messages
+        }
+        Err(e) => {
+            stages.finish("render", Err(e));
+            stages.skip(&["send", "receipt"]);
+            return Ok(report(tokens.len(), stages));
+        }
+    };
+
+    let ticket_ids = match send_with_retry(&state.secrets, &messages, None).await {
+        Ok(tickets) => {
+            let errors = tickets
+                .iter()
+                .filter_map(|ticket| match ticket {
+                    ExpoPushTicket::Error(e) => Some(e.message.clone()),
+                    ExpoPushTicket::Ok(_) => None,
+                })
+                .collect::<Vec<_>>();
+            if errors.is_empty() {
+                Ok(tickets
+                    .into_iter()
+                    .filter_map(|ticket| match ticket {
+                        ExpoPushTicket::Ok(ticket) => Some(ticket.id),
+                        ExpoPushTicket::Error(_) => None,
+                    })
+                    .collect::<Vec<ExpoPushReceiptId>>())
+            } else {
+                Err(format!("Expo rejected messages: {}", errors.join("; ")))
+            }
+        }
+        Err(e) => Err(format!("Expo request failed: {e}")),
+    };
+    let ticket_ids = match ticket_ids {
+        Ok(ids) => {
+            stages.finish("send", Ok(format!("Expo accepted {} messages", ids.len())));
+            ids
+        }
+        Err(e) => {
+            stages.finish("send", Err(e));
+            stages.skip(&["receipt"]);
+            return Ok(report(tokens.len(), stages));
+        }
+    };
+
+    let deadline = Instant::now() + receipt_wait();
+    let receipts = loop {
+        sleep(RECEIPT_POLL_INTERVAL).await;
+        match state
+            .expo
+            .get_push_notification_receipts(ticket_ids.clone())
+            .await
+        {
+            Ok(receipts) if receipts.len() == ticket_ids.len() => break Ok(receipts),
+            Ok(_) if Instant::now() < deadline => continue,
+            Ok(receipts) => {
+                break Err(format!(
+                    "Only {} of {} receipts available in time",
+                    receipts.len(),
+                    ticket_ids.len()
+                ))
+            }
+            Err(e) if Instant::now() < deadline => {
+                warn!(error = %e, "Receipt poll failed, retrying");
+            }
+            Err(e) => break Err(format!("Receipt request failed: {e}")),
+        }
+    };
+    let receipt_result = receipts.and_then(|receipts| {
+        let errors = receipts
+            .values()
+            .filter_map(|receipt| match receipt {
+                ExpoPushReceipt::Error(e) => Some(e.message.clone()),
+                ExpoPushReceipt::Ok => None,
+            })
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(format!("{} receipts ok", receipts.len()))
+        } else {
+            Err(format!("Delivery failed: {}", errors.join("; ")))
+        }
+    });
+    stages.finish("receipt", receipt_result);
+
+    Ok(report(tokens.len(), stages))
+}
+
+fn report(tokens: usize, stages: Stages) -> SelftestReport {
+    let passed = stages
+        .reports
+        .iter()
+        .all(|stage| stage.status == StageStatus::Pass);
+    info!(passed, "Finished self-test");
+    SelftestReport {
+        passed,
+        tokens,
+        stages: stages.reports,
+    }
+}
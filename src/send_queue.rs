@@ -0,0 +1,160 @@
+//! Asynchronous sends. With `?async=true` a send request is put on the SQS
+//! queue at `SEND_QUEUE_URL` and answered with `202` and a batch id right
+//! away, so a large broadcast does not run into API Gateway's 29 second
+//! limit. The `send_worker` binary consumes the queue (see
+//! [`crate::router::consume_send_queue`]) and performs the sends.
+
+use crate::api_keys::{current_caller, current_tenant};
+use crate::audience::audience_from_query_params;
+use crate::batches::{set_batch_state, BatchState};
+use crate::http_client::aws_sdk_config;
+use crate::http_handler::{initialize_supabase_client, ApiError};
+use crate::models::{from_json, SendRequest};
+use crate::router::{decode_body, dry_run_requested};
+use crate::secrets::loaded_secrets;
+use aws_sdk_sqs::Client as SqsClient;
+use axum::body::to_bytes;
+use axum::extract::{Query, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use tokio::sync::OnceCell;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+static SQS: OnceCell<SqsClient> = OnceCell::const_new();
+
+/// A send request waiting on the queue for the worker.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuedSend {
+    /// Returned to the caller; the worker sends under it as the `job_id`,
+    /// so progress shows on `GET /batches/{id}` and `GET /jobs/{id}` and a
+    /// redelivered message resumes from the last completed chunk.
+    pub batch_id: String,
+    /// Route the request was sent to, e.g. `/send` or `/scheduled`.
+    pub route: String,
+    pub body: Value,
+    pub enqueued_at: String,
+    /// The key and tenant that enqueued it, so the worker sends on their
+    /// behalf, e.g. to the tenant's webhooks.
+    #[serde(default)]
+    pub caller: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+async fn sqs() -> &'static SqsClient {
+    SQS.get_or_init(|| async {
+        let config = aws_sdk_config().await;
+        SqsClient::new(config)
+    })
+    .await
+}
+
+/// Puts a request on the send queue and returns its batch id.
+#[instrument(skip(body))]
+pub async fn enqueue(route: &str, body: &Value) -> Result<String, ApiError> {
+    let queue_url =
+        env::var("SEND_QUEUE_URL").map_err(|_| ApiError::MissingEnvVar("SEND_QUEUE_URL".into()))?;
+    let message = QueuedSend {
+        batch_id: Uuid::new_v4().to_string(),
+        route: route.to_string(),
+        body: body.clone(),
+        enqueued_at: Utc::now().to_rfc3339(),
+        caller: current_caller(),
+        tenant: current_tenant(),
+    };
+    let message_body = serde_json::to_string(&message)
+        .map_err(|_| ApiError::SendQueue("serialize failed".into()))?;
+    // Before the message, so the worker never finds the batch missing.
+    // Best effort: the send matters more than its status page.
+    if let Some(Ok(client)) = loaded_secrets().map(initialize_supabase_client) {
+        if let Err(e) =
+            set_batch_state(&client, &message.batch_id, BatchState::Queued, Some(route)).await
+        {
+            warn!(error = %e, "Failed to record queued batch");
+        }
+    }
+    sqs()
+        .await
+        .send_message()
+        .queue_url(queue_url)
+        .message_body(message_body)
+        .send()
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Failed to enqueue send request");
+            ApiError::SendQueue("send_message failed".into())
+        })?;
+    info!(batch_id = %message.batch_id, "Enqueued send request");
+    Ok(message.batch_id)
+}
+
+fn async_requested(request: &Request) -> bool {
+    request.uri().query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|pair| matches!(pair, "async=true" | "async=1"))
+    })
+}
+
+/// Route layer for the send endpoints. With `?async=true` the request is
+/// validated as far as that is possible without Supabase, enqueued with
+/// [`enqueue`] and answered with `202`; otherwise, and for dry runs, it
+/// passes through untouched.
+///
+/// Use with [`axum::middleware::from_fn`].
+pub async fn enqueue_async_request(request: Request, next: Next) -> Response {
+    if !async_requested(&request) || dry_run_requested(request.uri()) {
+        return next.run(request).await;
+    }
+    // With the query string, which holds the audience of a filtered
+    // broadcast.
+    let uri = request.uri().clone();
+    let route = uri
+        .path_and_query()
+        .map_or_else(|| uri.path().to_string(), |route| route.to_string());
+    let Ok(bytes) = to_bytes(request.into_body(), usize::MAX).await else {
+        return ApiError::InvalidBody.into_response();
+    };
+    let body = if bytes.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice(&decode_body(&bytes)) {
+            Ok(body) => body,
+            Err(_) => return ApiError::InvalidBody.into_response(),
+        }
+    };
+    match uri.path() {
+        "/" | "/send" => {
+            if let Err(e) = from_json::<SendRequest>(&body, "") {
+                return e.into_response();
+            }
+        }
+        "/broadcast" | "/scheduled" => {
+            let audience = Query::<Vec<(String, String)>>::try_from_uri(&uri)
+                .map_err(|e| ApiError::BadRequest(e.body_text()))
+                .and_then(|Query(params)| audience_from_query_params(&params));
+            if let Err(e) = audience {
+                return e.into_response();
+            }
+        }
+        _ => {}
+    }
+    match enqueue(&route, &body).await {
+        Ok(batch_id) => (
+            StatusCode::ACCEPTED,
+            Json(json!({
+                "message": "Queued for asynchronous sending",
+                "batch_id": batch_id,
+            })),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
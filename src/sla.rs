@@ -0,0 +1,82 @@
+use crate::metrics::record_delivery_latency;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use tracing::error;
+
+const DEFAULT_SLA_TARGET_SECS: f64 = 120.0;
+const DEFAULT_SLA_TARGET_RATIO: f64 = 0.95;
+
+/// Delivery latency of one category, from request acceptance to receipt
+/// resolution.
+#[derive(Debug, Serialize)]
+pub struct SlaReport {
+    pub count: usize,
+    pub p50_secs: f64,
+    pub p95_secs: f64,
+    /// Share of messages resolved within `SLA_TARGET_SECS`.
+    pub within_target: f64,
+    pub breached: bool,
+}
+
+/// Nearest-rank percentile of `sorted`, which must not be empty.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn env_f64(name: &str, default: f64) -> f64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Computes p50 / p95 per category and checks them against the SLA: at
+/// least `SLA_TARGET_RATIO` (default 0.95) of messages resolved within
+/// `SLA_TARGET_SECS` (default 120).
+pub fn sla_reports(latencies: HashMap<String, Vec<f64>>) -> BTreeMap<String, SlaReport> {
+    let target_secs = env_f64("SLA_TARGET_SECS", DEFAULT_SLA_TARGET_SECS);
+    let target_ratio = env_f64("SLA_TARGET_RATIO", DEFAULT_SLA_TARGET_RATIO);
+
+    latencies
+        .into_iter()
+        .filter(|(_, latencies)| !latencies.is_empty())
+        .map(|(category, mut latencies)| {
+            latencies.sort_by(f64::total_cmp);
+            let within = latencies.iter().filter(|&&l| l <= target_secs).count();
+            let report = SlaReport {
+                count: latencies.len(),
+                p50_secs: percentile(&latencies, 50.0),
+                p95_secs: percentile(&latencies, 95.0),
+                within_target: within as f64 / latencies.len() as f64,
+                breached: (within as f64 / latencies.len() as f64) < target_ratio,
+            };
+            (category, report)
+        })
+        .collect()
+}
+
+/// [`sla_reports`], with every category emitted as a metric and a breach
+/// logged as an `sla_breached` event so it can back an alarm.
+pub fn evaluate_sla(latencies: HashMap<String, Vec<f64>>) -> BTreeMap<String, SlaReport> {
+    let target_secs = env_f64("SLA_TARGET_SECS", DEFAULT_SLA_TARGET_SECS);
+    let target_ratio = env_f64("SLA_TARGET_RATIO", DEFAULT_SLA_TARGET_RATIO);
+
+    let reports = sla_reports(latencies);
+    for (category, report) in &reports {
+        record_delivery_latency(category, report);
+        if report.breached {
+            error!(
+                event = "sla_breached",
+                category = %category,
+                within_target = report.within_target,
+                target_ratio,
+                target_secs,
+                p95_secs = report.p95_secs,
+                "Delivery SLA breached"
+            );
+        }
+    }
+    reports
+}
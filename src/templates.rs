@@ -0,0 +1,342 @@
+use crate::audience::is_valid_identifier;
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use crate::trash::is_deleted;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+
+/// Renders the `{{name}}` placeholders in `template` from `vars`. A
+/// placeholder without a value is an error rather than an empty string, so
+/// "Hi , your bill is " never reaches a device.
+pub fn render(template: &str, vars: &Map<String, Value>) -> Result<String, ApiError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder
+            .find("}}")
+            .ok_or_else(|| ApiError::BadRequest("Unclosed placeholder in template".into()))?;
+        let name = placeholder[..end].trim();
+        match vars.get(name) {
+            Some(Value::String(value)) => rendered.push_str(value),
+            Some(Value::Null) | None => {
+                return Err(ApiError::BadRequest(format!(
+                    "Missing value for placeholder {{{{{name}}}}}"
+                )))
+            }
+            Some(value) => rendered.push_str(&value.to_string()),
+        }
+        rest = &placeholder[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Placeholders of the form `{{user.<column>}}` are filled from the
+/// recipient's row in `users`.
+pub const USER_PLACEHOLDER_PREFIX: &str = "user.";
+
+/// The names of the `{{name}}` placeholders in `template`, in order.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let placeholder = &rest[start + 2..];
+        let Some(end) = placeholder.find("}}") else {
+            break;
+        };
+        names.push(placeholder[..end].trim());
+        rest = &placeholder[end + 2..];
+    }
+    names
+}
+
+/// The `users` columns the `{{user.<column>}}` placeholders in `templates`
+/// refer to, deduplicated.
+pub fn user_columns<'a>(
+    templates: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<String>, ApiError> {
+    let mut columns = vec![];
+    for name in templates.into_iter().flat_map(placeholders) {
+        let Some(column) = name.strip_prefix(USER_PLACEHOLDER_PREFIX) else {
+            continue;
+        };
+        if !is_valid_identifier(column) {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid placeholder {{{{{name}}}}}: user columns are lowercase letters, digits and underscores"
+            )));
+        }
+        if !columns.iter().any(|known| known == column) {
+            columns.push(column.to_string());
+        }
+    }
+    Ok(columns)
+}
+
+/// Recipients looked up per `users` request, which keeps the
+/// `expo_push_token=in.(...)` filter well within URL length limits.
+const RECIPIENT_LOOKUP_CHUNK: usize = 100;
+/// Lookup requests in flight at once.
+const RECIPIENT_LOOKUP_CONCURRENCY: usize = 4;
+
+/// The `columns` of the `users` rows of `tokens`, so only the recipients'
+/// rows are read rather than the whole table.
+pub async fn select_recipient_rows(
+    client: &SupabaseClient,
+    tokens: &[String],
+    columns: Vec<&str>,
+) -> Result<Vec<Value>, String> {
+    let mut rows = vec![];
+    for batch in tokens.chunks(RECIPIENT_LOOKUP_CHUNK * RECIPIENT_LOOKUP_CONCURRENCY) {
+        let lookups = batch.chunks(RECIPIENT_LOOKUP_CHUNK).map(|chunk| {
+            client
+                .select("users")
+                .columns(columns.clone())
+                .in_("expo_push_token", chunk)
+                .execute()
+                .timed("select users")
+        });
+        for page in join_all(lookups).await {
+            rows.extend(page?);
+        }
+    }
+    Ok(rows)
+}
+
+/// Values for the `{{user.<column>}}` placeholders of each token in
+/// `tokens`, read from `users`. Tokens without a row get none, so their
+/// placeholders fail to render.
+#[instrument(skip(client, tokens), fields(token_count = tokens.len()))]
+pub async fn load_user_vars(
+    client: &SupabaseClient,
+    tokens: &[String],
+    columns: &[String],
+) -> Result<HashMap<String, Map<String, Value>>, ApiError> {
+    let mut selected = vec!["expo_push_token"];
+    selected.extend(columns.iter().map(String::as_str));
+    let rows = select_recipient_rows(client, tokens, selected)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching user columns for placeholders");
+            ApiError::SupabaseFetch
+        })?;
+    let mut by_token = rows
+        .iter()
+        .filter_map(|row| Some((row["expo_push_token"].as_str()?, row)))
+        .collect::<HashMap<_, _>>();
+    let vars = tokens
+        .iter()
+        .filter_map(|token| {
+            let row = by_token.remove(token.as_str())?;
+            let values = columns
+                .iter()
+                .map(|column| {
+                    (
+                        format!("{USER_PLACEHOLDER_PREFIX}{column}"),
+                        row[column.as_str()].clone(),
+                    )
+                })
+                .collect();
+            Some((token.clone(), values))
+        })
+        .collect::<HashMap<_, _>>();
+    info!(
+        resolved = vars.len(),
+        "Loaded user columns for placeholders"
+    );
+    Ok(vars)
+}
+
+/// Platform-specific parts of a template; unset fields fall back to the
+/// template's own.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TemplateVariant {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    /// Rich notification image, shown on iOS.
+    pub image: Option<String>,
+}
+
+/// A translation of a message; unset fields fall back to the default text.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalizedText {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// A row of `notification_templates`. `platforms` maps a `users.platform`
+/// value (`ios`, `android`, ...) to its variant, `locales` a `users.locale`
+/// value (`ja`, `en-US`, ...) to its translation.
+#[derive(Debug, Deserialize)]
+pub struct Template {
+    pub id: String,
+    pub title: String,
+    pub body: String,
+    #[serde(default)]
+    pub platforms: HashMap<String, TemplateVariant>,
+    #[serde(default)]
+    pub locales: HashMap<String, LocalizedText>,
+}
+
+#[instrument(skip(client))]
+pub async fn load_template(client: &SupabaseClient, id: &str) -> Result<Template, ApiError> {
+    let rows = client
+        .select("notification_templates")
+        .eq("id", id)
+        .execute()
+        .timed("select notification_templates")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching notification template");
+            ApiError::SupabaseFetch
+        })?;
+    let row = rows
+        .into_iter()
+        .find(|row| !is_deleted(row))
+        .ok_or_else(|| ApiError::BadRequest(format!("Unknown template_id: {id}")))?;
+    serde_json::from_value(row).map_err(|e| {
+        error!(error = %e, "Malformed notification template");
+        ApiError::BadRequest(format!("Template {id} is malformed"))
+    })
+}
+
+/// A template's platform variants, and the stored platform of each
+/// recipient they are picked by.
+#[derive(Debug, Default)]
+pub struct PlatformVariants {
+    pub template_id: String,
+    pub variants: HashMap<String, TemplateVariant>,
+    pub token_platforms: HashMap<String, String>,
+}
+
+impl PlatformVariants {
+    pub fn for_token(&self, token: &str) -> Option<&TemplateVariant> {
+        self.variants.get(self.token_platforms.get(token)?)
+    }
+
+    /// Looks up the platform of every token in `tokens` in `users`. Tokens
+    /// without a row, or without a platform, get the template's defaults.
+    #[instrument(skip(self, client, tokens), fields(template_id = %self.template_id))]
+    pub async fn resolve_platforms(
+        &mut self,
+        client: &SupabaseClient,
+        tokens: &[String],
+    ) -> Result<(), ApiError> {
+        if self.variants.is_empty() {
+            return Ok(());
+        }
+        let rows = select_recipient_rows(client, tokens, vec!["expo_push_token", "platform"])
+            .await
+            .map_err(|e| {
+                error!(error = ?e, "Error fetching token platforms");
+                ApiError::SupabaseFetch
+            })?;
+        let mut platforms = rows
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    row["expo_push_token"].as_str()?,
+                    row["platform"].as_str()?.to_string(),
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+        self.token_platforms = tokens
+            .iter()
+            .filter_map(|token| Some((token.clone(), platforms.remove(token.as_str())?)))
+            .collect();
+        info!(
+            resolved = self.token_platforms.len(),
+            "Resolved recipient platforms for template"
+        );
+        Ok(())
+    }
+}
+
+/// `en_US` and `EN-us` both become `en-us`.
+fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// Translations of a message, and the stored locale of each recipient they
+/// are picked by. A recipient whose exact locale (`pt-br`) has no
+/// translation gets its language's (`pt`), and failing that the default
+/// text.
+#[derive(Debug, Default)]
+pub struct LocaleVariants {
+    pub variants: HashMap<String, LocalizedText>,
+    pub token_locales: HashMap<String, String>,
+}
+
+impl LocaleVariants {
+    pub fn new(variants: HashMap<String, LocalizedText>) -> Self {
+        Self {
+            variants: variants
+                .into_iter()
+                .map(|(locale, text)| (normalize_locale(&locale), text))
+                .collect(),
+            token_locales: HashMap::new(),
+        }
+    }
+
+    pub fn for_token(&self, token: &str) -> Option<&LocalizedText> {
+        let locale = self.token_locales.get(token)?;
+        self.variants.get(locale).or_else(|| {
+            let (language, _) = locale.split_once('-')?;
+            self.variants.get(language)
+        })
+    }
+
+    /// Looks up the locale of every token in `tokens` in `users`, grouping
+    /// the recipients by language.
+    #[instrument(skip(self, client, tokens), fields(locales = self.variants.len()))]
+    pub async fn resolve_locales(
+        &mut self,
+        client: &SupabaseClient,
+        tokens: &[String],
+    ) -> Result<(), ApiError> {
+        if self.variants.is_empty() {
+            return Ok(());
+        }
+        let rows = select_recipient_rows(client, tokens, vec!["expo_push_token", "locale"])
+            .await
+            .map_err(|e| {
+                error!(error = ?e, "Error fetching token locales");
+                ApiError::SupabaseFetch
+            })?;
+        let mut locales = rows
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    row["expo_push_token"].as_str()?,
+                    normalize_locale(row["locale"].as_str()?),
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+        self.token_locales = tokens
+            .iter()
+            .filter_map(|token| Some((token.clone(), locales.remove(token.as_str())?)))
+            .collect();
+
+        let mut recipients_by_locale = HashMap::<&str, usize>::new();
+        for token in tokens {
+            let locale = self
+                .for_token(token)
+                .and_then(|_| self.token_locales.get(token))
+                .map_or("default", String::as_str);
+            *recipients_by_locale.entry(locale).or_default() += 1;
+        }
+        info!(
+            recipients_by_locale = ?recipients_by_locale,
+            "Resolved recipient locales"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::features;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::time::{Duration, Instant};
+
+/// Wall-clock breakdown of the send pipeline. Attached to responses as a
+/// `timings` object (milliseconds) when `DEBUG_MODE=true` or the `timings`
+/// feature flag is on for the request.
+#[derive(Debug)]
+pub struct Timings {
+    started: Instant,
+    last_mark: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            last_mark: now,
+            stages: vec![],
+        }
+    }
+
+    /// Attributes the time since the previous mark to `stage`.
+    pub fn mark(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.add(stage, now - self.last_mark);
+        self.last_mark = now;
+    }
+
+    /// Adds to `stage`, for stages that run interleaved (e.g. per chunk).
+    pub fn add(&mut self, stage: &'static str, elapsed: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total)) => *total += elapsed,
+            None => self.stages.push((stage, elapsed)),
+        }
+    }
+
+    /// Time since [`Timings::start`].
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn is_enabled() -> bool {
+        env::var("DEBUG_MODE").is_ok_and(|v| v == "true" || v == "1")
+            || features::is_enabled(features::TIMINGS)
+    }
+
+    pub fn attach(&self, body: &mut Value) {
+        if !Self::is_enabled() {
+            return;
+        }
+        let mut timings = self
+            .stages
+            .iter()
+            .map(|(stage, elapsed)| (stage.to_string(), json!(elapsed.as_millis())))
+            .collect::<Map<_, _>>();
+        timings.insert("total".into(), json!(self.started.elapsed().as_millis()));
+        body["timings"] = Value::Object(timings);
+    }
+}
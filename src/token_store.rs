@@ -0,0 +1,271 @@
+//! Where push tokens live. The send and registration paths go through
+//! [`TokenStore`] rather than `supabase_rs`, so they can run against
+//! DynamoDB instead of Supabase. Hygiene jobs, history and the other
+//! Supabase tables are not covered and still need Supabase.
+
+use crate::http_handler::{fetch_expo_push_tokens, initialize_supabase_client, ApiError};
+use crate::tokens::{delete_registration, register_token, RegisterTokenRequest, Registration};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::error;
+
+/// Storage for push tokens, selected with `TOKEN_STORE` (see
+/// [`token_store`]).
+pub trait TokenStore: Send + Sync {
+    /// Tokens a broadcast goes to; quarantined and deleted ones are left out.
+    fn fetch_tokens(&self) -> BoxFuture<'_, Result<Vec<String>, ApiError>>;
+
+    /// Registers `request.expo_push_token` for `request.user_id`, or
+    /// refreshes it if it is already registered.
+    fn save_token(
+        &self,
+        request: RegisterTokenRequest,
+    ) -> BoxFuture<'_, Result<Registration, ApiError>>;
+
+    /// Removes a token. `false` when it was not registered.
+    fn delete_token<'a>(
+        &'a self,
+        expo_push_token: &'a str,
+    ) -> BoxFuture<'a, Result<bool, ApiError>>;
+}
+
+/// The `users` table in Supabase. The default store.
+pub struct SupabaseTokenStore(pub SupabaseClient);
+
+impl TokenStore for SupabaseTokenStore {
+    fn fetch_tokens(&self) -> BoxFuture<'_, Result<Vec<String>, ApiError>> {
+        Box::pin(fetch_expo_push_tokens(&self.0))
+    }
+
+    fn save_token(
+        &self,
+        request: RegisterTokenRequest,
+    ) -> BoxFuture<'_, Result<Registration, ApiError>> {
+        Box::pin(register_token(&self.0, request))
+    }
+
+    fn delete_token<'a>(
+        &'a self,
+        expo_push_token: &'a str,
+    ) -> BoxFuture<'a, Result<bool, ApiError>> {
+        Box::pin(delete_registration(&self.0, expo_push_token))
+    }
+}
+
+#[cfg(feature = "dynamodb")]
+pub use dynamodb::DynamoDbTokenStore;
+
+#[cfg(feature = "dynamodb")]
+mod dynamodb {
+    use super::TokenStore;
+    use crate::http_client::aws_sdk_config;
+    use crate::http_handler::ApiError;
+    use crate::tokens::{
+        validate_registration, RegisterTokenRequest, Registration, RegistrationStatus,
+    };
+    use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+    use aws_sdk_dynamodb::Client as DynamoDbClient;
+    use chrono::Utc;
+    use futures::future::BoxFuture;
+    use serde_json::{Map, Value};
+    use std::collections::HashMap;
+    use tracing::{error, info, instrument, warn};
+
+    /// A DynamoDB table keyed by `expo_push_token` (string), with `user_id`,
+    /// `platform`, `app_version` and `last_seen` string attributes and
+    /// optional `quarantined` and `notifications_enabled` booleans. Deleted tokens are removed outright.
+    pub struct DynamoDbTokenStore {
+        client: DynamoDbClient,
+        table: String,
+    }
+
+    impl DynamoDbTokenStore {
+        pub async fn new(table: String) -> Self {
+            let config = aws_sdk_config().await;
+            Self {
+                client: DynamoDbClient::new(config),
+                table,
+            }
+        }
+
+        #[instrument(skip(self), fields(table = %self.table))]
+        async fn scan_tokens(&self) -> Result<Vec<String>, ApiError> {
+            let items = self
+                .client
+                .scan()
+                .table_name(&self.table)
+                .into_paginator()
+                .items()
+                .send()
+                .collect::<Result<Vec<_>, _>>()
+                .await
+                .map_err(|e| {
+                    error!(error = ?e, "Error scanning expo push tokens");
+                    ApiError::TokenStore("scan failed".into())
+                })?;
+
+            let tokens = items
+                .iter()
+                .filter(|item| {
+                    item.get("quarantined")
+                        .and_then(|value| value.as_bool().ok())
+                        != Some(&true)
+                        && item
+                            .get("notifications_enabled")
+                            .and_then(|value| value.as_bool().ok())
+                            != Some(&false)
+                })
+                .filter_map(|item| item.get("expo_push_token")?.as_s().ok().cloned())
+                .collect::<Vec<_>>();
+            info!(
+                token_count = tokens.len(),
+                "Fetched expo push tokens from DynamoDB"
+            );
+            Ok(tokens)
+        }
+
+        #[instrument(skip(self, request), fields(user_id = %request.user_id))]
+        async fn put_token(&self, request: RegisterTokenRequest) -> Result<Registration, ApiError> {
+            validate_registration(&request)?;
+            let existing = self
+                .client
+                .get_item()
+                .table_name(&self.table)
+                .key(
+                    "expo_push_token",
+                    AttributeValue::S(request.expo_push_token.clone()),
+                )
+                .send()
+                .await
+                .map_err(|e| {
+                    error!(error = ?e, "Error fetching token registration");
+                    ApiError::TokenStore("get_item failed".into())
+                })?;
+            let previous_user_id = existing
+                .item()
+                .and_then(|item| item.get("user_id")?.as_s().ok().cloned());
+
+            let mut attributes = HashMap::from([
+                ("user_id", request.user_id.clone()),
+                ("expo_push_token", request.expo_push_token.clone()),
+                ("last_seen", Utc::now().to_rfc3339()),
+            ]);
+            if let Some(platform) = &request.platform {
+                attributes.insert("platform", platform.clone());
+            }
+            if let Some(app_version) = &request.app_version {
+                attributes.insert("app_version", app_version.clone());
+            }
+            let mut put = self.client.put_item().table_name(&self.table);
+            for (name, value) in &attributes {
+                put = put.item(*name, AttributeValue::S(value.clone()));
+            }
+            put.send().await.map_err(|e| {
+                error!(error = ?e, "Error registering token");
+                ApiError::TokenStore("put_item failed".into())
+            })?;
+
+            let (status, previous_user_id) = match previous_user_id {
+                None => (RegistrationStatus::Created, None),
+                Some(previous) if previous != request.user_id => {
+                    warn!(
+                        event = "token_reassigned",
+                        previous_user_id = %previous,
+                        "Token moved to another user"
+                    );
+                    (RegistrationStatus::Reassigned, Some(previous))
+                }
+                Some(_) => (RegistrationStatus::Updated, None),
+            };
+            info!(status = ?status, "Registered expo push token");
+            Ok(Registration {
+                status,
+                user_id: request.user_id,
+                expo_push_token: request.expo_push_token,
+                previous_user_id,
+                row: Value::Object(
+                    attributes
+                        .into_iter()
+                        .map(|(name, value)| (name.to_string(), Value::String(value)))
+                        .collect::<Map<_, _>>(),
+                ),
+            })
+        }
+
+        #[instrument(skip(self, expo_push_token), fields(table = %self.table))]
+        async fn remove_token(&self, expo_push_token: &str) -> Result<bool, ApiError> {
+            let deleted = self
+                .client
+                .delete_item()
+                .table_name(&self.table)
+                .key(
+                    "expo_push_token",
+                    AttributeValue::S(expo_push_token.to_string()),
+                )
+                .return_values(ReturnValue::AllOld)
+                .send()
+                .await
+                .map_err(|e| {
+                    error!(error = ?e, "Error deleting token registration");
+                    ApiError::TokenStore("delete_item failed".into())
+                })?;
+            Ok(deleted.attributes().is_some())
+        }
+    }
+
+    impl TokenStore for DynamoDbTokenStore {
+        fn fetch_tokens(&self) -> BoxFuture<'_, Result<Vec<String>, ApiError>> {
+            Box::pin(self.scan_tokens())
+        }
+
+        fn save_token(
+            &self,
+            request: RegisterTokenRequest,
+        ) -> BoxFuture<'_, Result<Registration, ApiError>> {
+            Box::pin(self.put_token(request))
+        }
+
+        fn delete_token<'a>(
+            &'a self,
+            expo_push_token: &'a str,
+        ) -> BoxFuture<'a, Result<bool, ApiError>> {
+            Box::pin(self.remove_token(expo_push_token))
+        }
+    }
+}
+
+/// Whether `TOKEN_STORE` selects Supabase, which then also holds the users'
+/// preferences.
+pub fn is_supabase_store() -> bool {
+    matches!(
+        env::var("TOKEN_STORE").as_deref(),
+        Err(_) | Ok("" | "supabase")
+    )
+}
+
+/// The store named by `TOKEN_STORE`: `supabase` (the default) or, in builds
+/// with the `dynamodb` feature, `dynamodb`, which reads the table name from
+/// `DYNAMODB_TOKEN_TABLE`.
+pub async fn token_store(
+    secrets: &HashMap<String, String>,
+) -> Result<Box<dyn TokenStore>, ApiError> {
+    match env::var("TOKEN_STORE").as_deref() {
+        Err(_) | Ok("" | "supabase") => Ok(Box::new(SupabaseTokenStore(
+            initialize_supabase_client(secrets)?,
+        ))),
+        #[cfg(feature = "dynamodb")]
+        Ok("dynamodb") => {
+            let table = env::var("DYNAMODB_TOKEN_TABLE")
+                .map_err(|_| ApiError::MissingEnvVar("DYNAMODB_TOKEN_TABLE".into()))?;
+            Ok(Box::new(DynamoDbTokenStore::new(table).await))
+        }
+        Ok(other) => {
+            error!(token_store = other, "Unsupported TOKEN_STORE");
+            Err(ApiError::TokenStore(format!(
+                "unsupported TOKEN_STORE {other}"
+            )))
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use crate::trash::{is_deleted, soft_delete};
+use chrono::Utc;
+use expo_push_notification_client::Expo;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+/// Body of `POST /tokens`, sent by the app on every launch.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterTokenRequest {
+    pub user_id: String,
+    pub expo_push_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Native app version, for the token stats snapshots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationStatus {
+    /// First time this token was seen.
+    Created,
+    /// Already registered to this user; `last_seen` was refreshed.
+    Updated,
+    /// Was registered to another user, e.g. after signing in with a
+    /// different account on the same device.
+    Reassigned,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Registration {
+    pub status: RegistrationStatus,
+    pub user_id: String,
+    pub expo_push_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_user_id: Option<String>,
+    /// The `users` row as stored after the write, so the app sees the
+    /// values Supabase filled in (`id`, defaults, preferences).
+    pub row: Value,
+}
+
+/// `users.id` and `users.user_id` may be uuids or bigints.
+fn id_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+async fn select_registration(
+    client: &SupabaseClient,
+    expo_push_token: &str,
+) -> Result<Vec<Value>, ApiError> {
+    client
+        .select("users")
+        .eq("expo_push_token", expo_push_token)
+        .execute()
+        .timed("select users")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching token registration");
+            ApiError::SupabaseFetch
+        })
+}
+
+/// Checks a registration before any token store sees it.
+pub fn validate_registration(request: &RegisterTokenRequest) -> Result<(), ApiError> {
+    if request.user_id.is_empty() {
+        return Err(ApiError::BadRequest("user_id is required".into()));
+    }
+    if !Expo::is_expo_push_token(&request.expo_push_token) {
+        return Err(ApiError::BadRequest("Invalid expo push token".into()));
+    }
+    Ok(())
+}
+
+/// Upserts the `users` row for `(user_id, expo_push_token)`. Safe to repeat:
+/// registering the same pair again only refreshes `last_seen`. The status is
+/// worked out from the row as it was read just before the write.
+#[instrument(skip(client, request), fields(user_id = %request.user_id))]
+pub async fn register_token(
+    client: &SupabaseClient,
+    request: RegisterTokenRequest,
+) -> Result<Registration, ApiError> {
+    validate_registration(&request)?;
+    let rows = select_registration(client, &request.expo_push_token).await?;
+
+    let mut fields = json!({
+        "user_id": request.user_id,
+        "expo_push_token": request.expo_push_token,
+        "last_seen": Utc::now().to_rfc3339(),
+        // Registering again brings back a soft-deleted token.
+        "deleted_at": Value::Null,
+    });
+    if let Some(platform) = &request.platform {
+        fields["platform"] = json!(platform);
+    }
+    if let Some(app_version) = &request.app_version {
+        fields["app_version"] = json!(app_version);
+    }
+
+    // One write whatever the earlier read saw, so two launches registering
+    // the same token at once cannot both insert it. PostgREST resolves the
+    // conflict on the unique index on `users.expo_push_token`, updating only
+    // the columns sent here; supabase_rs has no `on_conflict` option, so it
+    // rides on the table name.
+    client
+        .upsert_without_defined_key("users?on_conflict=expo_push_token", fields)
+        .timed("upsert users")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error registering token");
+            ApiError::SupabaseWrite
+        })?;
+
+    let previous_user_id = rows.first().map(|row| id_string(&row["user_id"]));
+    let (status, previous_user_id) = match previous_user_id {
+        None => (RegistrationStatus::Created, None),
+        Some(Some(previous)) if previous != request.user_id => {
+            warn!(
+                event = "token_reassigned",
+                previous_user_id = %previous,
+                "Token moved to another user"
+            );
+            (RegistrationStatus::Reassigned, Some(previous))
+        }
+        Some(_) => (RegistrationStatus::Updated, None),
+    };
+
+    info!(status = ?status, "Registered expo push token");
+    let row = select_registration(client, &request.expo_push_token)
+        .await?
+        .into_iter()
+        .next()
+        .unwrap_or(Value::Null);
+    Ok(Registration {
+        status,
+        user_id: request.user_id,
+        expo_push_token: request.expo_push_token,
+        previous_user_id,
+        row,
+    })
+}
+
+/// Soft-deletes the `users` row holding `expo_push_token`, e.g. when the user
+/// signs out. Returns `false` when no live row holds it.
+#[instrument(skip(client, expo_push_token))]
+pub async fn delete_registration(
+    client: &SupabaseClient,
+    expo_push_token: &str,
+) -> Result<bool, ApiError> {
+    let rows = select_registration(client, expo_push_token).await?;
+    let Some(row_id) = rows
+        .iter()
+        .find(|row| !is_deleted(row))
+        .and_then(|row| id_string(&row["id"]))
+    else {
+        return Ok(false);
+    };
+    soft_delete(client, "users", &row_id).await.map_err(|e| {
+        error!(error = %e, "Error deleting token registration");
+        ApiError::SupabaseWrite
+    })?;
+    info!("Deleted expo push token");
+    Ok(true)
+}
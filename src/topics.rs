@@ -0,0 +1,148 @@
+//! Topics a device opts into, e.g. `breaking-news` or `promotions`. The app
+//! subscribes a token with `POST /topics/{topic}/subscribe`, the rows live
+//! in the `topic_subscriptions` table, and `POST /topics/{topic}/send`
+//! notifies every subscriber through [`TopicResolver`].
+
+use crate::audience::{AudienceQuery, AudienceResolver, Recipient};
+use crate::http_handler::{select_all_pages, ApiError};
+use crate::metrics::Timed;
+use chrono::Utc;
+use expo_push_notification_client::Expo;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+const MAX_TOPIC_LENGTH: usize = 64;
+
+/// Body of `POST /topics/{topic}/subscribe` and `/unsubscribe`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicSubscriptionRequest {
+    pub expo_push_token: String,
+}
+
+/// Topic names are lowercase letters, digits, `_` and `-`, so they are safe
+/// in a PostgREST filter and in a URL.
+pub fn validate_topic(topic: &str) -> Result<(), ApiError> {
+    let valid = !topic.is_empty()
+        && topic.len() <= MAX_TOPIC_LENGTH
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        return Err(ApiError::BadRequest(format!(
+            "Topic names are 1 to {MAX_TOPIC_LENGTH} lowercase letters, digits, _ or -"
+        )));
+    }
+    Ok(())
+}
+
+async fn select_subscriptions(
+    client: &SupabaseClient,
+    topic: &str,
+    expo_push_token: Option<&str>,
+) -> Result<Vec<Value>, ApiError> {
+    let select = || {
+        let select = client.select("topic_subscriptions").eq("topic", topic);
+        match expo_push_token {
+            Some(expo_push_token) => select.eq("expo_push_token", expo_push_token),
+            None => select,
+        }
+    };
+    select_all_pages(select, "select topic_subscriptions")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching topic subscriptions");
+            ApiError::SupabaseFetch
+        })
+}
+
+/// Subscribes a token to `topic`. Safe to repeat; returns whether a new
+/// subscription was stored.
+#[instrument(skip(client, expo_push_token))]
+pub async fn subscribe(
+    client: &SupabaseClient,
+    topic: &str,
+    expo_push_token: &str,
+) -> Result<bool, ApiError> {
+    validate_topic(topic)?;
+    if !Expo::is_expo_push_token(expo_push_token) {
+        return Err(ApiError::BadRequest("Invalid expo push token".into()));
+    }
+    if !select_subscriptions(client, topic, Some(expo_push_token))
+        .await?
+        .is_empty()
+    {
+        return Ok(false);
+    }
+    client
+        .insert(
+            "topic_subscriptions",
+            json!({
+                "id": Uuid::new_v4().to_string(),
+                "topic": topic,
+                "expo_push_token": expo_push_token,
+                "created_at": Utc::now().to_rfc3339(),
+            }),
+        )
+        .timed("insert topic_subscriptions")
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error subscribing token to topic");
+            ApiError::SupabaseWrite
+        })?;
+    info!("Subscribed token to topic");
+    Ok(true)
+}
+
+/// Removes a token from `topic`; returns whether it was subscribed.
+#[instrument(skip(client, expo_push_token))]
+pub async fn unsubscribe(
+    client: &SupabaseClient,
+    topic: &str,
+    expo_push_token: &str,
+) -> Result<bool, ApiError> {
+    validate_topic(topic)?;
+    let rows = select_subscriptions(client, topic, Some(expo_push_token)).await?;
+    for id in rows.iter().filter_map(|row| row["id"].as_str()) {
+        client
+            .delete("topic_subscriptions", id)
+            .timed("delete topic_subscriptions")
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Error unsubscribing token from topic");
+                ApiError::SupabaseWrite
+            })?;
+    }
+    info!(removed = rows.len(), "Unsubscribed token from topic");
+    Ok(!rows.is_empty())
+}
+
+/// The subscribers of the query's `topic`.
+pub struct TopicResolver(pub SupabaseClient);
+
+impl AudienceResolver for TopicResolver {
+    fn resolve<'a>(
+        &'a self,
+        query: &'a AudienceQuery,
+    ) -> BoxFuture<'a, Result<Vec<Recipient>, ApiError>> {
+        Box::pin(async move {
+            let topic = query
+                .topic
+                .as_deref()
+                .ok_or_else(|| ApiError::BadRequest("A topic is required".into()))?;
+            validate_topic(topic)?;
+            let rows = select_subscriptions(&self.0, topic, None).await?;
+            let recipients = rows
+                .iter()
+                .filter_map(|row| row["expo_push_token"].as_str())
+                .map(Recipient::token)
+                .collect::<Vec<_>>();
+            info!(token_count = recipients.len(), "Resolved topic subscribers");
+            Ok(recipients)
+        })
+    }
+}
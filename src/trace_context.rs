@@ -0,0 +1,83 @@
+use http::HeaderMap;
+use std::future::Future;
+use uuid::Uuid;
+
+/// W3C Trace Context received with the request
+/// (<https://www.w3.org/TR/trace-context/>).
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    trace_id: String,
+    flags: String,
+    tracestate: Option<String>,
+}
+
+tokio::task_local! {
+    static CURRENT: Option<TraceContext>;
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl TraceContext {
+    /// Parses `traceparent` (`00-<trace-id>-<parent-id>-<flags>`). Malformed
+    /// or all-zero ids are ignored as the spec requires.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get("traceparent")?.to_str().ok()?;
+        let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+        let [version, trace_id, parent_id, flags] = parts.as_slice() else {
+            return None;
+        };
+        if !is_lower_hex(version, 2)
+            || *version == "ff"
+            || !is_lower_hex(trace_id, 32)
+            || trace_id.bytes().all(|b| b == b'0')
+            || !is_lower_hex(parent_id, 16)
+            || parent_id.bytes().all(|b| b == b'0')
+            || !is_lower_hex(flags, 2)
+        {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            flags: flags.to_string(),
+            tracestate: headers
+                .get("tracestate")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+        })
+    }
+
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// `traceparent` for an outbound call, with a fresh parent id so the
+    /// downstream span hangs off this service.
+    fn child_traceparent(&self) -> String {
+        let span_id = &Uuid::new_v4().simple().to_string()[..16];
+        format!("00-{}-{span_id}-{}", self.trace_id, self.flags)
+    }
+}
+
+/// Runs `future` with `context` available to [`inject`].
+pub async fn scope<F: Future>(context: Option<TraceContext>, future: F) -> F::Output {
+    CURRENT.scope(context, future).await
+}
+
+/// Adds `traceparent`/`tracestate` to an outbound request made with our own
+/// `reqwest` client. The Expo and Supabase SDK clients build their requests
+/// internally and cannot carry these headers.
+pub fn inject(builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let Some(context) = CURRENT.try_with(|context| context.clone()).ok().flatten() else {
+        return builder;
+    };
+    let builder = builder.header("traceparent", context.child_traceparent());
+    match &context.tracestate {
+        Some(tracestate) => builder.header("tracestate", tracestate),
+        None => builder,
+    }
+}
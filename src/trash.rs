@@ -0,0 +1,142 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use chrono::{Duration, Utc};
+use futures::future::join_all;
+use serde_json::{json, Value};
+use std::env;
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument, warn};
+
+pub const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
+/// What can be soft-deleted, as named in the `/admin/trash/{kind}` routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrashKind {
+    Tokens,
+    Templates,
+}
+
+impl TrashKind {
+    pub fn parse(kind: &str) -> Result<Self, ApiError> {
+        match kind {
+            "tokens" => Ok(TrashKind::Tokens),
+            "templates" => Ok(TrashKind::Templates),
+            _ => Err(ApiError::NotFound(format!("Unknown trash kind: {kind}"))),
+        }
+    }
+
+    pub fn table(self) -> &'static str {
+        match self {
+            TrashKind::Tokens => "users",
+            TrashKind::Templates => "notification_templates",
+        }
+    }
+}
+
+/// Soft-deleted rows are kept this many days (`TRASH_RETENTION_DAYS`) before
+/// the cleanup job removes them for good.
+pub fn trash_retention_days() -> i64 {
+    env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS)
+}
+
+/// Whether `row` has been soft-deleted. Rows from before the `deleted_at`
+/// column existed count as live.
+pub fn is_deleted(row: &Value) -> bool {
+    !row["deleted_at"].is_null()
+}
+
+/// Marks a row as deleted instead of removing it, so an accidental admin
+/// operation can be undone with [`restore`].
+pub async fn soft_delete(client: &SupabaseClient, table: &str, id: &str) -> Result<(), String> {
+    client
+        .update(table, id, json!({ "deleted_at": Utc::now().to_rfc3339() }))
+        .timed(format!("update {table}"))
+        .await
+        .map(|_| ())
+}
+
+/// Soft-deletes every `users` row holding one of `tokens`, for tokens Expo
+/// reported as `DeviceNotRegistered`: the app was uninstalled, so sending to
+/// them again only wastes requests. Returns how many tokens were pruned;
+/// failures are logged and left for the next receipt check.
+#[instrument(skip(client, tokens), fields(token_count = tokens.len()))]
+pub async fn prune_unregistered_tokens(client: &SupabaseClient, tokens: &[String]) -> usize {
+    let deleted_at = Utc::now().to_rfc3339();
+    let results = join_all(tokens.iter().map(|token| {
+        client
+            .update_with_column_name(
+                "users",
+                "expo_push_token",
+                token,
+                json!({ "deleted_at": deleted_at }),
+            )
+            .timed("update users")
+    }))
+    .await;
+    let mut pruned = 0;
+    for result in results {
+        match result {
+            Ok(_) => pruned += 1,
+            Err(e) => warn!(error = %e, "Failed to prune unregistered token"),
+        }
+    }
+    if pruned > 0 {
+        info!(
+            event = "tokens_pruned",
+            pruned, "Pruned unregistered tokens"
+        );
+    }
+    pruned
+}
+
+/// Rows of `kind` deleted within the retention period, most recent first.
+#[instrument(skip(client))]
+pub async fn list_deleted(
+    client: &SupabaseClient,
+    kind: TrashKind,
+) -> Result<Vec<Value>, ApiError> {
+    let cutoff = (Utc::now() - Duration::days(trash_retention_days())).to_rfc3339();
+    client
+        .select(kind.table())
+        .gte("deleted_at", &cutoff)
+        .order("deleted_at", false)
+        .execute()
+        .timed(format!("select {}", kind.table()))
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching deleted rows");
+            ApiError::SupabaseFetch
+        })
+}
+
+/// Undoes [`soft_delete`]. `false` when there is no such deleted row.
+#[instrument(skip(client))]
+pub async fn restore(client: &SupabaseClient, kind: TrashKind, id: &str) -> Result<bool, ApiError> {
+    let rows = client
+        .select(kind.table())
+        .eq("id", id)
+        .execute()
+        .timed(format!("select {}", kind.table()))
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching row to restore");
+            ApiError::SupabaseFetch
+        })?;
+    if !rows.first().is_some_and(is_deleted) {
+        return Ok(false);
+    }
+
+    client
+        .update(kind.table(), id, json!({ "deleted_at": Value::Null }))
+        .timed(format!("update {}", kind.table()))
+        .await
+        .map_err(|e| {
+            error!(error = %e, "Error restoring deleted row");
+            ApiError::SupabaseWrite
+        })?;
+    info!(table = kind.table(), row_id = %id, "Restored deleted row");
+    Ok(true)
+}
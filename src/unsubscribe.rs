@@ -0,0 +1,216 @@
+use crate::http_handler::ApiError;
+use crate::metrics::Timed;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use supabase_rs::SupabaseClient;
+use tracing::{error, info, instrument};
+
+const DEFAULT_LINK_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+const SIGNING_KEY_SECRET: &str = "unsubscribe-signing-key";
+
+/// Categories whose notifications carry an unsubscribe token, from the
+/// comma-separated `MARKETING_CATEGORIES` (default `marketing`).
+pub fn is_marketing_category(category: &str) -> bool {
+    env::var("MARKETING_CATEGORIES")
+        .unwrap_or_else(|_| "marketing".into())
+        .split(',')
+        .any(|marketing| marketing.trim() == category)
+}
+
+fn link_ttl() -> Duration {
+    env::var("UNSUBSCRIBE_LINK_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse::<u64>().ok())
+        .map(|hours| Duration::from_secs(hours * 60 * 60))
+        .unwrap_or(DEFAULT_LINK_TTL)
+}
+
+fn signature(key: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// A token for the app to post to `/unsubscribe` that opts the device out
+/// of `category`: `{hex(expo_push_token)}.{category}.{expires_at}.{hmac}`,
+/// signed with the `unsubscribe-signing-key` secret. `None` without the
+/// secret.
+pub fn unsubscribe_token(
+    secrets: &HashMap<String, String>,
+    expo_push_token: &str,
+    category: &str,
+) -> Option<String> {
+    let key = secrets.get(SIGNING_KEY_SECRET)?;
+    let expires_at = (SystemTime::now() + link_ttl())
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let payload = format!("{}.{category}.{expires_at}", hex::encode(expo_push_token));
+    let mac = signature(key, &payload);
+    Some(format!(
+        "{payload}.{}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// Checks an [`unsubscribe_token`] and returns the push token and category
+/// it opts out.
+pub fn verify_unsubscribe_token(
+    secrets: &HashMap<String, String>,
+    token: &str,
+) -> Result<(String, String), ApiError> {
+    let invalid = || ApiError::BadRequest("Invalid or expired unsubscribe token".into());
+    let key = secrets
+        .get(SIGNING_KEY_SECRET)
+        .ok_or_else(|| ApiError::MissingSecret(SIGNING_KEY_SECRET.into()))?;
+
+    let (payload, provided) = token.rsplit_once('.').ok_or_else(invalid)?;
+    let provided = hex::decode(provided).map_err(|_| invalid())?;
+    signature(key, payload)
+        .verify_slice(&provided)
+        .map_err(|_| invalid())?;
+
+    // The hex token and the timestamp never contain a `.`; the category in
+    // between may.
+    let (expo_push_token, rest) = payload.split_once('.').ok_or_else(invalid)?;
+    let (category, expires_at) = rest.rsplit_once('.').ok_or_else(invalid)?;
+    let expires_at = expires_at.parse::<u64>().map_err(|_| invalid())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    if now > expires_at {
+        return Err(invalid());
+    }
+    let expo_push_token = hex::decode(expo_push_token)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(invalid)?;
+    Ok((expo_push_token, category.to_string()))
+}
+
+/// Turns `category` off in the `preferences` of every row holding
+/// `expo_push_token`. Returns how many rows were updated.
+#[instrument(skip(client, expo_push_token))]
+pub async fn opt_out(
+    client: &SupabaseClient,
+    expo_push_token: &str,
+    category: &str,
+) -> Result<usize, ApiError> {
+    let rows = client
+        .select("users")
+        .eq("expo_push_token", expo_push_token)
+        .execute()
+        .timed("select users")
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Error fetching users to unsubscribe");
+            ApiError::SupabaseFetch
+        })?;
+
+    let mut updated = 0;
+    for row in &rows {
+        let Some(id) = row["id"]
+            .as_str()
+            .map(str::to_string)
+            .or_else(|| row["id"].as_number().map(|n| n.to_string()))
+        else {
+            continue;
+        };
+        let mut preferences = row["preferences"]
+            .as_object()
+            .cloned()
+            .unwrap_or_else(Map::new);
+        preferences.insert(category.to_string(), json!(false));
+        client
+            .update("users", &id, json!({ "preferences": preferences }))
+            .timed("update users")
+            .await
+            .map_err(|e| {
+                error!(error = %e, row_id = %id, "Error updating preferences");
+                ApiError::SupabaseWrite
+            })?;
+        updated += 1;
+    }
+    info!(updated, "Unsubscribed from category");
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "ExponentPushToken[abc]";
+
+    fn secrets() -> HashMap<String, String> {
+        HashMap::from([(SIGNING_KEY_SECRET.to_string(), "test-key".to_string())])
+    }
+
+    fn signed(payload: &str) -> String {
+        let mac = signature("test-key", payload);
+        format!("{payload}.{}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn round_trips_the_token_and_category() {
+        let token = unsubscribe_token(&secrets(), TOKEN, "marketing").unwrap();
+        let (expo_push_token, category) = verify_unsubscribe_token(&secrets(), &token).unwrap();
+        assert_eq!(expo_push_token, TOKEN);
+        assert_eq!(category, "marketing");
+    }
+
+    #[test]
+    fn round_trips_a_category_containing_dots() {
+        let token = unsubscribe_token(&secrets(), TOKEN, "marketing.spring.sale").unwrap();
+        let (expo_push_token, category) = verify_unsubscribe_token(&secrets(), &token).unwrap();
+        assert_eq!(expo_push_token, TOKEN);
+        assert_eq!(category, "marketing.spring.sale");
+    }
+
+    #[test]
+    fn rejects_a_tampered_token() {
+        let token = unsubscribe_token(&secrets(), TOKEN, "marketing").unwrap();
+        let tampered = token.replacen("marketing", "news", 1);
+        assert!(verify_unsubscribe_token(&secrets(), &tampered).is_err());
+
+        let other_key = HashMap::from([(SIGNING_KEY_SECRET.to_string(), "other".to_string())]);
+        assert!(verify_unsubscribe_token(&other_key, &token).is_err());
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let token = signed(&format!("{}.marketing.1", hex::encode(TOKEN)));
+        assert!(verify_unsubscribe_token(&secrets(), &token).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        for payload in [
+            "",
+            "no-dots",
+            &format!("{}.marketing", hex::encode(TOKEN)),
+            &format!("{}.marketing.soon", hex::encode(TOKEN)),
+            &format!("not-hex.marketing.{}", u64::MAX),
+        ] {
+            assert!(
+                verify_unsubscribe_token(&secrets(), &signed(payload)).is_err(),
+                "{payload:?} was accepted"
+            );
+        }
+        assert!(verify_unsubscribe_token(&secrets(), "garbage").is_err());
+    }
+
+    #[test]
+    fn needs_the_signing_key() {
+        assert!(unsubscribe_token(&HashMap::new(), TOKEN, "marketing").is_none());
+        assert!(matches!(
+            verify_unsubscribe_token(&HashMap::new(), "a.b.1.00"),
+            Err(ApiError::MissingSecret(_))
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+use axum::body::Body;
+use axum::extract::{FromRequest, Request};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use expo_push_notification_api::http_handler::ApiError;
+use expo_push_notification_api::router::{decode_body, JsonBody};
+use serde_json::{json, Value};
+
+async fn extract(body: impl Into<Body>) -> Result<Value, ApiError> {
+    let request = Request::builder().body(body.into()).unwrap();
+    JsonBody::<Value>::from_request(request, &())
+        .await
+        .map(|JsonBody(value)| value)
+}
+
+#[test]
+fn plain_json_is_passed_through() {
+    let body = br#"{"title":"hi"}"#;
+    assert_eq!(decode_body(body).as_ref(), body);
+}
+
+#[test]
+fn base64_encoded_json_is_decoded() {
+    let encoded = BASE64_STANDARD.encode(r#"{"title":"hi"}"#);
+    assert_eq!(
+        decode_body(encoded.as_bytes()).as_ref(),
+        br#"{"title":"hi"}"#
+    );
+}
+
+#[test]
+fn surrounding_whitespace_is_ignored() {
+    let encoded = format!("  {}\n", BASE64_STANDARD.encode(r#"[1,2]"#));
+    assert_eq!(decode_body(encoded.as_bytes()).as_ref(), b"[1,2]");
+}
+
+#[test]
+fn base64_that_is_not_json_is_left_alone() {
+    let encoded = BASE64_STANDARD.encode("not json");
+    assert_eq!(decode_body(encoded.as_bytes()).as_ref(), encoded.as_bytes());
+}
+
+#[test]
+fn empty_body_is_left_alone() {
+    assert!(decode_body(b"").is_empty());
+}
+
+#[tokio::test]
+async fn json_body_extracts_encoded_body() {
+    let encoded = BASE64_STANDARD.encode(r#"{"title":"hi","body":"there"}"#);
+    let value = extract(encoded).await.unwrap();
+    assert_eq!(value, json!({ "title": "hi", "body": "there" }));
+}
+
+#[tokio::test]
+async fn json_body_still_extracts_plain_body() {
+    let value = extract(r#"{"title":"hi"}"#).await.unwrap();
+    assert_eq!(value, json!({ "title": "hi" }));
+}
+
+#[tokio::test]
+async fn json_body_rejects_garbage() {
+    let error = extract("%%%").await.unwrap_err();
+    assert_eq!(error.code(), "invalid_body");
+}